@@ -2,18 +2,20 @@ use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::time;
 use ic_cdk::{caller, print};
 use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
-use ic_stable_memory::collections::hash_map::SHashMap;
+use ic_stable_memory::collections::hash_map::{Entry, SHashMap};
 use ic_stable_memory::collections::vec::SVec;
 use ic_stable_memory::utils::ic_types::SPrincipal;
+use ic_stable_memory::utils::type_tag::{type_tag, TypeTag};
 use ic_stable_memory::{
-    get_allocated_size, get_free_size, s, set_max_grow_pages, stable, stable_memory_init,
-    stable_memory_post_upgrade, stable_memory_pre_upgrade, PAGE_SIZE_BYTES,
+    get_allocated_size, get_free_size, set_max_grow_pages, stable, stable_memory_init,
+    stable_memory_post_upgrade, stable_memory_pre_upgrade, SVar, PAGE_SIZE_BYTES,
 };
 use speedy::{Readable, Writable};
+use std::cmp::min;
 
-type AccountBalances = SHashMap<SPrincipal, u64>;
-type TransactionLedger = SVec<HistoryEntry>;
-type TotalSupply = u64;
+const ACCOUNT_BALANCES: SVar<SHashMap<SPrincipal, u64>> = SVar::new("account_balances");
+const TRANSACTION_LEDGER: SVar<SVec<HistoryEntry>> = SVar::new("transaction_ledger");
+const TOTAL_SUPPLY: SVar<u64> = SVar::new("total_supply");
 
 #[derive(CandidType, Deserialize, Readable, Writable)]
 struct HistoryEntry {
@@ -23,19 +25,22 @@ struct HistoryEntry {
     pub timestamp: u64,
 }
 
+impl TypeTag for HistoryEntry {
+    const TYPE_TAG: u64 = type_tag("HistoryEntry");
+}
+
 #[update]
 fn mint(to: SPrincipal, qty: u64) {
     // update balances
-    let mut balances = s!(AccountBalances);
-    let balance = balances.get_cloned(&to).unwrap_or_default();
-
-    balances.insert(to, &(balance + qty));
-
-    s! { AccountBalances = balances };
+    ACCOUNT_BALANCES.with_mut(|balances| {
+        balances
+            .entry(to)
+            .and_modify(|balance| *balance += qty)
+            .or_insert(qty);
+    });
 
     // update total supply
-    let total_supply: u64 = s!(TotalSupply);
-    s! { TotalSupply = total_supply + qty };
+    TOTAL_SUPPLY.with_mut(|total_supply| *total_supply += qty);
 
     // emit ledger entry
     let entry = HistoryEntry {
@@ -44,10 +49,7 @@ fn mint(to: SPrincipal, qty: u64) {
         qty,
         timestamp: time(),
     };
-    let mut ledger = s!(TransactionLedger);
-    ledger.push(&entry);
-
-    s! { TransactionLedger = ledger };
+    TRANSACTION_LEDGER.with_mut(|ledger| ledger.push(&entry));
 }
 
 #[update]
@@ -55,16 +57,14 @@ fn transfer(to: SPrincipal, qty: u64) {
     let from = SPrincipal(caller());
 
     // update balances
-    let mut balances = s!(AccountBalances);
+    ACCOUNT_BALANCES.with_mut(|balances| {
+        withdraw(balances, from, qty);
 
-    let from_balance = balances.get_cloned(&from).unwrap_or_default();
-    assert!(from_balance >= qty, "Insufficient funds");
-    balances.insert(from, &(from_balance - qty));
-
-    let to_balance = balances.get_cloned(&to).unwrap_or_default();
-    balances.insert(to, &(to_balance + qty));
-
-    s! { AccountBalances = balances };
+        balances
+            .entry(to)
+            .and_modify(|balance| *balance += qty)
+            .or_insert(qty);
+    });
 
     // emit ledger entry
     let entry = HistoryEntry {
@@ -73,10 +73,7 @@ fn transfer(to: SPrincipal, qty: u64) {
         qty,
         timestamp: time(),
     };
-    let mut ledger = s!(TransactionLedger);
-    ledger.push(&entry);
-
-    s! { TransactionLedger = ledger };
+    TRANSACTION_LEDGER.with_mut(|ledger| ledger.push(&entry));
 }
 
 #[update]
@@ -84,15 +81,9 @@ fn burn(qty: u64) {
     let from = SPrincipal(caller());
 
     // update balances
-    let mut balances = s!(AccountBalances);
-    let from_balance = balances.get_cloned(&from).unwrap_or_default();
-    assert!(from_balance >= qty, "Insufficient funds");
-
-    balances.insert(from, &(from_balance - qty));
-    s! { AccountBalances = balances };
+    ACCOUNT_BALANCES.with_mut(|balances| withdraw(balances, from, qty));
 
-    let total_supply = s!(TotalSupply);
-    s! { TotalSupply = total_supply - qty };
+    TOTAL_SUPPLY.with_mut(|total_supply| *total_supply -= qty);
 
     // emit ledger entry
     let entry = HistoryEntry {
@@ -101,36 +92,40 @@ fn burn(qty: u64) {
         qty,
         timestamp: time(),
     };
-    let mut ledger = s!(TransactionLedger);
-    ledger.push(&entry);
+    TRANSACTION_LEDGER.with_mut(|ledger| ledger.push(&entry));
+}
+
+fn withdraw(balances: &mut SHashMap<SPrincipal, u64>, from: SPrincipal, qty: u64) {
+    match balances.entry(from) {
+        Entry::Occupied(mut entry) => {
+            let balance = entry.get();
+            assert!(balance >= qty, "Insufficient funds");
 
-    s! { TransactionLedger = ledger };
+            entry.insert(&(balance - qty));
+        }
+        Entry::Vacant(_) => assert_eq!(qty, 0, "Insufficient funds"),
+    }
 }
 
 #[query]
 fn balance_of(of: SPrincipal) -> u64 {
-    s!(AccountBalances).get_cloned(&of).unwrap_or_default()
+    ACCOUNT_BALANCES.get().get_cloned(&of).unwrap_or_default()
 }
 
 #[query]
-fn total_supply() -> TotalSupply {
-    s!(TotalSupply)
+fn total_supply() -> u64 {
+    TOTAL_SUPPLY.get()
 }
 
 #[query]
 fn get_history(page_index: u64, page_size: u64) -> (Vec<HistoryEntry>, u64) {
-    let from = page_index * page_size;
-    let to = page_index * page_size + page_size;
-
-    let ledger = s!(TransactionLedger);
-    let mut result = vec![];
+    let ledger = TRANSACTION_LEDGER.get();
     let total_pages = ledger.len() / page_size + 1;
 
-    for i in from..to {
-        if let Some(entry) = ledger.get_cloned(i) {
-            result.push(entry);
-        }
-    }
+    let from = min(page_index * page_size, ledger.len());
+    let to = min(page_index * page_size + page_size, ledger.len());
+
+    let result = ledger.range(from..to).collect();
 
     (result, total_pages)
 }
@@ -151,14 +146,14 @@ fn init() {
     set_max_grow_pages(200);
 
     // initialize stable variables (cheap)
-    s! { AccountBalances = AccountBalances::new() };
-    s! { TransactionLedger = TransactionLedger::new() };
-    s! { TotalSupply = TotalSupply::default() };
+    ACCOUNT_BALANCES.set(&SHashMap::new());
+    TRANSACTION_LEDGER.set(&SVec::new());
+    TOTAL_SUPPLY.set(&0);
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    // save stable variables meta-info (cheap)
+    // does nothing, stable variables are already persisted
     stable_memory_pre_upgrade();
 }
 
@@ -1,5 +1,7 @@
 use crate::collections::vec::SVec;
 use speedy::{LittleEndian, Readable, Writable};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 
 #[derive(Readable, Writable)]
 pub enum SHeapType {
@@ -21,164 +23,291 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> SBinaryHe
         }
     }
 
+    /// Turns the vector into a heap in O(n), without moving its elements to another vector
+    pub fn from_vec(ty: SHeapType, arr: SVec<T>) -> Self {
+        let mut heap = Self { ty, arr };
+        heap.rebuild();
+
+        heap
+    }
+
     pub fn push(&mut self, elem: &T) {
         self.arr.push(elem);
+        self.sift_up(self.len() - 1, elem);
+    }
+
+    pub fn peek(&self) -> Option<T> {
+        self.arr.get_cloned(0)
+    }
+
+    /// Gives mutable access to the top element. If it is modified, it is written back and moved to
+    /// its new place, once the returned guard is dropped.
+    pub fn peek_mut(&mut self) -> Option<SBinaryHeapPeekMut<'a, '_, T>> {
+        let value = self.peek()?;
+
+        Some(SBinaryHeapPeekMut {
+            heap: self,
+            value,
+            changed: false,
+            _a: PhantomData,
+        })
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
         let len = self.len();
-        if len == 1 {
-            return;
+        if len == 0 {
+            return None;
         }
 
-        let mut idx = len - 1;
+        self.arr.swap(0, len - 1);
+        let elem = self.arr.pop();
+        self.sift_down(0, len - 1);
 
-        loop {
-            let parent_idx = idx / 2;
-            let parent = self.arr.get_cloned(parent_idx).unwrap();
+        elem
+    }
 
-            let mut flag = false;
+    /// Sorts the heap in place, turning it into a vector of elements in the reverse order of
+    /// `pop()` - ascending for [SHeapType::Max] and descending for [SHeapType::Min]
+    pub fn into_sorted_vec(mut self) -> SVec<T> {
+        for end in (1..self.len()).rev() {
+            self.arr.swap(0, end);
+            self.sift_down(0, end);
+        }
 
-            match self.ty {
-                SHeapType::Min => {
-                    if elem < &parent {
-                        flag = true;
-                    }
-                }
-                SHeapType::Max => {
-                    if elem > &parent {
-                        flag = true;
-                    }
-                }
-            };
+        self.arr
+    }
+
+    /// Removes all elements, returning them in an arbitrary order. Elements, which are not
+    /// iterated over, are removed as well.
+    pub fn drain(&mut self) -> SBinaryHeapDrain<'a, '_, T> {
+        SBinaryHeapDrain {
+            heap: self,
+            _a: PhantomData,
+        }
+    }
 
-            if flag {
-                self.arr.swap(idx, parent_idx);
-                idx = parent_idx;
+    /// Moves all elements of the other heap to this one, leaving the other heap empty
+    pub fn append(&mut self, other: &mut SBinaryHeap<T>) {
+        let start = self.len();
+        while let Some(elem) = other.arr.pop() {
+            self.arr.push(&elem);
+        }
 
-                if idx > 0 {
-                    continue;
-                }
+        // pushing the new elements one by one is only cheaper, when there are a few of them
+        if (self.len() - start) * 2 > start {
+            self.rebuild();
+        } else {
+            for idx in start..self.len() {
+                let elem = self.arr.get_cloned(idx).unwrap();
+                self.sift_up(idx, &elem);
             }
+        }
+    }
 
-            break;
+    /// Keeps only the elements for which the predicate returns `true`
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, f: F) {
+        let len = self.len();
+        self.arr.retain(f);
+
+        if self.len() < len {
+            self.rebuild();
         }
     }
 
-    pub fn peek(&self) -> Option<T> {
-        self.arr.get_cloned(0)
+    pub fn drop(self) {
+        self.arr.drop();
     }
 
-    pub fn pop(&mut self) -> Option<T> {
-        let len = self.len();
+    pub fn len(&self) -> u64 {
+        self.arr.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arr.is_empty()
+    }
 
-        if len == 1 {
-            return self.arr.pop();
+    /// Whether the first element should be closer to the top of the heap, than the second one
+    fn goes_before(&self, a: &T, b: &T) -> bool {
+        match self.ty {
+            SHeapType::Min => a < b,
+            SHeapType::Max => a > b,
         }
+    }
 
-        self.arr.swap(0, len - 1);
-        let elem = self.arr.pop().unwrap();
+    /// Moves the element at the index up, while it goes before its parent
+    fn sift_up(&mut self, mut idx: u64, elem: &T) {
+        while idx > 0 {
+            let parent_idx = (idx - 1) / 2;
+            let parent = self.arr.get_cloned(parent_idx).unwrap();
 
-        let last_idx = len - 2;
+            if !self.goes_before(elem, &parent) {
+                break;
+            }
 
-        let mut idx = 0;
+            self.arr.swap(idx, parent_idx);
+            idx = parent_idx;
+        }
+    }
 
-        loop {
-            let parent = self.arr.get_cloned(idx).unwrap();
+    /// Moves the element at the index down, while any of its children goes before it. Only the
+    /// first `len` elements are treated as the heap.
+    fn sift_down(&mut self, mut idx: u64, len: u64) {
+        if idx >= len {
+            return;
+        }
 
-            let left_child_idx = (idx + 1) * 2 - 1;
-            let right_child_idx = (idx + 1) * 2;
+        let elem = self.arr.get_cloned(idx).unwrap();
 
-            if left_child_idx > last_idx {
-                return Some(elem);
+        loop {
+            let left_idx = idx * 2 + 1;
+            if left_idx >= len {
+                break;
             }
 
-            let left_child = self.arr.get_cloned(left_child_idx).unwrap();
-
-            if right_child_idx > last_idx {
-                let mut flag = false;
+            let mut child_idx = left_idx;
+            let mut child = self.arr.get_cloned(left_idx).unwrap();
 
-                match self.ty {
-                    SHeapType::Min => {
-                        if parent > left_child {
-                            flag = true;
-                        }
-                    }
-                    SHeapType::Max => {
-                        if parent < left_child {
-                            flag = true;
-                        }
-                    }
-                };
+            if left_idx + 1 < len {
+                let right = self.arr.get_cloned(left_idx + 1).unwrap();
 
-                if flag {
-                    self.arr.swap(idx, left_child_idx);
+                if self.goes_before(&right, &child) {
+                    child_idx = left_idx + 1;
+                    child = right;
                 }
+            }
 
-                // this is the last iteration, we can return here
-                // because our binary tree is always complete
-                return Some(elem);
+            if !self.goes_before(&child, &elem) {
+                break;
             }
 
-            let right_child = self.arr.get_cloned(right_child_idx).unwrap();
+            self.arr.swap(idx, child_idx);
+            idx = child_idx;
+        }
+    }
 
-            match self.ty {
-                SHeapType::Min => {
-                    if left_child <= right_child && left_child < parent {
-                        self.arr.swap(idx, left_child_idx);
-                        idx = left_child_idx;
+    /// Restores the heap property for all elements, starting from the lowest parents, in O(n).
+    ///
+    /// Heaps stored by versions 0.2.x were built with a wrong parent index, so their elements can
+    /// be out of order - call this once for each of them, after the upgrade.
+    pub fn rebuild(&mut self) {
+        let len = self.len();
 
-                        continue;
-                    }
+        for idx in (0..len / 2).rev() {
+            self.sift_down(idx, len);
+        }
+    }
+}
 
-                    if right_child <= left_child && right_child < parent {
-                        self.arr.swap(idx, right_child_idx);
-                        idx = right_child_idx;
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Default for SBinaryHeap<T> {
+    fn default() -> Self {
+        SBinaryHeap::new(SHeapType::Max)
+    }
+}
 
-                        continue;
-                    }
-                }
-                SHeapType::Max => {
-                    if left_child >= right_child && left_child > parent {
-                        self.arr.swap(idx, left_child_idx);
-                        idx = left_child_idx;
+/// A guard of the top element of [SBinaryHeap], created by [SBinaryHeap::peek_mut]
+pub struct SBinaryHeapPeekMut<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord>
+{
+    heap: &'b mut SBinaryHeap<T>,
+    value: T,
+    changed: bool,
+    _a: PhantomData<&'a ()>,
+}
 
-                        continue;
-                    }
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord>
+    SBinaryHeapPeekMut<'a, 'b, T>
+{
+    /// Removes the top element from the heap, returning it (with all the modifications)
+    pub fn pop(mut this: Self) -> T {
+        let prev = this.heap.pop().unwrap();
+        this.changed = false;
 
-                    if right_child >= left_child && right_child > parent {
-                        self.arr.swap(idx, right_child_idx);
-                        idx = right_child_idx;
+        std::mem::replace(&mut this.value, prev)
+    }
+}
 
-                        continue;
-                    }
-                }
-            }
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Deref
+    for SBinaryHeapPeekMut<'a, 'b, T>
+{
+    type Target = T;
 
-            return Some(elem);
-        }
+    fn deref(&self) -> &Self::Target {
+        &self.value
     }
+}
 
-    pub fn drop(self) {
-        self.arr.drop();
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> DerefMut
+    for SBinaryHeapPeekMut<'a, 'b, T>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.changed = true;
+
+        &mut self.value
     }
+}
 
-    pub fn len(&self) -> u64 {
-        self.arr.len()
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Drop
+    for SBinaryHeapPeekMut<'a, 'b, T>
+{
+    fn drop(&mut self) {
+        if self.changed {
+            self.heap.arr.replace(0, &self.value);
+            self.heap.sift_down(0, self.heap.len());
+        }
     }
+}
 
-    pub fn is_empty(&self) -> bool {
-        self.arr.is_empty()
+/// A draining iterator over the elements of [SBinaryHeap], created by [SBinaryHeap::drain]
+pub struct SBinaryHeapDrain<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> {
+    heap: &'b mut SBinaryHeap<T>,
+    _a: PhantomData<&'a ()>,
+}
+
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Iterator
+    for SBinaryHeapDrain<'a, 'b, T>
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the last element can be removed without breaking the heap
+        self.heap.arr.pop()
     }
 }
 
-impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Default for SBinaryHeap<T> {
-    fn default() -> Self {
-        SBinaryHeap::new(SHeapType::Max)
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Drop
+    for SBinaryHeapDrain<'a, 'b, T>
+{
+    fn drop(&mut self) {
+        self.heap.arr.clear();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::collections::binary_heap::{SBinaryHeap, SHeapType};
-    use crate::{stable, stable_memory_init};
+    use crate::collections::binary_heap::{SBinaryHeap, SBinaryHeapPeekMut, SHeapType};
+    use crate::collections::vec::SVec;
+    use crate::{get_allocated_size, init_allocator, stable, stable_memory_init};
+    use speedy::{LittleEndian, Readable, Writable};
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+    use std::fmt::Debug;
+
+    /// Checks, that no element goes before its parent
+    fn validate_heap<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord + Debug>(
+        heap: &SBinaryHeap<T>,
+    ) {
+        for idx in 1..heap.len() {
+            let parent = heap.arr.get_cloned((idx - 1) / 2).unwrap();
+            let child = heap.arr.get_cloned(idx).unwrap();
+
+            assert!(
+                !heap.goes_before(&child, &parent),
+                "{:?} at {} goes before its parent {:?}",
+                child,
+                idx,
+                parent
+            );
+        }
+    }
 
     #[test]
     fn heap_sort_works_fine() {
@@ -250,4 +379,189 @@ mod tests {
         // probe should be the same as example
         assert_eq!(probe, example, "Invalid elements order (min)");
     }
+
+    #[test]
+    fn push_and_pop_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        // the parent of the 7th element is the 3rd one, so 4 has to be swapped with 3
+        let mut heap = SBinaryHeap::<u32>::new(SHeapType::Max);
+        for it in [1, 4, 5, 6, 2, 3, 7, 4] {
+            heap.push(&it);
+            validate_heap(&heap);
+        }
+
+        let mut probe = vec![];
+        while let Some(it) = heap.pop() {
+            validate_heap(&heap);
+            probe.push(it);
+        }
+        assert_eq!(probe, vec![7, 6, 5, 4, 4, 3, 2, 1]);
+        assert!(heap.pop().is_none());
+        assert!(heap.peek().is_none());
+
+        heap.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn randomized_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut max_heap = SBinaryHeap::<u64>::new(SHeapType::Max);
+        let mut min_heap = SBinaryHeap::<u64>::new(SHeapType::Min);
+        let mut std_max_heap = BinaryHeap::new();
+        let mut std_min_heap = BinaryHeap::new();
+
+        let mut seed = 11u64;
+        for i in 0..3000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let value = (seed >> 33) % 500;
+
+            match (seed >> 20) % 16 {
+                0..=7 => {
+                    max_heap.push(&value);
+                    min_heap.push(&value);
+                    std_max_heap.push(value);
+                    std_min_heap.push(Reverse(value));
+                }
+                8..=11 => {
+                    assert_eq!(max_heap.pop(), std_max_heap.pop());
+                    assert_eq!(min_heap.pop(), std_min_heap.pop().map(|it| it.0));
+                }
+                12 | 13 => {
+                    if let Some(mut top) = max_heap.peek_mut() {
+                        *top = value;
+                    }
+                    if let Some(mut top) = std_max_heap.peek_mut() {
+                        *top = value;
+                    }
+
+                    if let Some(top) = min_heap.peek_mut() {
+                        assert_eq!(SBinaryHeapPeekMut::pop(top), std_min_heap.pop().unwrap().0);
+                    }
+                }
+                14 => {
+                    max_heap.retain(|it| it % 7 != value % 7);
+                    std_max_heap.retain(|it| it % 7 != value % 7);
+                }
+                _ => {
+                    let mut other = SBinaryHeap::<u64>::new(SHeapType::Max);
+                    let mut std_other = BinaryHeap::new();
+                    for it in 0..(seed >> 40) % 50 {
+                        other.push(&(it * value % 500));
+                        std_other.push(it * value % 500);
+                    }
+
+                    max_heap.append(&mut other);
+                    std_max_heap.append(&mut std_other);
+                    assert!(other.is_empty());
+
+                    other.drop();
+                }
+            }
+
+            assert_eq!(max_heap.len(), std_max_heap.len() as u64);
+            assert_eq!(max_heap.peek(), std_max_heap.peek().cloned());
+            assert_eq!(min_heap.peek(), std_min_heap.peek().map(|it| it.0));
+
+            if i % 100 == 0 {
+                validate_heap(&max_heap);
+                validate_heap(&min_heap);
+            }
+        }
+
+        validate_heap(&max_heap);
+        validate_heap(&min_heap);
+
+        let sorted = max_heap.into_sorted_vec();
+        assert!(sorted.iter().eq(std_max_heap.into_sorted_vec()));
+        sorted.drop();
+
+        let sorted = min_heap.into_sorted_vec();
+        assert!(sorted
+            .iter()
+            .eq(std_min_heap.into_sorted_vec().into_iter().map(|it| it.0)));
+        sorted.drop();
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn from_vec_and_drain_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        for len in [0u64, 1, 2, 3, 10, 1000] {
+            let mut arr = SVec::new();
+            for i in 0..len {
+                arr.push(&(i * 7919 % 1000));
+            }
+
+            let mut heap = SBinaryHeap::from_vec(SHeapType::Min, arr);
+            validate_heap(&heap);
+            assert_eq!(heap.len(), len);
+
+            let mut drained = heap.drain().collect::<Vec<_>>();
+            drained.sort();
+            assert!(drained.into_iter().eq((0..len)
+                .map(|i| i * 7919 % 1000)
+                .collect::<BinaryHeap<_>>()
+                .into_sorted_vec()));
+            assert!(heap.is_empty());
+
+            // the rest is removed, even if the iteration is not complete
+            heap.push(&1);
+            heap.push(&2);
+            assert_eq!(heap.drain().count(), 2);
+            heap.push(&1);
+            heap.push(&2);
+            heap.drain().next();
+            assert!(heap.is_empty());
+
+            heap.drop();
+        }
+
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn rebuild_repairs_legacy_heaps() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        // 0.2.x placed the children of the element i at 2i + 1 and 2i + 2, but looked for its
+        // parent at i / 2, so the heap property of a stored heap can be broken - here it is broken
+        // for every element
+        let mut arr = SVec::new();
+        for i in 0..100u64 {
+            arr.push(&i);
+        }
+
+        let buf = SBinaryHeap {
+            ty: SHeapType::Max,
+            arr,
+        }
+        .write_to_vec()
+        .unwrap();
+
+        let mut heap = SBinaryHeap::<u64>::read_from_buffer_copying_data(&buf).unwrap();
+        heap.rebuild();
+        validate_heap(&heap);
+
+        for i in (0..100u64).rev() {
+            assert_eq!(heap.pop(), Some(i));
+        }
+
+        heap.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
 }
@@ -1,16 +1,111 @@
-use crate::SUnsafeCell;
-use speedy::{LittleEndian, Readable, Writable};
+use crate::primitive::ord_key::OrdKey;
+use crate::primitive::s_slice::Side;
+use crate::utils::key_order::{KeyOrder, MemcmpOrder, OrdOrder};
+use crate::utils::phantom_data::SPhantomData;
+use crate::{allocate, deallocate, try_allocate, OutOfMemory, SSlice, SUnsafeCell};
+use speedy::{Context, LittleEndian, Readable, Reader, Writable, Writer};
 use std::cmp::Ordering;
-use std::fmt::Debug;
+use std::ops::{Bound, Deref, RangeBounds, RangeFull};
+
+const DEFAULT_PAGE_SIZE: usize = 4096;
+const MIN_PAGE_SIZE: usize = 256;
+
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 2;
+
+// kind u8, keys count u16, then the previous and the next leaf u64 (for leaves) or the first
+// child u64 and the number of entries in its subtree u64 (for internal nodes)
+const HEADER_SIZE: usize = 1 + 2 + 8 + 8;
+const PREV_OFFSET: usize = 3;
+// key length u16, key bytes, value cell u64
+const LEAF_ENTRY_OVERHEAD: usize = 2 + 8;
+// key length u16, key bytes, child u64, the number of entries in the subtree of the child u64
+const INTERNAL_ENTRY_OVERHEAD: usize = 2 + 8 + 8;
+
+// the number of keys of a page is stored as u16, so a page can't be bigger than the one, which fits
+// u16::MAX leaf entries with empty keys
+const MAX_PAGE_SIZE: usize = HEADER_SIZE + u16::MAX as usize * LEAF_ENTRY_OVERHEAD;
+
+// the key length of a key, which is stored out of line - the page only has a pointer to it
+const LONG_KEY: u16 = u16::MAX;
+// references u32, key length u32, then key bytes
+const LONG_KEY_HEADER_SIZE: usize = 4 + 4;
+
+struct BTreePage;
+struct BTreeLongKey;
+
+/// Each map header starts with these bytes, followed by the version of the layout. Headers of
+/// maps created by versions 0.2.x start with the root node instead (see [LegacySBTreeMap]).
+const SBTREE_MAP_MAGIC: [u8; 4] = [b'S', b'B', b'T', b'M'];
+const SBTREE_MAP_LAYOUT_VERSION: u8 = 1;
+
+/// Makes a map stored with another layout fail to decode, instead of being misread
+struct SBTreeMapLayout;
+
+impl<'a, C: Context> Readable<'a, C> for SBTreeMapLayout {
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, <C as Context>::Error> {
+        let mut magic = [0u8; SBTREE_MAP_MAGIC.len()];
+        reader.read_bytes(&mut magic)?;
+
+        if magic != SBTREE_MAP_MAGIC {
+            return Err(speedy::Error::custom(
+                "SBTreeMap is stored with the layout of version 0.2.x - read it as LegacySBTreeMap and migrate it",
+            )
+            .into());
+        }
+
+        let version = reader.read_u8()?;
+        if version != SBTREE_MAP_LAYOUT_VERSION {
+            return Err(speedy::Error::custom(format!(
+                "Unknown SBTreeMap layout version {} (the latest known is {})",
+                version, SBTREE_MAP_LAYOUT_VERSION
+            ))
+            .into());
+        }
+
+        Ok(SBTreeMapLayout)
+    }
+}
 
-const DEFAULT_BTREE_DEGREE: usize = 4096;
+impl<C: Context> Writable<C> for SBTreeMapLayout {
+    fn write_to<W: ?Sized + Writer<C>>(&self, writer: &mut W) -> Result<(), <C as Context>::Error> {
+        writer.write_bytes(&SBTREE_MAP_MAGIC)?;
+        writer.write_u8(SBTREE_MAP_LAYOUT_VERSION)
+    }
+}
 
-/// FIXME: OOMs work really bad - I can't put my finger on that recursion
+/// Internal nodes on the way from the root to a leaf, each with the index of the child taken
+type BTreePath = Vec<(BTreeNode, usize)>;
+
+/// A B+tree, which nodes are fixed-size pages of stable memory.
+///
+/// Keys are stored right inside pages in their encoded form, so finding a key takes one page read
+/// per level of the tree. Values are only referenced by leaves - each value is stored in its own
+/// [SUnsafeCell]. Leaves are linked with each other, so scans move from one leaf to the next one
+/// without going back to the root.
+///
+/// Pages are split and merged by the size of their content, not by the number of keys. An encoded
+/// key, which takes more than 1/8 of a page (see [SBTreeMap::max_key_size]), is stored out of
+/// line, and pages only keep a pointer to it.
+///
+/// Internal nodes know the number of entries under each of their children, so entries can be
+/// found and counted by their position in logarithmic time (see [SBTreeMap::rank],
+/// [SBTreeMap::select] and [SBTreeMap::len_in_range]).
+///
+/// Keys are encoded and ordered by `O` ([OrdOrder] by default). Keys, which implement
+/// [OrdKey](crate::primitive::ord_key::OrdKey), are better stored with
+/// [MemcmpOrder](crate::utils::key_order::MemcmpOrder) - then pages are searched by comparing
+/// raw bytes, without decoding any keys.
 #[derive(Readable, Writable)]
-pub struct SBTreeMap<K, V> {
-    root: BTreeNode<K, V>,
-    degree: usize,
+pub struct SBTreeMap<K, V, O = OrdOrder> {
+    _layout: SBTreeMapLayout,
+    root: u64,
     len: u64,
+    page_size: u32,
+    order_id: u8,
+    _k: SPhantomData<K>,
+    _v: SPhantomData<V>,
+    _o: SPhantomData<O>,
 }
 
 impl<
@@ -20,764 +115,3141 @@ impl<
     > SBTreeMap<K, V>
 {
     pub fn new() -> Self {
-        Self::new_with_degree(DEFAULT_BTREE_DEGREE)
+        Self::new_with_order()
     }
 
+    pub fn new_with_page_size(page_size: usize) -> Self {
+        Self::new_with_order_and_page_size(page_size)
+    }
+
+    /// Creates a map, which pages fit `2 * degree - 1` keys of 8 bytes (the most keys a node of
+    /// the previous version of the tree could have)
+    #[deprecated(note = "pages are sized in bytes now - use new_with_page_size()")]
     pub fn new_with_degree(degree: usize) -> Self {
         assert!(degree > 1, "Unable to create BTree with degree less than 2");
 
+        let keys = degree.saturating_mul(2).saturating_sub(1);
+        let page_size = keys
+            .saturating_mul(INTERNAL_ENTRY_OVERHEAD + 8)
+            .saturating_add(HEADER_SIZE);
+
+        Self::new_with_page_size(page_size.clamp(MIN_PAGE_SIZE, MAX_PAGE_SIZE))
+    }
+
+    /// Creates a map from entries, which keys are sorted in ascending order (see `append_sorted()`)
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> Self {
+        let mut map = Self::new();
+        map.append_sorted(entries);
+
+        map
+    }
+}
+
+impl<'a, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>, O: KeyOrder<K>>
+    SBTreeMap<K, V, O>
+{
+    /// Creates a map, which encodes and orders its keys with `O`:
+    /// ```ignore
+    /// let map = SBTreeMap::<String, u64, MemcmpOrder>::new_with_order();
+    /// ```
+    pub fn new_with_order() -> Self {
+        Self::new_with_order_and_page_size(DEFAULT_PAGE_SIZE)
+    }
+
+    pub fn new_with_order_and_page_size(page_size: usize) -> Self {
+        assert!(
+            page_size >= MIN_PAGE_SIZE,
+            "Unable to create BTree with pages smaller than {} bytes",
+            MIN_PAGE_SIZE
+        );
+        assert!(
+            page_size <= MAX_PAGE_SIZE,
+            "Unable to create BTree with pages bigger than {} bytes",
+            MAX_PAGE_SIZE
+        );
+
         Self {
-            degree,
-            root: BTreeNode::<K, V>::new(true, true),
+            _layout: SBTreeMapLayout,
+            root: 0,
             len: 0,
+            page_size: page_size as u32,
+            order_id: O::ORDER_ID,
+            _k: SPhantomData::default(),
+            _v: SPhantomData::default(),
+            _o: SPhantomData::default(),
         }
     }
 
-    pub fn insert(&mut self, key: K, value: &V) -> Option<V> {
-        let root = &mut self.root;
-        let btree_key = BTreeKey::new(key, value);
+    /// Appends entries, which keys are sorted in ascending order and are greater than any key of
+    /// the map. Pages are filled completely from left to right, so this is a lot faster than
+    /// inserting the same entries one by one.
+    ///
+    /// The map is valid after each call, so a big import can be split into chunks, one per
+    /// message:
+    /// ```ignore
+    /// map.append_sorted(records.by_ref().take(10_000));
+    /// ```
+    pub fn append_sorted<I: IntoIterator<Item = (K, V)>>(&mut self, entries: I) {
+        // the rightmost node of each level, from the root to the leaf
+        let mut edge = Vec::new();
+        let mut last_key = None;
+
+        if self.root != 0 {
+            let mut node = BTreeNode::read(self.root, self.page_size());
+
+            while !node.is_leaf {
+                let child = BTreeNode::read(*node.ptrs.last().unwrap(), self.page_size());
+                edge.push(node);
+                node = child;
+            }
 
-        let res = if root.keys.len() == 2 * self.degree - 1 {
-            let mut temp = BTreeNode::new(false, false);
+            last_key = node.keys.last().map(|it| it.to_vec());
+            edge.push(node);
+        }
 
-            root.is_root = false;
-            temp.children.insert(0, SUnsafeCell::new(root));
+        for (key, value) in entries {
+            let key_buf = O::encode(&key);
 
-            Self::split_child(self.degree, &mut temp, 0);
-            let res = Self::insert_non_full(self.degree, &mut temp, btree_key);
+            if let Some(last_key) = &last_key {
+                assert_eq!(
+                    O::cmp(last_key, &key, &key_buf),
+                    Ordering::Less,
+                    "Keys are not sorted or are not greater than keys of the map"
+                );
+            }
 
-            self.root = temp;
-            self.root.is_root = true;
+            let cell = SUnsafeCell::new(&value);
+            let stored_key = self.new_key(key_buf.clone()).expect("Out of stable memory");
+            self.len += 1;
 
-            res
-        } else {
-            Self::insert_non_full(self.degree, &mut self.root, btree_key)
-        };
+            if edge.is_empty() {
+                let leaf = BTreeNode::new(BTreeNode::allocate(self.page_size()), true);
+                self.root = leaf.ptr;
+                edge.push(leaf);
+            }
 
-        if res.is_none() {
-            self.len += 1;
-        }
+            for node in edge.iter_mut().rev().skip(1) {
+                *node.counts.last_mut().unwrap() += 1;
+            }
 
-        res
-    }
+            let leaf = edge.last_mut().unwrap();
+            if leaf.entries_size() + LEAF_ENTRY_OVERHEAD + stored_key.stored_size()
+                <= self.payload_size()
+            {
+                leaf.keys.push(stored_key);
+                leaf.ptrs.push(unsafe { cell.as_ptr() });
+            } else {
+                // the leaf is full, the entry starts the next one
+                let mut next = BTreeNode::new(BTreeNode::allocate(self.page_size()), true);
+                next.prev = leaf.ptr;
+                leaf.next = next.ptr;
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        let res = Self::_delete(self.degree, &mut self.root, key)?;
-        self.len -= 1;
+                let separator = stored_key.share();
+                next.keys.push(stored_key);
+                next.ptrs.push(unsafe { cell.as_ptr() });
 
-        Some(res)
-    }
+                let level = edge.len() - 1;
+                self.append_node(&mut edge, level, separator, next);
+            }
 
-    pub fn drop(mut self) {
-        while let Some(key) = self.root.keys.pop() {
-            key.drop();
+            last_key = Some(key_buf);
         }
 
-        while let Some(child_node) = self.root.children.pop() {
-            self._drop(child_node);
+        for node in edge {
+            node.write();
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
-        self._get(&self.root, key)
-    }
-
-    pub fn contains_key(&self, key: &K) -> bool {
-        self._contains_key(&self.root, key)
-    }
-
-    pub fn len(&self) -> u64 {
-        self.len
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.len == 0
-    }
+    /// Replaces the node at this level of the right edge with the next one, adding the next node
+    /// to the parent. A full parent passes its rightmost child to a new node, which gets the next
+    /// node as well, so no node is ever left without keys.
+    fn append_node(
+        &mut self,
+        edge: &mut Vec<BTreeNode>,
+        mut level: usize,
+        mut separator: BTreeKey,
+        mut next: BTreeNode,
+    ) {
+        loop {
+            let prev = std::mem::replace(&mut edge[level], next);
+            prev.write();
+
+            let child = edge[level].ptr;
+            let child_len = edge[level].len();
+
+            if level == 0 {
+                let mut root = BTreeNode::new(BTreeNode::allocate(self.page_size()), false);
+                root.keys.push(separator);
+                root.ptrs.extend([prev.ptr, child]);
+                root.counts.extend([prev.len(), child_len]);
+
+                self.root = root.ptr;
+                edge.insert(0, root);
+
+                return;
+            }
 
-    fn insert_non_full(
-        degree: usize,
-        node: &mut BTreeNode<K, V>,
-        key: BTreeKey<K, V>,
-    ) -> Option<V> {
-        match node.keys.binary_search_by(|k| k.get_cloned().cmp(&key)) {
-            Ok(idx) => {
-                let new_key_cell = SUnsafeCell::new(&key);
-                let old_key_cell = std::mem::replace(&mut node.keys[idx], new_key_cell);
+            let parent = &mut edge[level - 1];
+            *parent.counts.last_mut().unwrap() -= child_len;
 
-                let old_key = old_key_cell.get_cloned();
-                old_key_cell.drop();
+            if parent.entries_size() + INTERNAL_ENTRY_OVERHEAD + separator.stored_size()
+                <= self.payload_size()
+            {
+                parent.keys.push(separator);
+                parent.ptrs.push(child);
+                parent.counts.push(child_len);
 
-                Some(old_key.drop())
+                return;
             }
-            Err(mut idx) => {
-                if node.is_leaf {
-                    let new_key_cell = SUnsafeCell::new(&key);
 
-                    node.keys.insert(idx, new_key_cell);
-                    None
-                } else {
-                    if node.children[idx].get_cloned().keys.len() == 2 * degree - 1 {
-                        Self::split_child(degree, node, idx);
+            next = BTreeNode::new(BTreeNode::allocate(self.page_size()), false);
+            next.keys.push(separator);
+            next.ptrs.extend([parent.ptrs.pop().unwrap(), child]);
+            next.counts
+                .extend([parent.counts.pop().unwrap(), child_len]);
 
-                        if key > node.keys[idx].get_cloned() {
-                            idx += 1;
-                        }
-                    }
+            separator = parent.keys.pop().unwrap();
+            level -= 1;
+        }
+    }
 
-                    let mut child = node.children[idx].get_cloned();
-                    let result = Self::insert_non_full(degree, &mut child, key);
+    pub fn insert(&mut self, key: K, value: &V) -> Option<V> {
+        self.try_insert(key, value).expect("Out of stable memory")
+    }
 
-                    unsafe { node.children[idx].set(&child) };
+    /// Same as `insert()`, but returns an error instead of panicking, when there is not enough
+    /// stable memory. The map is not modified in this case.
+    pub fn try_insert(&mut self, key: K, value: &V) -> Result<Option<V>, OutOfMemory> {
+        match self.entry(key) {
+            Entry::Occupied(mut it) => {
+                let prev = it.get();
+                it.write(value)?;
 
-                    result
-                }
+                Ok(Some(prev))
             }
+            Entry::Vacant(it) => it.try_insert(value).map(|_| None),
         }
     }
 
-    fn split_child(degree: usize, node: &mut BTreeNode<K, V>, idx: usize) {
-        let mut child = node.children[idx].get_cloned();
-        let mut new_child = BTreeNode::<K, V>::new(child.is_leaf, false);
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.try_remove(key).expect("Out of stable memory")
+    }
+
+    /// Same as `remove()`, but returns an error instead of panicking, when there is not enough
+    /// stable memory to rebalance the tree (a longer key can take place of the removed one in
+    /// an internal node). The map is not modified in this case.
+    pub fn try_remove(&mut self, key: &K) -> Result<Option<V>, OutOfMemory> {
+        let key_buf = O::encode(key);
+        let mut path = Vec::new();
+        let leaf = match self.find_leaf(key, &key_buf, Some(&mut path)) {
+            Some(it) => it,
+            None => return Ok(None),
+        };
 
-        for _ in 0..(degree - 1) {
-            new_child.keys.push(child.keys.remove(degree));
+        match Self::search(&leaf, key, &key_buf) {
+            Ok(idx) => self.remove_at(path, leaf, idx).map(Some),
+            Err(_) => Ok(None),
         }
-        node.keys.insert(idx, child.keys.remove(degree - 1));
+    }
 
-        if !child.is_leaf {
-            for _ in 0..degree {
-                new_child.children.push(child.children.remove(degree))
-            }
+    pub fn drop(self) {
+        if self.root == 0 {
+            return;
         }
 
-        unsafe { node.children[idx].set(&child) };
-        node.children.insert(idx + 1, SUnsafeCell::new(&new_child));
-    }
+        let mut pages = vec![self.root];
 
-    fn _contains_key(&self, node: &BTreeNode<K, V>, key: &K) -> bool {
-        match node.keys.binary_search_by(|k| k.get_cloned().key.cmp(key)) {
-            Ok(_) => true,
-            Err(idx) => {
-                if let Some(child_cell) = node.children.get(idx) {
-                    let child = child_cell.get_cloned();
+        while let Some(ptr) = pages.pop() {
+            let mut node = BTreeNode::read(ptr, self.page_size());
 
-                    self._contains_key(&child, key)
-                } else {
-                    false
+            if node.is_leaf {
+                for ptr in &node.ptrs {
+                    unsafe { SUnsafeCell::<V>::from_ptr(*ptr) }.drop();
                 }
+            } else {
+                pages.extend_from_slice(&node.ptrs);
             }
-        }
-    }
 
-    fn _get(&self, node: &BTreeNode<K, V>, key: &K) -> Option<V> {
-        match node.keys.binary_search_by(|k| k.get_cloned().key.cmp(key)) {
-            Ok(idx) => Some(node.keys[idx].get_cloned().value_cell.get_cloned()),
-            Err(idx) => {
-                let child = node.children.get(idx)?.get_cloned();
-                self._get(&child, key)
+            for key in node.keys.drain(..) {
+                key.release();
             }
+            node.free();
         }
     }
 
-    fn _drop(&mut self, node_cell: SUnsafeCell<BTreeNode<K, V>>) {
-        let mut node = node_cell.get_cloned();
-        for child_cell in node.children {
-            self._drop(child_cell);
-        }
-
-        while let Some(key) = node.keys.pop() {
-            key.drop();
-        }
-        node_cell.drop();
-    }
-
-    fn _delete(degree: usize, node: &mut BTreeNode<K, V>, key: &K) -> Option<V> {
-        match node.keys.binary_search_by(|k| k.get_cloned().key.cmp(key)) {
-            Ok(idx) => {
-                if node.is_leaf {
-                    let btree_key_cell = node.keys.remove(idx);
+    /// Frees up to `leaves` leftmost leaves (with their values) and internal nodes, which are left
+    /// without children. Returns `true`, once the whole map is freed.
+    ///
+    /// Use it instead of `drop()` to free a big map across several messages, writing the map back
+    /// after each call. The map must not be used in any other way, until the drop is complete.
+    pub fn drop_step(&mut self, leaves: usize) -> bool {
+        for _ in 0..leaves {
+            if self.root == 0 {
+                break;
+            }
 
-                    let btree_key = btree_key_cell.get_cloned();
-                    btree_key_cell.drop();
+            let mut path = Vec::new();
+            let mut node = BTreeNode::read(self.root, self.page_size());
 
-                    Some(btree_key.drop())
-                } else {
-                    Self::delete_internal_node(degree, node, key, idx)
-                }
+            while !node.is_leaf {
+                let child = BTreeNode::read(node.ptrs[0], self.page_size());
+                path.push(node);
+                node = child;
             }
-            Err(idx) => {
-                if node.is_leaf {
-                    return None;
-                }
 
-                let mut child = node.children[idx].get_cloned();
+            for ptr in &node.ptrs {
+                unsafe { SUnsafeCell::<V>::from_ptr(*ptr) }.drop();
+            }
+            for key in node.keys.drain(..) {
+                key.release();
+            }
 
-                if child.keys.len() >= degree {
-                    let res = Self::_delete(degree, &mut child, key);
-                    unsafe { node.children[idx].set(&child) };
+            let freed = node.ptrs.len() as u64;
+            self.len -= freed;
+            for parent in path.iter_mut() {
+                parent.counts[0] -= freed;
+            }
 
-                    res
-                } else {
-                    if idx != 0 && idx + 2 < node.children.len() {
-                        let left_child_sibling = node.children[idx - 1].get_cloned();
-                        let right_child_sibling = node.children[idx + 1].get_cloned();
-
-                        if left_child_sibling.keys.len() >= degree {
-                            Self::delete_sibling(node, idx, idx - 1);
-                        } else if right_child_sibling.keys.len() >= degree {
-                            Self::delete_sibling(node, idx, idx + 1);
-                        } else {
-                            Self::delete_merge(node, idx, idx + 1);
+            if node.next != 0 {
+                BTreeNode::write_prev(node.next, 0);
+            }
+            node.free();
+
+            // detach the freed child from its parent, freeing the parent as well, if it was the
+            // last one
+            loop {
+                match path.pop() {
+                    Some(mut parent) => {
+                        parent.ptrs.remove(0);
+                        parent.counts.remove(0);
+
+                        if parent.ptrs.is_empty() {
+                            parent.free();
+                            continue;
                         }
-                    } else if idx == 0 {
-                        let right_child_sibling = node.children[idx + 1].get_cloned();
 
-                        if right_child_sibling.keys.len() >= degree {
-                            Self::delete_sibling(node, idx, idx + 1);
-                        } else {
-                            Self::delete_merge(node, idx, idx + 1);
+                        if !parent.keys.is_empty() {
+                            parent.keys.remove(0).release();
                         }
-                    } else if idx + 1 == node.children.len() {
-                        let left_child_sibling = node.children[idx - 1].get_cloned();
+                        parent.write();
 
-                        if left_child_sibling.keys.len() >= degree {
-                            Self::delete_sibling(node, idx, idx - 1);
-                        } else {
-                            Self::delete_merge(node, idx, idx - 1);
+                        for ancestor in path {
+                            ancestor.write_count(0);
                         }
                     }
-
-                    if node.is_leaf {
-                        return Self::_delete(degree, node, key);
-                    }
-
-                    let mut child = node.children[idx].get_cloned();
-                    let res = Self::_delete(degree, &mut child, key);
-                    unsafe { node.children[idx].set(&child) };
-
-                    res
+                    None => self.root = 0,
                 }
+
+                break;
             }
         }
+
+        self.root == 0
     }
 
-    fn delete_internal_node(
-        degree: usize,
-        node: &mut BTreeNode<K, V>,
-        key: &K,
-        idx: usize,
-    ) -> Option<V> {
-        let idx_key = node.keys[idx].get_cloned();
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key_buf = O::encode(key);
+        let leaf = self.find_leaf(key, &key_buf, None)?;
+        let idx = Self::search(&leaf, key, &key_buf).ok()?;
 
-        if node.is_leaf && idx_key.key.eq(key) {
-            let btree_key_cell = node.keys.remove(idx);
-            let btree_key = btree_key_cell.get_cloned();
-            btree_key_cell.drop();
+        Some(unsafe { SUnsafeCell::<V>::from_ptr(leaf.ptrs[idx]) }.get_cloned())
+    }
 
-            return Some(btree_key.drop());
+    /// Locates the key, so its value can be read and updated without looking it up again
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, O> {
+        let key_buf = O::encode(&key);
+        let mut path = Vec::new();
+        let leaf = self.find_leaf(&key, &key_buf, Some(&mut path));
+
+        match leaf.as_ref().map(|it| Self::search(it, &key, &key_buf)) {
+            Some(Ok(idx)) => {
+                let leaf = leaf.unwrap();
+                let cell = unsafe { SUnsafeCell::from_ptr(leaf.ptrs[idx]) };
+
+                Entry::Occupied(OccupiedEntry {
+                    map: self,
+                    key,
+                    path,
+                    leaf,
+                    idx,
+                    cell,
+                })
+            }
+            Some(Err(idx)) => Entry::Vacant(VacantEntry {
+                map: self,
+                key,
+                key_buf,
+                path,
+                leaf,
+                idx,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                map: self,
+                key,
+                key_buf,
+                path,
+                leaf: None,
+                idx: 0,
+            }),
         }
+    }
 
-        let mut left_child = node.children[idx].get_cloned();
-        let mut right_child = node.children[idx + 1].get_cloned();
+    pub fn contains_key(&self, key: &K) -> bool {
+        let key_buf = O::encode(key);
 
-        if left_child.keys.len() >= degree {
-            let btree_key_cell = std::mem::replace(
-                &mut node.keys[idx],
-                Self::delete_predecessor(degree, &mut left_child),
-            );
-            unsafe { node.children[idx].set(&left_child) };
+        self.find_leaf(key, &key_buf, None)
+            .map(|leaf| Self::search(&leaf, key, &key_buf).is_ok())
+            .unwrap_or_default()
+    }
 
-            let btree_key = btree_key_cell.get_cloned();
-            btree_key_cell.drop();
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        self.iter().next()
+    }
 
-            Some(btree_key.drop())
-        } else if right_child.keys.len() >= degree {
-            let btree_key_cell = std::mem::replace(
-                &mut node.keys[idx],
-                Self::delete_successor(degree, &mut right_child),
-            );
-            unsafe { node.children[idx + 1].set(&right_child) };
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        self.iter().next_back()
+    }
 
-            let btree_key = btree_key_cell.get_cloned();
-            btree_key_cell.drop();
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.first_key_value()?;
+        let value = self.remove(&key)?;
 
-            Some(btree_key.drop())
-        } else {
-            Self::delete_merge(node, idx, idx + 1);
+        Some((key, value))
+    }
 
-            if node.is_root {
-                Self::_delete(degree, node, key)
-            } else {
-                let mut left_child = node.children[idx].get_cloned();
-                let res = Self::delete_internal_node(degree, &mut left_child, key, degree - 1);
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.last_key_value()?;
+        let value = self.remove(&key)?;
 
-                unsafe { node.children[idx].set(&left_child) };
+        Some((key, value))
+    }
 
-                res
-            }
-        }
+    /// Iterates over all entries in ascending order of keys. Use `.rev()` for descending order.
+    pub fn iter(&self) -> SBTreeMapIter<'_, K, V, O, RangeFull> {
+        self.range(..)
     }
 
-    fn delete_predecessor(
-        degree: usize,
-        child: &mut BTreeNode<K, V>,
-    ) -> SUnsafeCell<BTreeKey<K, V>> {
-        if child.is_leaf {
-            return child.keys.pop().unwrap();
+    /// Iterates over entries, which keys are within the range, in ascending order of keys:
+    /// ```ignore
+    /// for (key, value) in map.range(10..=20).rev() { ... }
+    /// ```
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> SBTreeMapIter<'_, K, V, O, R> {
+        let encode = |bound: Bound<&K>| bound.map(|it| O::encode(it));
+
+        SBTreeMapIter {
+            map: self,
+            start: encode(range.start_bound()),
+            end: encode(range.end_bound()),
+            prefix: None,
+            range,
+            front: None,
+            back: None,
+            front_pos: None,
+            back_pos: None,
+            finished: false,
         }
+    }
 
-        let n = child.keys.len() - 1;
-        let grand_child = child.children[n].get_cloned();
-
-        if grand_child.keys.len() >= degree {
-            Self::delete_sibling(child, n + 1, n);
-        } else {
-            Self::delete_merge(child, n + 1, n);
+    /// A cursor, which is off the map, until it is moved or seeks a key. Unlike iterators, it can
+    /// update and remove entries on its way:
+    /// ```ignore
+    /// let mut cursor = map.cursor();
+    /// cursor.seek(&from);
+    ///
+    /// while let Some((key, value)) = cursor.current() {
+    ///     if value.is_expired() {
+    ///         cursor.remove();
+    ///     } else {
+    ///         cursor.move_next();
+    ///     }
+    /// }
+    /// ```
+    pub fn cursor(&mut self) -> SBTreeMapCursor<'_, K, V, O> {
+        SBTreeMapCursor {
+            map: self,
+            path: Vec::new(),
+            leaf: None,
         }
+    }
 
-        let mut grand_child = child.children[n].get_cloned();
-        let res = Self::delete_predecessor(degree, &mut grand_child);
+    pub fn len(&self) -> u64 {
+        self.len
+    }
 
-        unsafe { child.children[n].set(&grand_child) };
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-        res
+    /// The number of keys, which are less than the key. If the key is in the map, this is its
+    /// position in ascending order of keys.
+    pub fn rank(&self, key: &K) -> u64 {
+        self.count_preceding(key, false)
     }
 
-    fn delete_successor(degree: usize, child: &mut BTreeNode<K, V>) -> SUnsafeCell<BTreeKey<K, V>> {
-        if child.is_leaf {
-            return child.keys.remove(0);
+    /// The entry with the n-th smallest key (from 0)
+    pub fn select(&self, n: u64) -> Option<(K, V)> {
+        if n >= self.len {
+            return None;
         }
 
-        let grand_child = child.children[1].get_cloned();
+        let mut n = n;
+        let mut node = BTreeNode::read(self.root, self.page_size());
 
-        if grand_child.keys.len() >= degree {
-            Self::delete_sibling(child, 0, 1);
-        } else {
-            Self::delete_merge(child, 0, 1);
-        }
+        while !node.is_leaf {
+            let mut idx = 0;
+            while n >= node.counts[idx] {
+                n -= node.counts[idx];
+                idx += 1;
+            }
 
-        let mut grand_child = child.children[1].get_cloned();
-        let res = Self::delete_successor(degree, &mut grand_child);
+            node = BTreeNode::read(node.ptrs[idx], self.page_size());
+        }
 
-        unsafe { child.children[0].set(&grand_child) };
+        let key = O::decode(&node.keys[n as usize]);
+        let value = unsafe { SUnsafeCell::<V>::from_ptr(node.ptrs[n as usize]) }.get_cloned();
 
-        res
+        Some((key, value))
     }
 
-    fn delete_merge(node: &mut BTreeNode<K, V>, i: usize, j: usize) {
-        let mut child = node.children[i].get_cloned();
-
-        let mut new = if j > i {
-            let child_right_sibling = node.children[j].get_cloned();
-            child.keys.push(node.keys.remove(i));
+    /// The number of keys within the range, counted without iterating over them
+    pub fn len_in_range<R: RangeBounds<K>>(&self, range: R) -> u64 {
+        let from = match range.start_bound() {
+            Bound::Included(key) => self.count_preceding(key, false),
+            Bound::Excluded(key) => self.count_preceding(key, true),
+            Bound::Unbounded => 0,
+        };
 
-            child.keys.extend(child_right_sibling.keys);
-            child.children.extend(child_right_sibling.children);
+        let to = match range.end_bound() {
+            Bound::Included(key) => self.count_preceding(key, true),
+            Bound::Excluded(key) => self.count_preceding(key, false),
+            Bound::Unbounded => self.len,
+        };
 
-            unsafe { node.children[i].set(&child) };
+        to.saturating_sub(from)
+    }
 
-            let child_right_sibling_ptr = node.children.remove(j);
-            child_right_sibling_ptr.drop();
+    /// The biggest encoded key, which is stored right inside pages. Longer keys are stored out of
+    /// line, so each of them takes an extra allocation and an extra read, when its page is read.
+    pub fn max_key_size(&self) -> usize {
+        (self.payload_size() / 8 - INTERNAL_ENTRY_OVERHEAD).min(LONG_KEY as usize - 1)
+    }
 
-            child
+    /// Stores the encoded key out of line, if it is too long for a page
+    fn new_key(&self, key_buf: Vec<u8>) -> Result<BTreeKey, OutOfMemory> {
+        if key_buf.len() <= self.max_key_size() {
+            Ok(BTreeKey::inline(key_buf))
         } else {
-            let mut child_left_sibling = node.children[j].get_cloned();
-            child_left_sibling.keys.push(node.keys.remove(j));
+            BTreeKey::try_new_long(key_buf)
+        }
+    }
 
-            child_left_sibling.keys.extend(child.keys);
-            child_left_sibling.children.extend(child.children);
+    /// The number of keys, which are less than (or equal to, if inclusive) the key
+    fn count_preceding(&self, key: &K, inclusive: bool) -> u64 {
+        let key_buf = O::encode(key);
 
-            unsafe { node.children[j].set(&child_left_sibling) };
+        self.count_while(|it| match O::cmp(it, key, &key_buf) {
+            Ordering::Less => true,
+            Ordering::Equal => inclusive,
+            Ordering::Greater => false,
+        })
+    }
 
-            let child_ptr = node.children.remove(i);
-            child_ptr.drop();
+    /// The number of keys, for which the predicate holds. The predicate must hold for all keys up
+    /// to some point and for none of the keys after it.
+    fn count_while<F: Fn(&[u8]) -> bool>(&self, f: F) -> u64 {
+        if self.root == 0 {
+            return 0;
+        }
 
-            child_left_sibling
-        };
+        let mut count = 0;
+        let mut node = BTreeNode::read(self.root, self.page_size());
 
-        if node.is_root && node.keys.is_empty() {
-            // dealing with memory leaks - remove the element from stable memory, if it becomes root
-            if j > i {
-                // FIXME: dirty, but rust does not let me go straight to children[i], since I want to consume it during drop()
-                unsafe {
-                    SUnsafeCell::<BTreeNode<K, V>>::from_ptr(node.children[i].as_ptr()).drop()
-                };
-            } else {
-                // FIXME: dirty, but rust does not let me go straight to children[j], since I want to consume it during drop()
-                unsafe {
-                    SUnsafeCell::<BTreeNode<K, V>>::from_ptr(node.children[j].as_ptr()).drop()
-                };
-            }
+        while !node.is_leaf {
+            let idx = node.keys.partition_point(|it| f(it));
+            count += node.counts[..idx].iter().sum::<u64>();
 
-            // FIXME: make this new node the root
-            new.is_root = true;
-            *node = new;
+            node = BTreeNode::read(node.ptrs[idx], self.page_size());
         }
+
+        count + node.keys.partition_point(|it| f(it)) as u64
     }
 
-    fn delete_sibling(node: &mut BTreeNode<K, V>, i: usize, j: usize) {
-        let mut child = node.children[i].get_cloned();
+    /// The leaf and the position in it, which goes right after the keys, for which the predicate
+    /// holds (see `count_while()`). The position can be at the very end of the leaf.
+    fn find_position<F: Fn(&[u8]) -> bool>(&self, f: F) -> Option<(BTreeNode, usize)> {
+        if self.root == 0 {
+            return None;
+        }
 
-        if j > i {
-            let mut child_right_sibling = node.children[j].get_cloned();
+        let mut node = BTreeNode::read(self.root, self.page_size());
+        while !node.is_leaf {
+            let idx = node.keys.partition_point(|it| f(it));
+            node = BTreeNode::read(node.ptrs[idx], self.page_size());
+        }
 
-            child.keys.push(node.keys.remove(i));
-            node.keys.insert(i, child_right_sibling.keys.remove(0));
+        let idx = node.keys.partition_point(|it| f(it));
 
-            if !child_right_sibling.children.is_empty() {
-                child.children.push(child_right_sibling.children.remove(0));
-            }
+        Some((node, idx))
+    }
 
-            unsafe { node.children[j].set(&child_right_sibling) };
-        } else {
-            let mut child_left_sibling = node.children[j].get_cloned();
+    fn page_size(&self) -> usize {
+        assert_eq!(
+            self.order_id,
+            O::ORDER_ID,
+            "SBTreeMap is created with another order"
+        );
 
-            child.keys.insert(0, node.keys.remove(i - 1));
-            node.keys
-                .insert(i - 1, child_left_sibling.keys.pop().unwrap());
+        self.page_size as usize
+    }
 
-            if !child_left_sibling.children.is_empty() {
-                child
-                    .children
-                    .insert(0, child_left_sibling.children.pop().unwrap())
-            }
+    fn payload_size(&self) -> usize {
+        self.page_size() - HEADER_SIZE
+    }
 
-            unsafe { node.children[j].set(&child_left_sibling) };
-        }
+    fn overflows(&self, node: &BTreeNode) -> bool {
+        node.entries_size() > self.payload_size()
+    }
 
-        unsafe { node.children[i].set(&child) };
+    fn underflows(&self, node: &BTreeNode) -> bool {
+        node.entries_size() * 4 < self.payload_size()
     }
-}
 
-impl<
-        'a,
-        K: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian>,
-        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
-    > Default for SBTreeMap<K, V>
-{
-    fn default() -> Self {
-        SBTreeMap::<K, V>::new()
+    /// Whether the node can overflow after getting one more key
+    fn may_overflow(&self, node: &BTreeNode) -> bool {
+        node.entries_size() + node.entry_overhead() + self.max_key_size() > self.payload_size()
     }
-}
 
-#[derive(Readable, Writable)]
-struct BTreeKey<K, V> {
-    key: K,
-    value_cell: SUnsafeCell<V>,
-}
+    /// The number of pages, which an insertion of a key of this size into the leaf can allocate
+    fn pages_to_insert(
+        &self,
+        path: &BTreePath,
+        leaf: Option<&BTreeNode>,
+        key_size: usize,
+    ) -> usize {
+        let leaf = match leaf {
+            Some(it) => it,
+            None => return 1,
+        };
 
-impl<'a, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>> BTreeKey<K, V> {
-    pub fn new(key: K, value: &V) -> Self {
-        Self {
-            key,
-            value_cell: SUnsafeCell::new(value),
+        if leaf.entries_size() + LEAF_ENTRY_OVERHEAD + key_size <= self.payload_size() {
+            return 0;
         }
-    }
 
-    pub fn drop(self) -> V {
-        let it = self.value_cell.get_cloned();
-        self.value_cell.drop();
+        // each split puts a separator into the parent, which can split the parent as well
+        let mut count = 1;
+        for (parent, _) in path.iter().rev() {
+            if !self.may_overflow(parent) {
+                return count;
+            }
+
+            count += 1;
+        }
 
-        it
+        // the root is split, so there is a new root
+        count + 1
     }
-}
 
-impl<K: Ord, V> Eq for BTreeKey<K, V> {}
+    /// The number of pages, which a removal from a leaf at the end of the path can allocate
+    fn pages_to_remove(&self, path: &BTreePath) -> usize {
+        // rebalancing changes each node on the path by at most one key, so only nodes, which are
+        // almost full, can be split
+        let mut count = path
+            .iter()
+            .filter(|(node, _)| self.may_overflow(node))
+            .count();
 
-impl<K: Ord, V> PartialEq<Self> for BTreeKey<K, V> {
-    fn eq(&self, other: &Self) -> bool {
-        self.key.eq(&other.key)
-    }
-}
+        if let Some((root, _)) = path.first() {
+            if self.may_overflow(root) {
+                count += 1;
+            }
+        }
 
-impl<K: Ord, V> PartialOrd<Self> for BTreeKey<K, V> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.key.partial_cmp(&other.key)
+        count
     }
-}
 
-impl<K: Ord, V> Ord for BTreeKey<K, V> {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.key.cmp(&other.key)
+    /// Searches for the key, which is also passed encoded, so the order can compare either form
+    fn search(node: &BTreeNode, key: &K, key_buf: &[u8]) -> Result<usize, usize> {
+        node.keys.binary_search_by(|it| O::cmp(it, key, key_buf))
     }
 
-    fn min(self, other: Self) -> Self
-    where
-        Self: Sized,
-    {
-        if self < other {
-            self
-        } else {
-            other
+    /// Index of the child of an internal node, which can contain the key - a key equal to a
+    /// separator belongs to the right child of this separator
+    fn child_idx(node: &BTreeNode, key: &K, key_buf: &[u8]) -> usize {
+        match Self::search(node, key, key_buf) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
         }
     }
 
-    fn max(self, other: Self) -> Self
-    where
-        Self: Sized,
-    {
-        if self > other {
-            self
-        } else {
-            other
+    fn find_leaf(
+        &self,
+        key: &K,
+        key_buf: &[u8],
+        mut path: Option<&mut BTreePath>,
+    ) -> Option<BTreeNode> {
+        if self.root == 0 {
+            return None;
         }
-    }
 
-    fn clamp(self, min: Self, max: Self) -> Self
-    where
-        Self: Sized,
-    {
-        if self > max {
-            max
-        } else if self < min {
-            min
-        } else {
-            self
-        }
-    }
-}
+        let mut node = BTreeNode::read(self.root, self.page_size());
 
-#[derive(Readable, Writable)]
-struct BTreeNode<K, V> {
-    is_leaf: bool,
-    is_root: bool,
-    keys: Vec<SUnsafeCell<BTreeKey<K, V>>>,
-    children: Vec<SUnsafeCell<BTreeNode<K, V>>>,
-}
+        while !node.is_leaf {
+            let idx = Self::child_idx(&node, key, key_buf);
+            let child = BTreeNode::read(node.ptrs[idx], self.page_size());
 
-impl<
-        'a,
-        K: Readable<'a, LittleEndian> + Writable<LittleEndian>,
-        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
-    > BTreeNode<K, V>
-{
-    pub fn new(is_leaf: bool, is_root: bool) -> Self {
-        Self {
-            is_root,
-            is_leaf,
-            keys: Vec::new(),
-            children: Vec::new(),
+            if let Some(path) = path.as_mut() {
+                path.push((node, idx));
+            }
+
+            node = child;
         }
-    }
-}
 
-fn btree_to_sorted_vec<
-    'a,
-    K: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian> + Clone,
-    V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
->(
-    btree_node: &BTreeNode<K, V>,
-    vec: &mut Vec<(K, V)>,
-) {
-    for i in 0..btree_node.keys.len() {
-        if let Some(child) = btree_node.children.get(i).map(|it| it.get_cloned()) {
-            btree_to_sorted_vec(&child, vec);
-        }
-        let btree_key = &btree_node.keys[i].get_cloned();
-        vec.push((btree_key.key.clone(), btree_key.value_cell.get_cloned()));
-    }
-
-    if let Some(child) = btree_node
-        .children
-        .get(btree_node.keys.len())
-        .map(|it| it.get_cloned())
-    {
-        btree_to_sorted_vec(&child, vec);
+        Some(node)
     }
-}
 
-fn print_btree<
-    'a,
-    K: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian> + Debug,
-    V: Readable<'a, LittleEndian> + Writable<LittleEndian> + Debug,
->(
-    btree: &SBTreeMap<K, V>,
-) {
-    let mut nodes_1 = print_btree_level(&btree.root);
-    println!();
+    fn insert_at(
+        &mut self,
+        mut path: BTreePath,
+        leaf: Option<BTreeNode>,
+        idx: usize,
+        key_buf: Vec<u8>,
+        value: &V,
+    ) -> Result<(), OutOfMemory> {
+        // everything is allocated before the tree is touched, so running out of memory leaves
+        // the tree as it was
+        let cell = SUnsafeCell::try_new(value)?;
+        let key = match self.new_key(key_buf) {
+            Ok(it) => it,
+            Err(e) => {
+                cell.drop();
+                return Err(e);
+            }
+        };
+        let mut pages = match BTreeNode::reserve(
+            self.pages_to_insert(&path, leaf.as_ref(), key.stored_size()),
+            self.page_size(),
+        ) {
+            Ok(it) => it,
+            Err(e) => {
+                cell.drop();
+                key.release();
+                return Err(e);
+            }
+        };
+
+        let mut leaf = leaf.unwrap_or_else(|| {
+            let leaf = BTreeNode::new(pages.pop().unwrap(), true);
+            self.root = leaf.ptr;
 
-    loop {
-        let mut nodes_2 = vec![];
+            leaf
+        });
 
-        for node in &nodes_1 {
-            let res = print_btree_level(node);
+        leaf.keys.insert(idx, key);
+        leaf.ptrs.insert(idx, unsafe { cell.as_ptr() });
+        self.len += 1;
 
-            for n in res {
-                nodes_2.push(n);
-            }
+        for (node, idx) in path.iter_mut() {
+            node.counts[*idx] += 1;
         }
 
-        println!();
+        self.rebalance_after_insert(path, leaf, &mut pages);
+        BTreeNode::release(pages);
+
+        Ok(())
+    }
+
+    fn remove_at(
+        &mut self,
+        mut path: BTreePath,
+        mut leaf: BTreeNode,
+        idx: usize,
+    ) -> Result<V, OutOfMemory> {
+        let mut pages = BTreeNode::reserve(self.pages_to_remove(&path), self.page_size())?;
+
+        leaf.keys.remove(idx).release();
+        let cell = unsafe { SUnsafeCell::<V>::from_ptr(leaf.ptrs.remove(idx)) };
+        self.len -= 1;
 
-        if nodes_2.is_empty() {
-            break;
+        for (node, idx) in path.iter_mut() {
+            node.counts[*idx] -= 1;
         }
 
-        nodes_1 = nodes_2;
-    }
-}
+        let value = cell.get_cloned();
+        cell.drop();
 
-fn print_btree_level<
-    'a,
-    K: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian> + Debug,
-    V: Readable<'a, LittleEndian> + Writable<LittleEndian> + Debug,
->(
-    btree_node: &BTreeNode<K, V>,
-) -> Vec<BTreeNode<K, V>> {
-    let mut children = vec![];
-
-    print!(
-        "{:?}",
-        btree_node
-            .keys
-            .iter()
-            .map(|it| (it.get_cloned().key, it.get_cloned().value_cell.get_cloned()))
-            .collect::<Vec<_>>()
-    );
+        self.rebalance_after_remove(path, leaf, &mut pages);
+        BTreeNode::release(pages);
 
-    for ch in &btree_node.children {
-        children.push(ch.get_cloned());
+        Ok(value)
     }
 
-    children
-}
+    /// Replaces the value in the cell of a leaf entry
+    fn write_value(
+        leaf: &mut BTreeNode,
+        idx: usize,
+        cell: &mut SUnsafeCell<V>,
+        value: &V,
+    ) -> Result<(), OutOfMemory> {
+        let buf = value.write_to_vec().expect("Unable to encode");
+
+        // a bigger cell is allocated before the current one is freed, so the value stays in
+        // place, if there is no memory for it
+        if buf.len() > cell._allocated_size() {
+            let new_cell = SUnsafeCell::try_new_encoded(buf)?;
+            std::mem::replace(cell, new_cell).drop();
+
+            leaf.ptrs[idx] = unsafe { cell.as_ptr() };
+            leaf.write();
+        } else {
+            unsafe { cell.set_encoded(buf) };
+        }
 
-#[cfg(test)]
-mod tests {
-    use crate::collections::btree_map::{btree_to_sorted_vec, print_btree, SBTreeMap};
-    use crate::{init_allocator, stable};
+        Ok(())
+    }
 
-    #[test]
-    fn random_works_as_expected() {
-        stable::clear();
-        stable::grow(1).unwrap();
-        init_allocator(0);
+    /// Splits the node, while it doesn't fit into its page, moving up the path. New nodes are
+    /// taken from the reserved pages. Subtree counts on the path are expected to be up to date.
+    fn rebalance_after_insert(
+        &mut self,
+        mut path: BTreePath,
+        mut node: BTreeNode,
+        pages: &mut Vec<u64>,
+    ) {
+        while self.overflows(&node) {
+            let mut right = BTreeNode::new(pages.pop().unwrap(), node.is_leaf);
+
+            if node.is_leaf {
+                right.prev = node.ptr;
+                right.next = node.next;
+                node.next = right.ptr;
+
+                if right.next != 0 {
+                    BTreeNode::write_prev(right.next, right.ptr);
+                }
+            }
 
-        let example = vec![
-            (10, 1),
-            (20, 2),
-            (30, 3),
-            (40, 4),
-            (50, 5),
-            (60, 6),
-            (70, 7),
-            (80, 8),
-            (90, 9),
-        ];
+            let separator = Self::divide(&mut node, &mut right);
+            node.write();
+            right.write();
 
-        let mut map = SBTreeMap::<u64, u64>::new_with_degree(3);
+            match path.pop() {
+                Some((mut parent, idx)) => {
+                    parent.keys.insert(idx, separator);
+                    parent.ptrs.insert(idx + 1, right.ptr);
+                    parent.counts[idx] = node.len();
+                    parent.counts.insert(idx + 1, right.len());
 
-        println!("INSERTION");
+                    node = parent;
+                }
+                None => {
+                    let mut root = BTreeNode::new(pages.pop().unwrap(), false);
+                    root.keys.push(separator);
+                    root.ptrs.push(node.ptr);
+                    root.ptrs.push(right.ptr);
+                    root.counts.push(node.len());
+                    root.counts.push(right.len());
+
+                    self.root = root.ptr;
+                    node = root;
+                }
+            }
+        }
 
-        assert!(map.insert(30, &3).is_none());
-        print_btree(&map);
-        println!();
+        node.write();
+        BTreeNode::write_counts(path);
+    }
 
-        assert!(map.insert(90, &9).is_none());
-        print_btree(&map);
-        println!();
+    /// Merges the node with its sibling or moves some entries from the sibling to it, while the
+    /// node is less than 1/4 full, moving up the path
+    fn rebalance_after_remove(
+        &mut self,
+        mut path: BTreePath,
+        mut node: BTreeNode,
+        pages: &mut Vec<u64>,
+    ) {
+        while let Some((mut parent, idx)) = path.pop() {
+            // a new separator can be longer than the previous one
+            if self.overflows(&node) {
+                path.push((parent, idx));
+
+                return self.rebalance_after_insert(path, node, pages);
+            }
 
-        assert!(map.insert(10, &1).is_none());
-        print_btree(&map);
-        println!();
+            if !self.underflows(&node) {
+                node.write();
+                parent.write_count(idx);
+                BTreeNode::write_counts(path);
 
-        assert!(map.insert(70, &7).is_none());
-        print_btree(&map);
-        println!();
+                return;
+            }
+
+            let left_idx = if idx + 1 < parent.ptrs.len() {
+                idx
+            } else {
+                idx - 1
+            };
+
+            let (mut left, mut right) = if left_idx == idx {
+                let sibling = BTreeNode::read(parent.ptrs[idx + 1], self.page_size());
+                (node, sibling)
+            } else {
+                let sibling = BTreeNode::read(parent.ptrs[idx - 1], self.page_size());
+                (sibling, node)
+            };
+
+            let separator = parent.keys.remove(left_idx);
+            parent.ptrs.remove(left_idx + 1);
+            parent.counts.remove(left_idx + 1);
+
+            let merged_size = if left.is_leaf {
+                left.entries_size() + right.entries_size()
+            } else {
+                left.entries_size()
+                    + right.entries_size()
+                    + INTERNAL_ENTRY_OVERHEAD
+                    + separator.stored_size()
+            };
+
+            // separators of leaves are copies of their keys, unlike separators of internal nodes
+            if left.is_leaf {
+                separator.release();
+            } else {
+                left.keys.push(separator);
+            }
+            left.keys.append(&mut right.keys);
+            left.ptrs.append(&mut right.ptrs);
+            left.counts.append(&mut right.counts);
+
+            if merged_size <= self.payload_size() {
+                if left.is_leaf {
+                    left.next = right.next;
+
+                    if left.next != 0 {
+                        BTreeNode::write_prev(left.next, left.ptr);
+                    }
+                }
+
+                left.write();
+                parent.counts[left_idx] = left.len();
+                right.free();
+            } else {
+                let separator = Self::divide(&mut left, &mut right);
+                left.write();
+                right.write();
+
+                parent.keys.insert(left_idx, separator);
+                parent.ptrs.insert(left_idx + 1, right.ptr);
+                parent.counts[left_idx] = left.len();
+                parent.counts.insert(left_idx + 1, right.len());
+            }
+
+            node = parent;
+        }
+
+        // the root is allowed to be almost empty, but not completely empty
+        if self.overflows(&node) {
+            self.rebalance_after_insert(path, node, pages);
+        } else if !node.keys.is_empty() {
+            node.write();
+        } else {
+            self.root = if node.is_leaf { 0 } else { node.ptrs[0] };
+            node.free();
+        }
+    }
+
+    /// Moves the upper half (by size) of the entries of the left node to the empty right one.
+    /// Returns the key, which separates these nodes in their parent.
+    fn divide(left: &mut BTreeNode, right: &mut BTreeNode) -> BTreeKey {
+        let total_size = left.entries_size();
+        let mut size = 0;
+
+        let mut mid = left.keys.len();
+        for (idx, key) in left.keys.iter().enumerate() {
+            size += left.entry_overhead() + key.stored_size();
+
+            if size * 2 >= total_size {
+                mid = idx + 1;
+                break;
+            }
+        }
+
+        if left.is_leaf {
+            let mid = mid.clamp(1, left.keys.len() - 1);
+
+            right.keys = left.keys.split_off(mid);
+            right.ptrs = left.ptrs.split_off(mid);
+
+            right.keys[0].share()
+        } else {
+            // the key in the middle moves up, both nodes keep at least one key
+            let mid = mid.clamp(1, left.keys.len() - 2);
+
+            right.keys = left.keys.split_off(mid + 1);
+            right.ptrs = left.ptrs.split_off(mid + 1);
+            right.counts = left.counts.split_off(mid + 1);
+
+            left.keys.pop().unwrap()
+        }
+    }
+}
+
+impl<'a, K: OrdKey, V: Readable<'a, LittleEndian> + Writable<LittleEndian>>
+    SBTreeMap<K, V, MemcmpOrder>
+{
+    /// Iterates over entries, which encoded keys start with the prefix, in ascending order of
+    /// keys. Since keys are sorted as their encodings are, these entries go one after another.
+    ///
+    /// The prefix is encoded the same way as keys. For composite keys it can be the encoding of
+    /// their first parts, and for strings - see [ord_prefix](crate::primitive::ord_key::ord_prefix):
+    /// ```ignore
+    /// for (key, order) in orders.prefix(&ord_prefix(format!("user/{}/", user).as_bytes())) { ... }
+    /// ```
+    pub fn prefix(&self, prefix: &[u8]) -> SBTreeMapIter<'_, K, V, MemcmpOrder, RangeFull> {
+        SBTreeMapIter {
+            map: self,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            prefix: Some(prefix.to_vec()),
+            range: ..,
+            front: None,
+            back: None,
+            front_pos: None,
+            back_pos: None,
+            finished: false,
+        }
+    }
+
+    /// The number of entries, which encoded keys start with the prefix, counted without
+    /// iterating over them
+    pub fn prefix_count(&self, prefix: &[u8]) -> u64 {
+        let to = self.count_while(|it| it < prefix || it.starts_with(prefix));
+        let from = self.count_while(|it| it < prefix);
+
+        to - from
+    }
+}
+
+impl<'a, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>, O: KeyOrder<K>> Default
+    for SBTreeMap<K, V, O>
+{
+    fn default() -> Self {
+        SBTreeMap::<K, V, O>::new_with_order()
+    }
+}
+
+#[derive(Readable, Writable)]
+struct LegacyBTreeKey<K, V> {
+    key: K,
+    value_cell: SUnsafeCell<V>,
+}
+
+#[derive(Readable, Writable)]
+struct LegacyBTreeNode<K, V> {
+    is_leaf: bool,
+    is_root: bool,
+    keys: Vec<SUnsafeCell<LegacyBTreeKey<K, V>>>,
+    children: Vec<SUnsafeCell<LegacyBTreeNode<K, V>>>,
+}
+
+/// [SBTreeMap] as it was stored by versions 0.2.x - a B-tree, which root node is stored inline
+/// and every other node, key and value is stored in its own cell. Such a map can't be read as
+/// [SBTreeMap] anymore. Read it as `LegacySBTreeMap` instead and migrate it:
+/// ```ignore
+/// let legacy = get_var::<LegacySBTreeMap<u64, Order>>("orders");
+/// set_var("orders", &legacy.migrate());
+/// ```
+/// Legacy sets (`SBTreeSet<T>`) are read as `LegacySBTreeMap<T, ()>` and migrated with
+/// `migrate_set()`.
+#[derive(Readable, Writable)]
+pub struct LegacySBTreeMap<K, V> {
+    _root: LegacyBTreeNode<K, V>,
+    _degree: usize,
+    _len: u64,
+}
+
+impl<
+        'a,
+        K: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+    > LegacySBTreeMap<K, V>
+{
+    pub fn len(&self) -> u64 {
+        self._len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self._len == 0
+    }
+
+    /// Moves all the entries into a new map and frees this one. The entries are visited in
+    /// ascending order, so the new map is bulk-loaded with `append_sorted()`. Takes time
+    /// proportional to the number of entries, all within a single call.
+    pub fn migrate(self) -> SBTreeMap<K, V> {
+        // nodes on the way to the current entry, each with the position in it (even positions
+        // are children, odd ones are keys) and its cell (the root has none)
+        let mut stack = vec![(self._root, 0usize, None)];
+
+        let entries = std::iter::from_fn(move || loop {
+            let (node, pos, _) = stack.last_mut()?;
+            let idx = *pos;
+            *pos += 1;
+
+            if node.is_leaf {
+                if idx < node.keys.len() {
+                    return Some(Self::take_entry(node.keys[idx].slice.ptr));
+                }
+            } else if idx <= node.keys.len() * 2 {
+                if idx % 2 == 1 {
+                    return Some(Self::take_entry(node.keys[idx / 2].slice.ptr));
+                }
+
+                let cell = unsafe {
+                    SUnsafeCell::<LegacyBTreeNode<K, V>>::from_ptr(node.children[idx / 2].slice.ptr)
+                };
+                stack.push((cell.get_cloned(), 0, Some(cell)));
+
+                continue;
+            }
+
+            if let Some(cell) = stack.pop().unwrap().2 {
+                cell.drop();
+            }
+        });
+
+        let mut map = SBTreeMap::new();
+        map.append_sorted(entries);
+
+        map
+    }
+
+    /// Reads the entry and frees its cells
+    fn take_entry(ptr: u64) -> (K, V) {
+        let cell = unsafe { SUnsafeCell::<LegacyBTreeKey<K, V>>::from_ptr(ptr) };
+        let entry = cell.get_cloned();
+        cell.drop();
+
+        let value = entry.value_cell.get_cloned();
+        entry.value_cell.drop();
+
+        (entry.key, value)
+    }
+}
+
+/// A view into a single entry of [SBTreeMap], created by [SBTreeMap::entry]
+pub enum Entry<'b, K, V, O = OrdOrder> {
+    Occupied(OccupiedEntry<'b, K, V, O>),
+    Vacant(VacantEntry<'b, K, V, O>),
+}
+
+pub struct OccupiedEntry<'b, K, V, O = OrdOrder> {
+    map: &'b mut SBTreeMap<K, V, O>,
+    key: K,
+    path: BTreePath,
+    leaf: BTreeNode,
+    idx: usize,
+    cell: SUnsafeCell<V>,
+}
+
+pub struct VacantEntry<'b, K, V, O = OrdOrder> {
+    map: &'b mut SBTreeMap<K, V, O>,
+    key: K,
+    key_buf: Vec<u8>,
+    path: BTreePath,
+    leaf: Option<BTreeNode>,
+    idx: usize,
+}
+
+impl<'a, 'b, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>, O: KeyOrder<K>>
+    Entry<'b, K, V, O>
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(it) => it.key(),
+            Entry::Vacant(it) => it.key(),
+        }
+    }
+
+    /// Inserts the default value, if the entry is vacant. Returns the value of the entry.
+    pub fn or_insert(self, default: V) -> V {
+        match self {
+            Entry::Occupied(it) => it.get(),
+            Entry::Vacant(it) => {
+                it.insert(&default);
+                default
+            }
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> V {
+        match self {
+            Entry::Occupied(it) => it.get(),
+            Entry::Vacant(it) => {
+                let value = f();
+                it.insert(&value);
+
+                value
+            }
+        }
+    }
+
+    /// Modifies the value, if the entry is occupied. The modified value is written back into the
+    /// same cell, unless it doesn't fit there anymore.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut it) => {
+                let mut value = it.get();
+                f(&mut value);
+                it.write(&value).expect("Out of stable memory");
+
+                Entry::Occupied(it)
+            }
+            Entry::Vacant(it) => Entry::Vacant(it),
+        }
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K,
+        V: Default + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        O: KeyOrder<K>,
+    > Entry<'b, K, V, O>
+{
+    pub fn or_default(self) -> V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, 'b, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>, O: KeyOrder<K>>
+    OccupiedEntry<'b, K, V, O>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> V {
+        self.cell.get_cloned()
+    }
+
+    /// Replaces the value of the entry, returning the previous one
+    pub fn insert(&mut self, value: &V) -> V {
+        let prev = self.get();
+        self.write(value).expect("Out of stable memory");
+
+        prev
+    }
+
+    pub fn remove(self) -> V {
+        self.map
+            .remove_at(self.path, self.leaf, self.idx)
+            .expect("Out of stable memory")
+    }
+
+    fn write(&mut self, value: &V) -> Result<(), OutOfMemory> {
+        SBTreeMap::<K, V, O>::write_value(&mut self.leaf, self.idx, &mut self.cell, value)
+    }
+}
+
+impl<'a, 'b, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>, O: KeyOrder<K>>
+    VacantEntry<'b, K, V, O>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(self, value: &V) {
+        self.try_insert(value).expect("Out of stable memory")
+    }
+
+    /// Same as `insert()`, but returns an error instead of panicking, when there is not enough
+    /// stable memory. The map is not modified in this case.
+    pub fn try_insert(self, value: &V) -> Result<(), OutOfMemory> {
+        self.map
+            .insert_at(self.path, self.leaf, self.idx, self.key_buf, value)
+    }
+}
+
+/// A lazy iterator over [SBTreeMap] entries, created by [SBTreeMap::iter] and [SBTreeMap::range].
+///
+/// Each end of the iterator only keeps its current leaf in heap memory and moves to the
+/// neighbouring one, once this leaf is over.
+pub struct SBTreeMapIter<'b, K, V, O, R> {
+    map: &'b SBTreeMap<K, V, O>,
+    range: R,
+    // bounds of the range, encoded with the order of the map
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // replaces the range, if set
+    prefix: Option<Vec<u8>>,
+    // the leaf and the index of the next key to return from it
+    front: Option<(BTreeNode, usize)>,
+    // the leaf and the number of its keys, which are not returned yet
+    back: Option<(BTreeNode, usize)>,
+    // leaves and indices of the last returned keys, so the ends can detect each other
+    front_pos: Option<(u64, usize)>,
+    back_pos: Option<(u64, usize)>,
+    finished: bool,
+}
+
+impl<
+        'a,
+        'b,
+        K,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        O: KeyOrder<K>,
+        R: RangeBounds<K>,
+    > SBTreeMapIter<'b, K, V, O, R>
+{
+    fn init_front(&self) -> Option<(BTreeNode, usize)> {
+        self.map.find_position(|it| self.before_start(it))
+    }
+
+    fn init_back(&self) -> Option<(BTreeNode, usize)> {
+        self.map.find_position(|it| self.before_end(it))
+    }
+
+    /// Whether the stored key goes before the first key of the iterator
+    fn before_start(&self, stored: &[u8]) -> bool {
+        if let Some(prefix) = &self.prefix {
+            return stored < prefix.as_slice();
+        }
+
+        match (self.range.start_bound(), &self.start) {
+            (Bound::Included(key), Bound::Included(key_buf)) => {
+                O::cmp(stored, key, key_buf) == Ordering::Less
+            }
+            (Bound::Excluded(key), Bound::Excluded(key_buf)) => {
+                O::cmp(stored, key, key_buf) != Ordering::Greater
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the stored key goes before the end of the iterator (or is its last key)
+    fn before_end(&self, stored: &[u8]) -> bool {
+        if let Some(prefix) = &self.prefix {
+            return stored < prefix.as_slice() || stored.starts_with(prefix);
+        }
+
+        match (self.range.end_bound(), &self.end) {
+            (Bound::Included(key), Bound::Included(key_buf)) => {
+                O::cmp(stored, key, key_buf) != Ordering::Greater
+            }
+            (Bound::Excluded(key), Bound::Excluded(key_buf)) => {
+                O::cmp(stored, key, key_buf) == Ordering::Less
+            }
+            _ => true,
+        }
+    }
+
+    fn finish(&mut self) -> Option<(K, V)> {
+        self.finished = true;
+        self.front = None;
+        self.back = None;
+
+        None
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        O: KeyOrder<K>,
+        R: RangeBounds<K>,
+    > Iterator for SBTreeMapIter<'b, K, V, O, R>
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if self.front.is_none() {
+            self.front = self.init_front();
+        }
+
+        let (leaf, idx) = match self.front.as_mut() {
+            Some(it) => it,
+            None => return self.finish(),
+        };
+
+        while *idx == leaf.keys.len() {
+            if leaf.next == 0 {
+                return self.finish();
+            }
+
+            *leaf = BTreeNode::read(leaf.next, self.map.page_size());
+            *idx = 0;
+        }
+
+        let pos = (leaf.ptr, *idx);
+        let key_buf = std::mem::take(&mut leaf.keys[*idx].buf);
+        let value_ptr = leaf.ptrs[*idx];
+        *idx += 1;
+
+        // both ends met or the range is over
+        if self.back_pos == Some(pos) || !self.before_end(&key_buf) {
+            return self.finish();
+        }
+        self.front_pos = Some(pos);
+        let key = O::decode(&key_buf);
+
+        Some((
+            key,
+            unsafe { SUnsafeCell::<V>::from_ptr(value_ptr) }.get_cloned(),
+        ))
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        O: KeyOrder<K>,
+        R: RangeBounds<K>,
+    > DoubleEndedIterator for SBTreeMapIter<'b, K, V, O, R>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if self.back.is_none() {
+            self.back = self.init_back();
+        }
+
+        let (leaf, idx) = match self.back.as_mut() {
+            Some(it) => it,
+            None => return self.finish(),
+        };
+
+        while *idx == 0 {
+            if leaf.prev == 0 {
+                return self.finish();
+            }
+
+            *leaf = BTreeNode::read(leaf.prev, self.map.page_size());
+            *idx = leaf.keys.len();
+        }
+
+        *idx -= 1;
+        let pos = (leaf.ptr, *idx);
+        let key_buf = std::mem::take(&mut leaf.keys[*idx].buf);
+        let value_ptr = leaf.ptrs[*idx];
+
+        if self.front_pos == Some(pos) || self.before_start(&key_buf) {
+            return self.finish();
+        }
+        self.back_pos = Some(pos);
+        let key = O::decode(&key_buf);
+
+        Some((
+            key,
+            unsafe { SUnsafeCell::<V>::from_ptr(value_ptr) }.get_cloned(),
+        ))
+    }
+}
+
+/// Is returned by [SBTreeMapCursor::seek_token], when the token is not a key of the map
+#[derive(Debug, Copy, Clone)]
+pub struct InvalidToken;
+
+/// A cursor over [SBTreeMap] entries, created by [SBTreeMap::cursor].
+///
+/// The cursor keeps the path from the root to its leaf, so moving to a neighbouring entry only
+/// reads pages, which the path doesn't have yet, and an entry is updated or removed without
+/// searching for it again. Past the last entry (and before the first one) the cursor is off the
+/// map - moving forward from there goes to the first entry, and moving back goes to the last one.
+///
+/// The position can be saved as a token (e.g. for a client to continue a listing in another
+/// message). A token stays valid whatever happens to the map - if its entry is removed, the
+/// cursor seeks the entry, which goes next.
+pub struct SBTreeMapCursor<'b, K, V, O = OrdOrder> {
+    map: &'b mut SBTreeMap<K, V, O>,
+    path: BTreePath,
+    // the leaf and the index of the entry under the cursor
+    leaf: Option<(BTreeNode, usize)>,
+}
+
+impl<'a, 'b, K, V: Readable<'a, LittleEndian> + Writable<LittleEndian>, O: KeyOrder<K>>
+    SBTreeMapCursor<'b, K, V, O>
+{
+    /// The entry under the cursor
+    pub fn current(&self) -> Option<(K, V)> {
+        let (leaf, idx) = self.leaf.as_ref()?;
+
+        Some((
+            O::decode(&leaf.keys[*idx]),
+            unsafe { SUnsafeCell::<V>::from_ptr(leaf.ptrs[*idx]) }.get_cloned(),
+        ))
+    }
+
+    pub fn key(&self) -> Option<K> {
+        let (leaf, idx) = self.leaf.as_ref()?;
+
+        Some(O::decode(&leaf.keys[*idx]))
+    }
+
+    pub fn value(&self) -> Option<V> {
+        let (leaf, idx) = self.leaf.as_ref()?;
+
+        Some(unsafe { SUnsafeCell::<V>::from_ptr(leaf.ptrs[*idx]) }.get_cloned())
+    }
+
+    /// Moves to the first entry, which key is not less than the key (or off the map, if there is
+    /// no such entry)
+    pub fn seek(&mut self, key: &K) {
+        let key_buf = O::encode(key);
+        self.seek_while(|it| O::cmp(it, key, &key_buf) == Ordering::Less);
+    }
+
+    /// The position of the cursor, which can be passed to `seek_token()` later. `None`, if the
+    /// cursor is off the map.
+    pub fn token(&self) -> Option<Vec<u8>> {
+        let (leaf, idx) = self.leaf.as_ref()?;
+
+        Some(leaf.keys[*idx].to_vec())
+    }
+
+    /// Moves to the entry of the token, or to the next one, if this entry is removed. Returns an
+    /// error and leaves the cursor where it was, if the token is malformed (tokens usually come
+    /// from clients, so they can't be trusted).
+    pub fn seek_token(&mut self, token: &[u8]) -> Result<(), InvalidToken> {
+        let key = O::try_decode(token).ok_or(InvalidToken)?;
+        self.seek(&key);
+
+        Ok(())
+    }
+
+    pub fn move_next(&mut self) {
+        match self.leaf.as_mut() {
+            Some((_, idx)) => {
+                *idx += 1;
+                self.skip_leaf_end();
+            }
+            None => self.seek_while(|_| false),
+        }
+    }
+
+    pub fn move_prev(&mut self) {
+        let ptr = match self.leaf.as_mut() {
+            Some((_, idx)) if *idx > 0 => {
+                *idx -= 1;
+                return;
+            }
+            Some(_) => {
+                self.leaf = None;
+
+                // up to the first ancestor, which has a child on the left
+                loop {
+                    match self.path.pop() {
+                        Some((_, 0)) => continue,
+                        Some((parent, idx)) => {
+                            self.path.push((parent, idx - 1));
+                            break;
+                        }
+                        None => return,
+                    }
+                }
+
+                let (parent, idx) = self.path.last().unwrap();
+                parent.ptrs[*idx]
+            }
+            None => {
+                if self.map.root == 0 {
+                    return;
+                }
+
+                self.map.root
+            }
+        };
+
+        // down to the rightmost leaf of this child
+        let mut node = BTreeNode::read(ptr, self.map.page_size());
+        while !node.is_leaf {
+            let child = node.ptrs.len() - 1;
+            let next = BTreeNode::read(node.ptrs[child], self.map.page_size());
+
+            self.path.push((node, child));
+            node = next;
+        }
+
+        let idx = node.keys.len() - 1;
+        self.leaf = Some((node, idx));
+    }
+
+    /// Replaces the value under the cursor, returning the previous one. Does nothing, if the
+    /// cursor is off the map.
+    pub fn set_value(&mut self, value: &V) -> Option<V> {
+        self.try_set_value(value).expect("Out of stable memory")
+    }
+
+    /// Same as `set_value()`, but returns an error instead of panicking, when there is not enough
+    /// stable memory for a bigger value
+    pub fn try_set_value(&mut self, value: &V) -> Result<Option<V>, OutOfMemory> {
+        let (leaf, idx) = match self.leaf.as_mut() {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+
+        let mut cell = unsafe { SUnsafeCell::<V>::from_ptr(leaf.ptrs[*idx]) };
+        let prev = cell.get_cloned();
+        SBTreeMap::<K, V, O>::write_value(leaf, *idx, &mut cell, value)?;
+
+        Ok(Some(prev))
+    }
+
+    /// Removes the entry under the cursor, moving the cursor to the next one
+    pub fn remove(&mut self) -> Option<(K, V)> {
+        self.try_remove().expect("Out of stable memory")
+    }
+
+    /// Same as `remove()`, but returns an error instead of panicking, when there is not enough
+    /// stable memory to rebalance the tree. Neither the map nor the cursor is modified in this
+    /// case.
+    pub fn try_remove(&mut self) -> Result<Option<(K, V)>, OutOfMemory> {
+        let (leaf, idx) = match self.leaf.as_ref() {
+            Some((leaf, idx)) => (leaf.clone(), *idx),
+            None => return Ok(None),
+        };
+
+        // the leaf is not rebalanced, unless it becomes too small, so the cursor can stay in it
+        let mut rest = leaf.clone();
+        let key_buf = rest.keys.remove(idx);
+        rest.ptrs.remove(idx);
+        let stays = if self.path.is_empty() {
+            !rest.keys.is_empty()
+        } else {
+            !self.map.underflows(&rest)
+        };
+
+        let value = self.map.remove_at(self.path.clone(), leaf, idx)?;
+        let key = O::decode(&key_buf);
+
+        if stays {
+            for (node, idx) in self.path.iter_mut() {
+                node.counts[*idx] -= 1;
+            }
+
+            self.leaf = Some((rest, idx));
+            self.skip_leaf_end();
+        } else {
+            self.seek_while(|it| O::cmp(it, &key, &key_buf) != Ordering::Greater);
+        }
+
+        Ok(Some((key, value)))
+    }
+
+    /// Moves to the first key, for which the predicate doesn't hold (see
+    /// `SBTreeMap::count_while()`)
+    fn seek_while<F: Fn(&[u8]) -> bool>(&mut self, f: F) {
+        self.path.clear();
+        self.leaf = None;
+
+        if self.map.root == 0 {
+            return;
+        }
+
+        let mut node = BTreeNode::read(self.map.root, self.map.page_size());
+        while !node.is_leaf {
+            let idx = node.keys.partition_point(|it| f(it));
+            let child = BTreeNode::read(node.ptrs[idx], self.map.page_size());
+
+            self.path.push((node, idx));
+            node = child;
+        }
+
+        let idx = node.keys.partition_point(|it| f(it));
+        self.leaf = Some((node, idx));
+        self.skip_leaf_end();
+    }
+
+    /// Moves from the end of the leaf to the beginning of the next one
+    fn skip_leaf_end(&mut self) {
+        match self.leaf.as_ref() {
+            Some((leaf, idx)) if *idx == leaf.keys.len() => {}
+            _ => return,
+        }
+        self.leaf = None;
+
+        // up to the first ancestor, which has a child on the right
+        loop {
+            match self.path.pop() {
+                Some((parent, idx)) if idx + 1 < parent.ptrs.len() => {
+                    self.path.push((parent, idx + 1));
+                    break;
+                }
+                Some(_) => continue,
+                None => return,
+            }
+        }
+
+        // down to the leftmost leaf of this child
+        let (parent, idx) = self.path.last().unwrap();
+        let mut node = BTreeNode::read(parent.ptrs[*idx], self.map.page_size());
+        while !node.is_leaf {
+            let next = BTreeNode::read(node.ptrs[0], self.map.page_size());
+
+            self.path.push((node, 0));
+            node = next;
+        }
+
+        self.leaf = Some((node, 0));
+    }
+}
+
+/// A copy of a page of [SBTreeMap] in heap memory
+#[derive(Clone)]
+struct BTreeNode {
+    ptr: u64,
+    is_leaf: bool,
+    keys: Vec<BTreeKey>,
+    // value cells of a leaf (one per key) or children of an internal node (one more than keys)
+    ptrs: Vec<u64>,
+    // the number of entries under each child of an internal node
+    counts: Vec<u64>,
+    prev: u64,
+    next: u64,
+}
+
+impl BTreeNode {
+    fn new(ptr: u64, is_leaf: bool) -> Self {
+        Self {
+            ptr,
+            is_leaf,
+            keys: Vec::new(),
+            ptrs: Vec::new(),
+            counts: Vec::new(),
+            prev: 0,
+            next: 0,
+        }
+    }
+
+    /// Allocates all the pages or none of them
+    fn reserve(count: usize, page_size: usize) -> Result<Vec<u64>, OutOfMemory> {
+        let mut pages = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            match try_allocate::<BTreePage>(page_size) {
+                Ok(page) => pages.push(page.ptr),
+                Err(e) => {
+                    Self::release(pages);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(pages)
+    }
+
+    fn allocate(page_size: usize) -> u64 {
+        let page: SSlice<BTreePage> = allocate(page_size);
+
+        page.ptr
+    }
+
+    fn release(pages: Vec<u64>) {
+        for ptr in pages {
+            deallocate(Self::page(ptr));
+        }
+    }
+
+    fn page(ptr: u64) -> SSlice<BTreePage> {
+        unsafe { SSlice::from_ptr(ptr, Side::Start).unwrap() }
+    }
+
+    fn read(ptr: u64, page_size: usize) -> Self {
+        let mut buf = vec![0u8; page_size];
+        Self::page(ptr)._read_bytes(0, &mut buf);
+
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+
+        let is_leaf = buf[0] == LEAF;
+        let count = u16::from_le_bytes(buf[1..3].try_into().unwrap()) as usize;
+
+        let mut node = Self::new(ptr, is_leaf);
+        if is_leaf {
+            node.prev = read_u64(PREV_OFFSET);
+            node.next = read_u64(PREV_OFFSET + 8);
+        } else {
+            node.ptrs.push(read_u64(PREV_OFFSET));
+            node.counts.push(read_u64(PREV_OFFSET + 8));
+        }
+
+        let mut offset = HEADER_SIZE;
+        for _ in 0..count {
+            let len = u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap());
+            offset += 2;
+
+            if len == LONG_KEY {
+                node.keys.push(BTreeKey::read_long(read_u64(offset)));
+                offset += 8;
+            } else {
+                node.keys.push(BTreeKey::inline(
+                    buf[offset..offset + len as usize].to_vec(),
+                ));
+                offset += len as usize;
+            }
+
+            node.ptrs.push(read_u64(offset));
+            offset += 8;
+
+            if !is_leaf {
+                node.counts.push(read_u64(offset));
+                offset += 8;
+            }
+        }
+
+        node
+    }
+
+    fn write(&self) {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.entries_size());
+
+        buf.push(if self.is_leaf { LEAF } else { INTERNAL });
+        buf.extend_from_slice(&(self.keys.len() as u16).to_le_bytes());
+
+        if self.is_leaf {
+            buf.extend_from_slice(&self.prev.to_le_bytes());
+            buf.extend_from_slice(&self.next.to_le_bytes());
+
+            for (key, ptr) in self.keys.iter().zip(&self.ptrs) {
+                key.write_to(&mut buf);
+                buf.extend_from_slice(&ptr.to_le_bytes());
+            }
+        } else {
+            buf.extend_from_slice(&self.ptrs[0].to_le_bytes());
+            buf.extend_from_slice(&self.counts[0].to_le_bytes());
+
+            for (idx, key) in self.keys.iter().enumerate() {
+                key.write_to(&mut buf);
+                buf.extend_from_slice(&self.ptrs[idx + 1].to_le_bytes());
+                buf.extend_from_slice(&self.counts[idx + 1].to_le_bytes());
+            }
+        }
+
+        Self::page(self.ptr)._write_bytes(0, &buf);
+    }
+
+    /// Updates the subtree count of a child of an internal node without rewriting the whole page
+    fn write_count(&self, idx: usize) {
+        let offset = if idx == 0 {
+            PREV_OFFSET + 8
+        } else {
+            HEADER_SIZE
+                + self.keys[..idx]
+                    .iter()
+                    .map(|it| INTERNAL_ENTRY_OVERHEAD + it.stored_size())
+                    .sum::<usize>()
+                - 8
+        };
+
+        Self::page(self.ptr)._write_bytes(offset, &self.counts[idx].to_le_bytes());
+    }
+
+    fn write_counts(path: BTreePath) {
+        for (node, idx) in path {
+            node.write_count(idx);
+        }
+    }
+
+    /// Updates the link to the previous leaf without reading the whole page
+    fn write_prev(ptr: u64, prev: u64) {
+        Self::page(ptr)._write_bytes(PREV_OFFSET, &prev.to_le_bytes());
+    }
+
+    fn free(self) {
+        deallocate(Self::page(self.ptr));
+    }
+
+    fn entries_size(&self) -> usize {
+        self.keys
+            .iter()
+            .map(|it| self.entry_overhead() + it.stored_size())
+            .sum()
+    }
+
+    fn entry_overhead(&self) -> usize {
+        if self.is_leaf {
+            LEAF_ENTRY_OVERHEAD
+        } else {
+            INTERNAL_ENTRY_OVERHEAD
+        }
+    }
+
+    /// The number of entries in the subtree of this node
+    fn len(&self) -> u64 {
+        if self.is_leaf {
+            self.keys.len() as u64
+        } else {
+            self.counts.iter().sum()
+        }
+    }
+}
+
+/// An encoded key of a page. A key, which is longer than [SBTreeMap::max_key_size], is stored out
+/// of line - its page only has a pointer to it. A separator of leaves is a copy of a leaf key, so
+/// such a copy shares the stored key with the leaf, and the stored key counts its references.
+#[derive(Clone, Default)]
+struct BTreeKey {
+    buf: Vec<u8>,
+    // the stored key or 0, if the key is stored right inside the page
+    long_ptr: u64,
+}
+
+impl BTreeKey {
+    fn inline(buf: Vec<u8>) -> Self {
+        Self { buf, long_ptr: 0 }
+    }
+
+    fn try_new_long(buf: Vec<u8>) -> Result<Self, OutOfMemory> {
+        let slice = try_allocate::<BTreeLongKey>(LONG_KEY_HEADER_SIZE + buf.len())?;
+
+        slice._write_bytes(0, &1u32.to_le_bytes());
+        slice._write_bytes(4, &(buf.len() as u32).to_le_bytes());
+        slice._write_bytes(LONG_KEY_HEADER_SIZE, &buf);
+
+        Ok(Self {
+            buf,
+            long_ptr: slice.ptr,
+        })
+    }
+
+    fn read_long(ptr: u64) -> Self {
+        let slice = Self::slice(ptr);
+
+        let mut len = [0u8; 4];
+        slice._read_bytes(4, &mut len);
+
+        let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+        slice._read_bytes(LONG_KEY_HEADER_SIZE, &mut buf);
+
+        Self { buf, long_ptr: ptr }
+    }
+
+    /// A copy of the key for another page
+    fn share(&self) -> Self {
+        if self.long_ptr != 0 {
+            let refs = self.refs();
+            Self::slice(self.long_ptr)._write_bytes(0, &(refs + 1).to_le_bytes());
+        }
+
+        self.clone()
+    }
+
+    /// Is called, once a page doesn't have the key anymore
+    fn release(self) {
+        if self.long_ptr == 0 {
+            return;
+        }
+
+        let refs = self.refs();
+        if refs == 1 {
+            deallocate(Self::slice(self.long_ptr));
+        } else {
+            Self::slice(self.long_ptr)._write_bytes(0, &(refs - 1).to_le_bytes());
+        }
+    }
+
+    fn refs(&self) -> u32 {
+        let mut refs = [0u8; 4];
+        Self::slice(self.long_ptr)._read_bytes(0, &mut refs);
+
+        u32::from_le_bytes(refs)
+    }
+
+    fn slice(ptr: u64) -> SSlice<BTreeLongKey> {
+        unsafe { SSlice::from_ptr(ptr, Side::Start).unwrap() }
+    }
+
+    /// The number of bytes, which the key takes in its page (not counting its length)
+    fn stored_size(&self) -> usize {
+        if self.long_ptr == 0 {
+            self.buf.len()
+        } else {
+            8
+        }
+    }
+
+    fn write_to(&self, page: &mut Vec<u8>) {
+        if self.long_ptr == 0 {
+            page.extend_from_slice(&(self.buf.len() as u16).to_le_bytes());
+            page.extend_from_slice(&self.buf);
+        } else {
+            page.extend_from_slice(&LONG_KEY.to_le_bytes());
+            page.extend_from_slice(&self.long_ptr.to_le_bytes());
+        }
+    }
+}
+
+impl Deref for BTreeKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::btree_map::{
+        BTreeKey, BTreeNode, Entry, LegacyBTreeKey, LegacyBTreeNode, LegacySBTreeMap, SBTreeMap,
+        HEADER_SIZE, MAX_PAGE_SIZE, MIN_PAGE_SIZE,
+    };
+    use crate::collections::btree_set::SBTreeSet;
+    use crate::primitive::ord_key::{ord_prefix, OrdKey};
+    use crate::utils::key_order::{ByComparator, Comparator, KeyOrder, MemcmpOrder};
+    use crate::SUnsafeCell;
+    use crate::{get_allocated_size, init_allocator, set_max_grow_pages, stable};
+    use speedy::{LittleEndian, Readable, Writable};
+    use std::cmp::Ordering;
+    use std::fmt::Debug;
+    use std::ops::Bound;
+
+    fn print_btree<
+        'a,
+        K: Debug,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        O: KeyOrder<K>,
+    >(
+        btree: &SBTreeMap<K, V, O>,
+    ) {
+        let mut level = if btree.root == 0 {
+            vec![]
+        } else {
+            vec![btree.root]
+        };
+
+        while !level.is_empty() {
+            let mut next_level = vec![];
+
+            for ptr in level {
+                let node = BTreeNode::read(ptr, btree.page_size());
+
+                print!(
+                    "{:?}",
+                    node.keys.iter().map(|it| O::decode(it)).collect::<Vec<_>>()
+                );
+
+                if !node.is_leaf {
+                    next_level.extend(node.ptrs);
+                }
+            }
+
+            println!();
+            level = next_level;
+        }
+    }
+
+    /// Checks, that keys are sorted and separated correctly, that all leaves are at the same depth and
+    /// linked in the right order, and that all pages fit
+    fn validate_btree<
+        'a,
+        K: Debug,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        O: KeyOrder<K>,
+    >(
+        btree: &SBTreeMap<K, V, O>,
+    ) {
+        if btree.root == 0 {
+            assert_eq!(btree.len(), 0);
+            return;
+        }
+
+        // node, depth, indices of the keys (of the parent), which bound keys of this node
+        let mut stack = vec![(btree.root, 0, None::<BTreeKey>, None::<BTreeKey>)];
+        let mut leaves = vec![];
+        let mut leaf_depth = None;
+        let mut len = 0;
+
+        while let Some((ptr, depth, min, max)) = stack.pop() {
+            let node = BTreeNode::read(ptr, btree.page_size());
+            let keys = node.keys.iter().map(|it| O::decode(it)).collect::<Vec<_>>();
+            let less = |a: &[u8], b: &[u8]| O::cmp(a, &O::decode(b), b) == Ordering::Less;
+
+            assert!(!btree.overflows(&node));
+            assert!(ptr == btree.root || !keys.is_empty());
+            assert!(
+                node.keys.windows(2).all(|it| less(&it[0], &it[1])),
+                "{:?}",
+                keys
+            );
+
+            if let (Some(min), Some(first)) = (&min, node.keys.first()) {
+                assert!(!less(first, min));
+            }
+            if let (Some(max), Some(last)) = (&max, node.keys.last()) {
+                assert!(less(last, max));
+            }
+
+            if node.is_leaf {
+                assert_eq!(*leaf_depth.get_or_insert(depth), depth);
+
+                len += keys.len() as u64;
+                leaves.push(node);
+                continue;
+            }
+
+            assert_eq!(node.ptrs.len(), keys.len() + 1);
+
+            // pushed in reverse, so leaves are visited from left to right
+            for idx in (0..node.ptrs.len()).rev() {
+                let min = if idx == 0 {
+                    min.clone()
+                } else {
+                    Some(node.keys[idx - 1].clone())
+                };
+                let max = if idx == keys.len() {
+                    max.clone()
+                } else {
+                    Some(node.keys[idx].clone())
+                };
+
+                stack.push((node.ptrs[idx], depth + 1, min, max));
+            }
+        }
+
+        assert_eq!(len, btree.len());
+
+        // checks subtree counts of internal nodes, returning the number of entries under the node
+        fn count_entries(ptr: u64, page_size: usize) -> u64 {
+            let node = BTreeNode::read(ptr, page_size);
+            if node.is_leaf {
+                return node.len();
+            }
+
+            assert_eq!(node.counts.len(), node.ptrs.len());
+            for (child, count) in node.ptrs.iter().zip(&node.counts) {
+                assert_eq!(count_entries(*child, page_size), *count);
+            }
+
+            node.len()
+        }
+        assert_eq!(count_entries(btree.root, btree.page_size()), btree.len());
+
+        for (idx, leaf) in leaves.iter().enumerate() {
+            let prev = if idx == 0 { 0 } else { leaves[idx - 1].ptr };
+            let next = leaves.get(idx + 1).map(|it| it.ptr).unwrap_or_default();
+
+            assert_eq!(leaf.prev, prev);
+            assert_eq!(leaf.next, next);
+        }
+    }
+
+    #[test]
+    fn random_works_as_expected() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let example = vec![
+            (10, 1),
+            (20, 2),
+            (30, 3),
+            (40, 4),
+            (50, 5),
+            (60, 6),
+            (70, 7),
+            (80, 8),
+            (90, 9),
+        ];
+
+        let mut map = SBTreeMap::<u64, u64>::new_with_page_size(MIN_PAGE_SIZE);
+
+        assert!(map.insert(30, &3).is_none());
+        print_btree(&map);
+
+        assert!(map.insert(90, &9).is_none());
+        print_btree(&map);
+
+        assert!(map.insert(10, &1).is_none());
+        print_btree(&map);
+
+        assert!(map.insert(70, &7).is_none());
+        print_btree(&map);
 
         assert!(map.insert(80, &8).is_none());
         print_btree(&map);
-        println!();
 
-        assert!(map.insert(50, &5).is_none());
-        print_btree(&map);
-        println!();
+        assert!(map.insert(50, &5).is_none());
+        print_btree(&map);
+
+        assert!(map.insert(20, &2).is_none());
+        print_btree(&map);
+
+        assert!(map.insert(60, &6).is_none());
+        print_btree(&map);
+
+        assert!(map.insert(40, &4).is_none());
+        print_btree(&map);
+
+        assert_eq!(map.len(), 9);
+
+        let probe = map.iter().collect::<Vec<_>>();
+        assert_eq!(example, probe);
+
+        assert_eq!(map.remove(&30).unwrap(), 3);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&70).unwrap(), 7);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&50).unwrap(), 5);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&40).unwrap(), 4);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&60).unwrap(), 6);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&20).unwrap(), 2);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&80).unwrap(), 8);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&10).unwrap(), 1);
+        print_btree(&map);
+
+        assert_eq!(map.remove(&90).unwrap(), 9);
+        print_btree(&map);
+
+        assert!(map.is_empty());
+
+        map.drop();
+    }
+
+    #[test]
+    fn sequential_works_as_expected() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, u64>::new_with_page_size(MIN_PAGE_SIZE);
+
+        for i in 0..100 {
+            map.insert(i, &0);
+        }
+        print_btree(&map);
+        validate_btree(&map);
+
+        for i in 0..100 {
+            map.remove(&i).unwrap();
+            validate_btree(&map);
+        }
+        print_btree(&map);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn entry_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, Vec<u64>>::new_with_page_size(MIN_PAGE_SIZE);
+
+        for i in 0..100u64 {
+            map.entry(i % 10).or_default();
+            // the value outgrows its cell every few updates
+            map.entry(i % 10)
+                .and_modify(|it| it.push(i))
+                .or_insert(vec![]);
+        }
+
+        assert_eq!(map.len(), 10);
+        for i in 0..10u64 {
+            let expected = (0..10).map(|j| i + j * 10).collect::<Vec<_>>();
+            assert_eq!(map.get(&i), Some(expected));
+        }
+
+        assert_eq!(map.entry(10).or_insert_with(|| vec![10]), vec![10]);
+        assert_eq!(map.entry(10).or_insert(vec![11]), vec![10]);
+
+        match map.entry(10) {
+            Entry::Occupied(mut it) => {
+                assert_eq!(*it.key(), 10);
+                assert_eq!(it.insert(&vec![12]), vec![10]);
+                assert_eq!(it.get(), vec![12]);
+                assert_eq!(it.remove(), vec![12]);
+            }
+            Entry::Vacant(_) => unreachable!(),
+        }
+
+        assert!(matches!(map.entry(10), Entry::Vacant(_)));
+        assert_eq!(map.len(), 10);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn iteration_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, u64>::new_with_page_size(MIN_PAGE_SIZE);
+        let mut expected = std::collections::BTreeMap::new();
+
+        assert!(map.iter().next().is_none());
+        assert!(map.first_key_value().is_none());
+        assert!(map.pop_last().is_none());
+
+        // only even keys, so bounds hit both existing and missing keys
+        let mut seed = 42u64;
+        for _ in 0..300 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (seed >> 33) % 200 * 2;
+
+            map.insert(key, &(key * 10));
+            expected.insert(key, key * 10);
+        }
+
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            expected.clone().into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            map.iter().rev().collect::<Vec<_>>(),
+            expected.clone().into_iter().rev().collect::<Vec<_>>()
+        );
+
+        for (from, to) in [(0, 400), (10, 11), (11, 12), (33, 250), (398, 400)] {
+            assert_eq!(
+                map.range(from..to).collect::<Vec<_>>(),
+                expected
+                    .range(from..to)
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                map.range(from..=to).rev().collect::<Vec<_>>(),
+                expected
+                    .range(from..=to)
+                    .rev()
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                map.range((Bound::Excluded(from), Bound::Unbounded))
+                    .collect::<Vec<_>>(),
+                expected
+                    .range((Bound::Excluded(from), Bound::Unbounded))
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                map.range(..to).rev().collect::<Vec<_>>(),
+                expected
+                    .range(..to)
+                    .rev()
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        assert_eq!(
+            map.range((Bound::Included(250), Bound::Excluded(33)))
+                .count(),
+            0
+        );
+        assert_eq!(
+            map.range((Bound::Included(250), Bound::Included(33)))
+                .rev()
+                .count(),
+            0
+        );
+
+        // both ends meet in the middle
+        let mut iter = map.range(100..200);
+        let mut expected_iter = expected.range(100..200).map(|(k, v)| (*k, *v));
+        loop {
+            let it = iter.next();
+            assert_eq!(it, expected_iter.next());
+
+            let it_back = iter.next_back();
+            assert_eq!(it_back, expected_iter.next_back());
+
+            if it.is_none() || it_back.is_none() {
+                break;
+            }
+        }
+        assert!(iter.next().is_none());
+
+        assert_eq!(
+            map.first_key_value(),
+            expected.first_key_value().map(|(k, v)| (*k, *v))
+        );
+        assert_eq!(
+            map.last_key_value(),
+            expected.last_key_value().map(|(k, v)| (*k, *v))
+        );
+
+        while !expected.is_empty() {
+            assert_eq!(map.pop_first(), expected.pop_first());
+            assert_eq!(map.pop_last(), expected.pop_last());
+            assert_eq!(map.len(), expected.len() as u64);
+        }
+
+        assert!(map.is_empty());
+        assert!(map.pop_first().is_none());
+
+        map.drop();
+    }
+
+    #[test]
+    fn randomized_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        for page_size in [MIN_PAGE_SIZE, 512, 4096] {
+            let mut map = SBTreeMap::<u64, u64>::new_with_page_size(page_size);
+            let mut expected = std::collections::BTreeMap::new();
 
-        assert!(map.insert(20, &2).is_none());
-        print_btree(&map);
-        println!();
+            // lcg - keys are from a small range, so there are a lot of overwrites and removals
+            let mut seed = page_size as u64;
+            for i in 0..3000u64 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let key = (seed >> 33) % 600;
 
-        assert!(map.insert(60, &6).is_none());
-        print_btree(&map);
-        println!();
+                if seed % 3 == 1 {
+                    assert_eq!(map.remove(&key), expected.remove(&key));
+                } else {
+                    assert_eq!(map.insert(key, &i), expected.insert(key, i));
+                }
 
-        assert!(map.insert(40, &4).is_none());
-        print_btree(&map);
-        println!();
+                assert_eq!(map.len(), expected.len() as u64);
 
-        assert_eq!(map.len(), 9);
+                if i % 100 == 0 {
+                    validate_btree(&map);
+                }
+            }
 
-        let mut probe = vec![];
-        btree_to_sorted_vec(&map.root, &mut probe);
-        assert_eq!(example, probe);
+            validate_btree(&map);
+            assert_eq!(
+                map.iter().collect::<Vec<_>>(),
+                expected.into_iter().collect::<Vec<_>>()
+            );
 
-        println!("DELETION");
+            map.drop();
+            assert_eq!(get_allocated_size(), 0);
+        }
+    }
 
-        assert_eq!(map.remove(&30).unwrap(), 3);
-        print_btree(&map);
-        println!();
+    #[test]
+    fn variable_size_keys_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
 
-        assert_eq!(map.remove(&70).unwrap(), 7);
-        print_btree(&map);
-        println!();
+        let mut map = SBTreeMap::<String, u64>::new_with_page_size(MIN_PAGE_SIZE);
+        let mut expected = std::collections::BTreeMap::new();
 
-        assert_eq!(map.remove(&50).unwrap(), 5);
-        print_btree(&map);
-        println!();
+        let mut seed = 7u64;
+        for i in 0..2000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
 
-        assert_eq!(map.remove(&40).unwrap(), 4);
-        print_btree(&map);
-        println!();
+            // from 1 to 7 characters - the longest key still fits
+            let len = (seed >> 60) as usize % 7 + 1;
+            let key = format!("{:0>width$}", (seed >> 33) % 500, width = len);
 
-        assert_eq!(map.remove(&60).unwrap(), 6);
-        print_btree(&map);
-        println!();
+            if seed % 4 == 1 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key.clone(), &i), expected.insert(key, i));
+            }
+        }
 
-        assert_eq!(map.remove(&20).unwrap(), 2);
-        print_btree(&map);
-        println!();
+        validate_btree(&map);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            expected.into_iter().collect::<Vec<_>>()
+        );
 
-        assert_eq!(map.remove(&80).unwrap(), 8);
-        print_btree(&map);
-        println!();
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
 
-        assert_eq!(map.remove(&10).unwrap(), 1);
-        print_btree(&map);
-        println!();
+    #[test]
+    fn out_of_memory_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+        // never grow, so the memory runs out soon
+        set_max_grow_pages(1);
+
+        let mut map = SBTreeMap::<u64, Vec<u8>>::new_with_page_size(MIN_PAGE_SIZE);
+        let mut expected = std::collections::BTreeMap::new();
+        let mut failures = 0;
+
+        let mut seed = 13u64;
+        for i in 0..5000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (seed >> 33) % 1000;
+
+            // values of different sizes, so existing cells have to grow too
+            if seed % 5 == 1 {
+                match map.try_remove(&key) {
+                    Ok(it) => assert_eq!(it, expected.remove(&key)),
+                    Err(_) => failures += 1,
+                }
+            } else {
+                let value = vec![0u8; (i % 50) as usize];
 
-        assert_eq!(map.remove(&90).unwrap(), 9);
-        print_btree(&map);
-        println!();
+                match map.try_insert(key, &value) {
+                    Ok(it) => assert_eq!(it, expected.insert(key, value)),
+                    Err(_) => failures += 1,
+                }
+            }
+
+            assert_eq!(map.len(), expected.len() as u64);
+
+            if i % 100 == 0 {
+                validate_btree(&map);
+            }
+        }
+
+        assert!(failures > 0);
+
+        validate_btree(&map);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            expected.into_iter().collect::<Vec<_>>()
+        );
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn drop_step_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, u64>::new_with_page_size(MIN_PAGE_SIZE);
+        for i in 0..2000 {
+            map.insert(i, &i);
+        }
+
+        let mut steps = 0;
+        while !map.drop_step(5) {
+            steps += 1;
+
+            // what is left is still a correct map
+            let rest = map.iter().map(|(k, _)| k).collect::<Vec<_>>();
+            assert_eq!(rest.len() as u64, map.len());
+            assert_eq!(rest, (2000 - map.len()..2000).collect::<Vec<_>>());
+            assert_eq!(map.iter().rev().count() as u64, map.len());
+            assert_eq!(map.select(0).map(|(k, _)| k), rest.first().copied());
+            assert_eq!(map.len_in_range(..), map.len());
+        }
 
+        assert!(steps > 1);
         assert!(map.is_empty());
+        assert!(map.drop_step(5));
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn order_statistics_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, u64>::new_with_page_size(MIN_PAGE_SIZE);
+        let mut expected = std::collections::BTreeMap::new();
+
+        assert_eq!(map.rank(&10), 0);
+        assert!(map.select(0).is_none());
+        assert_eq!(map.len_in_range(..), 0);
+
+        let mut seed = 21u64;
+        for i in 0..3000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (seed >> 33) % 1000;
+
+            if seed % 3 == 1 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, &i), expected.insert(key, i));
+            }
+
+            if i % 100 == 0 {
+                validate_btree(&map);
+            }
+
+            if i % 10 == 0 {
+                let probe = (seed >> 13) % 1000;
+
+                assert_eq!(map.rank(&probe), expected.range(..probe).count() as u64);
+                assert_eq!(
+                    map.len_in_range(probe..probe + 100),
+                    expected.range(probe..probe + 100).count() as u64
+                );
+                assert_eq!(
+                    map.len_in_range((Bound::Excluded(probe), Bound::Included(probe + 50))),
+                    expected
+                        .range((Bound::Excluded(probe), Bound::Included(probe + 50)))
+                        .count() as u64
+                );
+
+                let n = probe % (expected.len() as u64 + 1);
+                assert_eq!(
+                    map.select(n),
+                    expected.iter().nth(n as usize).map(|(k, v)| (*k, *v))
+                );
+            }
+        }
+
+        validate_btree(&map);
+
+        for (n, (key, value)) in expected.iter().enumerate() {
+            assert_eq!(map.rank(key), n as u64);
+            assert_eq!(map.select(n as u64), Some((*key, *value)));
+        }
+
+        assert_eq!(map.len_in_range(..), map.len());
+        assert_eq!(
+            map.len_in_range((Bound::Included(500), Bound::Excluded(100))),
+            0
+        );
 
         map.drop();
+        assert_eq!(get_allocated_size(), 0);
     }
 
     #[test]
-    fn sequential_works_as_expected() {
+    fn append_sorted_works_fine() {
         stable::clear();
         stable::grow(1).unwrap();
         init_allocator(0);
 
-        let mut map = SBTreeMap::<u64, u64>::new_with_degree(2);
+        for page_size in [MIN_PAGE_SIZE, 4096] {
+            let mut map = SBTreeMap::<u64, u64>::new_with_page_size(page_size);
+            map.append_sorted((0..10).map(|it| (it, it)));
+            validate_btree(&map);
 
-        println!("INSERTION");
+            // an import, split into chunks
+            let mut entries = (10..20_000u64).map(|it| (it, it * 2));
+            loop {
+                let len = map.len();
+                map.append_sorted(entries.by_ref().take(3000));
+                validate_btree(&map);
 
-        for i in 0..10 {
-            map.insert(i, &0);
-            print_btree(&map);
-            println!();
+                if map.len() == len {
+                    break;
+                }
+            }
+
+            assert_eq!(map.len(), 20_000);
+            assert_eq!(map.select(15_000), Some((15_000, 30_000)));
+            assert_eq!(map.rank(&15_000), 15_000);
+            assert_eq!(map.get(&9), Some(9));
+            assert_eq!(map.get(&19_999), Some(39_998));
+            assert_eq!(map.iter().rev().count(), 20_000);
+
+            // packed pages still split and merge as usual
+            for i in (0..20_000).step_by(3) {
+                assert!(map.remove(&i).is_some());
+            }
+            for i in 20_000..21_000 {
+                map.insert(i, &i);
+                map.insert(i * 7 % 20_000, &0);
+            }
+            validate_btree(&map);
+
+            map.drop();
+            assert_eq!(get_allocated_size(), 0);
         }
 
-        println!("DELETION");
+        let map = SBTreeMap::<u64, u64>::from_sorted_iter((0..100).map(|it| (it, it)));
+        validate_btree(&map);
+        assert_eq!(map.len(), 100);
 
-        for i in 0..10 {
-            map.remove(&i).unwrap();
-            print_btree(&map);
-            println!();
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Keys are not sorted")]
+    fn append_unsorted_panics() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, u64>::from_sorted_iter([(1, 1), (5, 5)]);
+        map.append_sorted([(3, 3)]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn new_with_degree_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        assert_eq!(
+            SBTreeMap::<u64, u64>::new_with_degree(2).page_size(),
+            MIN_PAGE_SIZE
+        );
+        assert_eq!(
+            SBTreeMap::<u64, u64>::new_with_degree(64).page_size(),
+            HEADER_SIZE + 127 * 26
+        );
+        assert_eq!(
+            SBTreeMap::<u64, u64>::new_with_degree(32768).page_size(),
+            MAX_PAGE_SIZE
+        );
+
+        let mut map = SBTreeMap::<u64, u64>::new_with_degree(3);
+        for i in 0..1000 {
+            map.insert(i, &i);
+        }
+        validate_btree(&map);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn max_page_size_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        // a leaf of u16 keys fits more keys than the previous version of the tree could count
+        let mut map = SBTreeMap::<u16, ()>::new_with_page_size(MAX_PAGE_SIZE);
+        map.append_sorted((0..u16::MAX).map(|it| (it, ())));
+        map.insert(u16::MAX, &());
+
+        assert_eq!(map.len(), u16::MAX as u64 + 1);
+        validate_btree(&map);
+
+        for i in (0..=u16::MAX).step_by(1000) {
+            assert_eq!(map.get(&i), Some(()));
+        }
+        assert_eq!(map.iter().count(), u16::MAX as usize + 1);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to create BTree with pages bigger than")]
+    fn too_big_pages_panic() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        SBTreeMap::<u64, u64>::new_with_page_size(MAX_PAGE_SIZE + 1);
+    }
+
+    #[test]
+    fn long_keys_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<String, u64>::new_with_page_size(MIN_PAGE_SIZE);
+        let mut expected = std::collections::BTreeMap::new();
+
+        let mut seed = 11u64;
+        for i in 0..2000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+
+            // from 1 to 300 characters - most keys don't fit into a page
+            let len = (seed >> 52) as usize % 300 + 1;
+            let key = format!("{:0>width$}", (seed >> 33) % 400, width = len);
+
+            if seed % 4 == 1 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key.clone(), &i), expected.insert(key, i));
+            }
+
+            if i % 100 == 0 {
+                validate_btree(&map);
+            }
+        }
+
+        validate_btree(&map);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            expected.clone().into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            map.iter().rev().collect::<Vec<_>>(),
+            expected.clone().into_iter().rev().collect::<Vec<_>>()
+        );
+
+        let mut cursor = map.cursor();
+        cursor.move_next();
+        while let Some((key, value)) = cursor.remove() {
+            assert_eq!(expected.remove(&key), Some(value));
+        }
+        assert!(map.is_empty());
+        assert_eq!(get_allocated_size(), 0);
+
+        let long = "a".repeat(map.max_key_size() + 1);
+        let map = SBTreeMap::<String, u64>::from_sorted_iter(
+            (0..500u64).map(|i| (format!("{}{:03}", long, i), i)),
+        );
+        validate_btree(&map);
+        assert_eq!(map.get(&format!("{}{:03}", long, 250)), Some(250));
+
+        let mut map = map;
+        while !map.drop_step(1) {}
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn memcmp_order_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map =
+            SBTreeMap::<(String, u64), u64, MemcmpOrder>::new_with_order_and_page_size(512);
+        let mut expected = std::collections::BTreeMap::new();
+
+        let mut seed = 7u64;
+        for i in 0..3000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let user = format!("user/{}", (seed >> 40) % 30);
+            let key = (user, (seed >> 20) % 50);
+
+            if seed % 4 == 1 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key.clone(), &i), expected.insert(key, i));
+            }
+
+            if i % 100 == 0 {
+                validate_btree(&map);
+            }
+        }
+
+        validate_btree(&map);
+        assert!(map.iter().eq(expected.clone().into_iter()));
+        assert!(map.iter().rev().eq(expected.clone().into_iter().rev()));
+
+        let from = (String::from("user/1"), 10);
+        let to = (String::from("user/2"), 0);
+        assert!(map
+            .range(&from..&to)
+            .map(|(k, _)| k)
+            .eq(expected.range(&from..&to).map(|(k, _)| k.clone())));
+        assert_eq!(map.rank(&to), expected.range(..&to).count() as u64);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    struct Desc;
+
+    impl Comparator<u64> for Desc {
+        fn cmp(a: &u64, b: &u64) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn comparator_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map =
+            SBTreeMap::<u64, u64, ByComparator<Desc>>::new_with_order_and_page_size(MIN_PAGE_SIZE);
+        for i in 0..1000 {
+            map.insert(i * 7 % 1000, &i);
+        }
+        validate_btree(&map);
+
+        assert_eq!(map.first_key_value().map(|(k, _)| k), Some(999));
+        assert_eq!(map.last_key_value().map(|(k, _)| k), Some(0));
+        assert!(map.range(&500..=&400).map(|(k, _)| k).eq((400..=500).rev()));
+        assert_eq!(map.rank(&900), 99);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn prefix_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<String, u64, MemcmpOrder>::new_with_order_and_page_size(512);
+        let mut expected = std::collections::BTreeMap::new();
+
+        for i in 0..2000u64 {
+            let key = format!("user/{}/orders/{}", i % 13, i * 7 % 100);
+            map.insert(key.clone(), &i);
+            expected.insert(key, i);
+        }
+        map.insert(String::from("user/1\0"), &0);
+        expected.insert(String::from("user/1\0"), 0);
+        validate_btree(&map);
+
+        for prefix in [
+            "",
+            "user/",
+            "user/1",
+            "user/1/",
+            "user/12/orders/9",
+            "user/5/x",
+            "z",
+        ] {
+            let encoded = ord_prefix(prefix.as_bytes());
+            let matching = expected
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect::<Vec<_>>();
+
+            assert!(map.prefix(&encoded).eq(matching.clone()), "{}", prefix);
+            assert!(map
+                .prefix(&encoded)
+                .rev()
+                .eq(matching.iter().cloned().rev()));
+            assert_eq!(map.prefix_count(&encoded), matching.len() as u64);
+        }
+
+        map.drop();
+
+        // the first parts of composite keys
+        let mut map = SBTreeMap::<(String, u64), u64, MemcmpOrder>::new_with_order();
+        for i in 0..300u64 {
+            map.insert((format!("user/{}", i % 11), i), &i);
+        }
+
+        let user = String::from("user/1").to_ord_bytes();
+        assert!(map
+            .prefix(&user)
+            .map(|(k, _)| k.1)
+            .eq((0..300).filter(|it| it % 11 == 1)));
+        assert_eq!(map.prefix_count(&user), 28);
+        assert_eq!(map.prefix_count(&ord_prefix(b"user/1")), 28 + 27);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn cursor_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SBTreeMap::<u64, String>::new_with_page_size(MIN_PAGE_SIZE);
+        let mut expected = std::collections::BTreeMap::new();
+
+        let mut cursor = map.cursor();
+        cursor.move_next();
+        assert!(cursor.current().is_none());
+
+        for i in 0..1000u64 {
+            map.insert(i * 2, &i.to_string());
+            expected.insert(i * 2, i.to_string());
+        }
+
+        // both ways, across leaves and off the map
+        let mut cursor = map.cursor();
+        cursor.seek(&301);
+        assert_eq!(cursor.current(), Some((302, String::from("151"))));
+        for (key, _) in expected.range(302..) {
+            assert_eq!(cursor.key(), Some(*key));
+            cursor.move_next();
+        }
+        assert!(cursor.key().is_none());
+
+        cursor.move_prev();
+        for (key, _) in expected.iter().rev() {
+            assert_eq!(cursor.key(), Some(*key));
+            cursor.move_prev();
+        }
+        assert!(cursor.key().is_none());
+        cursor.move_next();
+        assert_eq!(cursor.key(), Some(0));
+
+        cursor.seek(&2000);
+        assert!(cursor.key().is_none());
+
+        // updates and removals on the way
+        cursor.seek(&100);
+        while let Some(key) = cursor.key() {
+            if key % 3 == 0 {
+                let (_, value) = cursor.remove().unwrap();
+                assert_eq!(expected.remove(&key), Some(value));
+            } else {
+                let value = format!("updated value of {}", key);
+                assert_eq!(
+                    cursor.set_value(&value),
+                    expected.insert(key, value.clone())
+                );
+                cursor.move_next();
+            }
+        }
+        assert!(cursor.remove().is_none());
+        assert!(cursor.set_value(&String::new()).is_none());
+
+        validate_btree(&map);
+        assert!(map.iter().eq(expected.clone().into_iter()));
+
+        // tokens outlive cursors and the entries they point to
+        let mut cursor = map.cursor();
+        cursor.seek(&500);
+        let token = cursor.token().unwrap();
+        assert!(map.remove(&500).is_some());
+
+        let mut cursor = map.cursor();
+        cursor.seek_token(&token).unwrap();
+        assert_eq!(cursor.key(), Some(502));
+
+        // malformed tokens don't move the cursor
+        assert!(cursor.seek_token(&[1, 2, 3]).is_err());
+        assert!(cursor
+            .seek_token(&[token.clone(), vec![0]].concat())
+            .is_err());
+        assert_eq!(cursor.key(), Some(502));
+
+        // removing everything
+        let mut cursor = map.cursor();
+        cursor.move_prev();
+        while cursor.key().is_some() {
+            cursor.remove();
+            cursor.move_prev();
+        }
+
+        let mut cursor = map.cursor();
+        cursor.move_next();
+        while cursor.key().is_some() {
+            cursor.remove();
+        }
+
+        assert!(map.is_empty());
+        validate_btree(&map);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    fn legacy_node(
+        is_root: bool,
+        keys: &[u64],
+        children: Vec<LegacyBTreeNode<u64, u64>>,
+    ) -> LegacyBTreeNode<u64, u64> {
+        LegacyBTreeNode {
+            is_leaf: children.is_empty(),
+            is_root,
+            keys: keys
+                .iter()
+                .map(|it| {
+                    SUnsafeCell::new(&LegacyBTreeKey {
+                        key: *it,
+                        value_cell: SUnsafeCell::new(&(it * 10)),
+                    })
+                })
+                .collect(),
+            children: children.iter().map(SUnsafeCell::new).collect(),
         }
+    }
+
+    #[test]
+    fn legacy_maps_are_rejected_and_migrated() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        // a map, as it was stored by 0.2.x
+        let leaves = vec![
+            legacy_node(false, &[1, 2, 3], vec![]),
+            legacy_node(false, &[11, 12], vec![]),
+            legacy_node(false, &[21, 22, 23, 24], vec![]),
+        ];
+        let legacy = LegacySBTreeMap {
+            _root: legacy_node(true, &[10, 20], leaves),
+            _degree: 4096,
+            _len: 11,
+        };
+        let buf = legacy.write_to_vec().unwrap();
+
+        assert!(SBTreeMap::<u64, u64>::read_from_buffer_copying_data(&buf).is_err());
+        assert!(SBTreeSet::<u64>::read_from_buffer_copying_data(&buf).is_err());
+
+        let legacy = LegacySBTreeMap::<u64, u64>::read_from_buffer_copying_data(&buf).unwrap();
+        assert_eq!(legacy.len(), 11);
+
+        let map = legacy.migrate();
+        validate_btree(&map);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [1, 2, 3, 10, 11, 12, 20, 21, 22, 23, 24]
+                .into_iter()
+                .map(|it| (it, it * 10))
+                .collect::<Vec<_>>()
+        );
+
+        let buf = map.write_to_vec().unwrap();
+        let map = SBTreeMap::<u64, u64>::read_from_buffer_copying_data(&buf).unwrap();
+        assert_eq!(map.len(), 11);
 
         map.drop();
+        assert_eq!(get_allocated_size(), initial_allocated);
+
+        // a set, as it was stored by 0.2.x
+        let legacy = LegacySBTreeMap::<u64, ()> {
+            _root: LegacyBTreeNode {
+                is_leaf: true,
+                is_root: true,
+                keys: (0..5u64)
+                    .map(|key| {
+                        SUnsafeCell::new(&LegacyBTreeKey {
+                            key,
+                            value_cell: SUnsafeCell::new(&()),
+                        })
+                    })
+                    .collect(),
+                children: Vec::new(),
+            },
+            _degree: 4096,
+            _len: 5,
+        };
+
+        let set = legacy.migrate_set();
+        assert_eq!(set.iter().collect::<Vec<_>>(), (0..5).collect::<Vec<_>>());
+
+        set.drop();
+        assert_eq!(get_allocated_size(), initial_allocated);
     }
 }
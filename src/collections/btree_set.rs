@@ -1,9 +1,17 @@
-use crate::collections::btree_map::SBTreeMap;
+use crate::collections::btree_map::{LegacySBTreeMap, SBTreeMap, SBTreeMapIter};
+use crate::primitive::ord_key::OrdKey;
+use crate::utils::key_order::{KeyOrder, MemcmpOrder, OrdOrder};
+use crate::OutOfMemory;
 use speedy::{LittleEndian, Readable, Writable};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::iter::Peekable;
+use std::marker::PhantomData;
+use std::ops::{RangeBounds, RangeFull};
 
 #[derive(Readable, Writable)]
-pub struct SBTreeSet<T> {
-    map: SBTreeMap<T, ()>,
+pub struct SBTreeSet<T, O = OrdOrder> {
+    map: SBTreeMap<T, (), O>,
 }
 
 impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> SBTreeSet<T> {
@@ -13,20 +21,74 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> SBTreeSet
         }
     }
 
+    pub fn new_with_page_size(page_size: usize) -> Self {
+        Self {
+            map: SBTreeMap::new_with_page_size(page_size),
+        }
+    }
+
+    /// See [SBTreeMap::new_with_degree]
+    #[deprecated(note = "pages are sized in bytes now - use new_with_page_size()")]
+    #[allow(deprecated)]
     pub fn new_with_degree(degree: usize) -> Self {
         Self {
             map: SBTreeMap::new_with_degree(degree),
         }
     }
 
+    /// See [SBTreeMap::from_sorted_iter]
+    pub fn from_sorted_iter<I: IntoIterator<Item = T>>(values: I) -> Self {
+        Self {
+            map: SBTreeMap::from_sorted_iter(values.into_iter().map(|it| (it, ()))),
+        }
+    }
+}
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> LegacySBTreeMap<T, ()> {
+    /// Same as [LegacySBTreeMap::migrate], but for a set stored by versions 0.2.x
+    pub fn migrate_set(self) -> SBTreeSet<T> {
+        SBTreeSet {
+            map: self.migrate(),
+        }
+    }
+}
+
+impl<T, O: KeyOrder<T>> SBTreeSet<T, O> {
+    /// See [SBTreeMap::new_with_order]
+    pub fn new_with_order() -> Self {
+        Self {
+            map: SBTreeMap::new_with_order(),
+        }
+    }
+
+    pub fn new_with_order_and_page_size(page_size: usize) -> Self {
+        Self {
+            map: SBTreeMap::new_with_order_and_page_size(page_size),
+        }
+    }
+
+    /// See [SBTreeMap::append_sorted]
+    pub fn append_sorted<I: IntoIterator<Item = T>>(&mut self, values: I) {
+        self.map
+            .append_sorted(values.into_iter().map(|it| (it, ())))
+    }
+
     pub fn insert(&mut self, value: T) -> bool {
         self.map.insert(value, &()).is_some()
     }
 
+    pub fn try_insert(&mut self, value: T) -> Result<bool, OutOfMemory> {
+        self.map.try_insert(value, &()).map(|it| it.is_some())
+    }
+
     pub fn remove(&mut self, value: &T) -> bool {
         self.map.remove(value).is_some()
     }
 
+    pub fn try_remove(&mut self, value: &T) -> Result<bool, OutOfMemory> {
+        self.map.try_remove(value).map(|it| it.is_some())
+    }
+
     pub fn contains(&self, value: &T) -> bool {
         self.map.contains_key(value)
     }
@@ -39,13 +101,340 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> SBTreeSet
         self.map.is_empty()
     }
 
+    /// See [SBTreeMap::rank]
+    pub fn rank(&self, value: &T) -> u64 {
+        self.map.rank(value)
+    }
+
+    /// The n-th smallest value (from 0)
+    pub fn select(&self, n: u64) -> Option<T> {
+        self.map.select(n).map(|(it, _)| it)
+    }
+
+    pub fn len_in_range<R: RangeBounds<T>>(&self, range: R) -> u64 {
+        self.map.len_in_range(range)
+    }
+
     pub fn drop(self) {
         self.map.drop()
     }
+
+    /// See [SBTreeMap::drop_step]
+    pub fn drop_step(&mut self, leaves: usize) -> bool {
+        self.map.drop_step(leaves)
+    }
+
+    /// Iterates over all values in ascending order
+    pub fn iter(&self) -> SBTreeSetIter<'_, T, O, RangeFull> {
+        SBTreeSetIter {
+            iter: self.map.iter(),
+        }
+    }
+
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> SBTreeSetIter<'_, T, O, R> {
+        SBTreeSetIter {
+            iter: self.map.range(range),
+        }
+    }
+
+    /// Removes all values, for which the predicate doesn't hold
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let mut cursor = self.map.cursor();
+        cursor.move_next();
+
+        while let Some(value) = cursor.key() {
+            if f(&value) {
+                cursor.move_next();
+            } else {
+                cursor.remove();
+            }
+        }
+    }
+
+    /// Values of both sets in ascending order. The other set is iterated in the order of this
+    /// set (see [SortedSet]), so the sets are merged in one pass:
+    /// ```ignore
+    /// let all = stable_set.union(&other_stable_set);
+    /// let common = stable_set.intersection(&std_btree_set);
+    /// ```
+    ///
+    /// A set, which is not sorted this way (e.g. a `HashSet`), is better combined by lookups:
+    /// `stable_set.iter().filter(|it| hash_set.contains(it))`.
+    pub fn union<'b, S: SortedSet<T, O>>(&'b self, other: &'b S) -> SBTreeSetMerge<'b, T, O> {
+        self.merge(other, MergeKind::Union)
+    }
+
+    /// Values of this set, which are in the other one too
+    pub fn intersection<'b, S: SortedSet<T, O>>(
+        &'b self,
+        other: &'b S,
+    ) -> SBTreeSetMerge<'b, T, O> {
+        self.merge(other, MergeKind::Intersection)
+    }
+
+    /// Values of this set, which are not in the other one
+    pub fn difference<'b, S: SortedSet<T, O>>(&'b self, other: &'b S) -> SBTreeSetMerge<'b, T, O> {
+        self.merge(other, MergeKind::Difference)
+    }
+
+    /// Values, which are in exactly one of the sets
+    pub fn symmetric_difference<'b, S: SortedSet<T, O>>(
+        &'b self,
+        other: &'b S,
+    ) -> SBTreeSetMerge<'b, T, O> {
+        self.merge(other, MergeKind::SymmetricDifference)
+    }
+
+    /// Whether all values of this set are in the other one
+    pub fn is_subset<S: SortedSet<T, O>>(&self, other: &S) -> bool {
+        self.difference(other).next().is_none()
+    }
+
+    /// Whether the sets have no values in common
+    pub fn is_disjoint<S: SortedSet<T, O>>(&self, other: &S) -> bool {
+        self.intersection(other).next().is_none()
+    }
+
+    fn merge<'b, S: SortedSet<T, O>>(
+        &'b self,
+        other: &'b S,
+        kind: MergeKind,
+    ) -> SBTreeSetMerge<'b, T, O> {
+        SBTreeSetMerge {
+            a: self.iter().peekable(),
+            b: other.sorted_values().peekable(),
+            kind,
+            _o: PhantomData,
+        }
+    }
 }
 
-impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Ord> Default for SBTreeSet<T> {
+impl<T, O: KeyOrder<T>> Default for SBTreeSet<T, O> {
     fn default() -> Self {
-        SBTreeSet::new()
+        SBTreeSet::new_with_order()
+    }
+}
+
+/// An iterator over values of [SBTreeSet], created by [SBTreeSet::iter] and [SBTreeSet::range]
+pub struct SBTreeSetIter<'b, T, O, R> {
+    iter: SBTreeMapIter<'b, T, (), O, R>,
+}
+
+impl<'b, T, O: KeyOrder<T>, R: RangeBounds<T>> Iterator for SBTreeSetIter<'b, T, O, R> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(it, _)| it)
+    }
+}
+
+impl<'b, T, O: KeyOrder<T>, R: RangeBounds<T>> DoubleEndedIterator for SBTreeSetIter<'b, T, O, R> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|(it, _)| it)
+    }
+}
+
+/// A set, which [SBTreeSet] with the order `O` can be merged with - its values are iterated in
+/// the same order and have no duplicates. Another [SBTreeSet] with the same order or a `BTreeSet`
+/// (which is sorted by `Ord`, just like [OrdOrder] and [MemcmpOrder] sort keys).
+pub trait SortedSet<T, O> {
+    fn sorted_values(&self) -> Box<dyn Iterator<Item = T> + '_>;
+}
+
+impl<T, O: KeyOrder<T>> SortedSet<T, O> for SBTreeSet<T, O> {
+    fn sorted_values(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+impl<'a, T: Ord + Clone + Readable<'a, LittleEndian> + Writable<LittleEndian>>
+    SortedSet<T, OrdOrder> for BTreeSet<T>
+{
+    fn sorted_values(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        Box::new(self.iter().cloned())
+    }
+}
+
+impl<T: Ord + Clone + OrdKey> SortedSet<T, MemcmpOrder> for BTreeSet<T> {
+    fn sorted_values(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        Box::new(self.iter().cloned())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum MergeKind {
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference,
+}
+
+/// A lazy union, intersection, difference or symmetric difference of [SBTreeSet] and a
+/// [SortedSet], which merges them in one pass
+pub struct SBTreeSetMerge<'b, T, O: KeyOrder<T>> {
+    a: Peekable<SBTreeSetIter<'b, T, O, RangeFull>>,
+    b: Peekable<Box<dyn Iterator<Item = T> + 'b>>,
+    kind: MergeKind,
+    _o: PhantomData<O>,
+}
+
+impl<'b, T, O: KeyOrder<T>> Iterator for SBTreeSetMerge<'b, T, O> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let only_a = self.kind != MergeKind::Intersection;
+        let only_b = matches!(self.kind, MergeKind::Union | MergeKind::SymmetricDifference);
+        let both = matches!(self.kind, MergeKind::Union | MergeKind::Intersection);
+
+        loop {
+            let ordering = match (self.a.peek(), self.b.peek()) {
+                (Some(a), Some(b)) => O::cmp_keys(a, b),
+                // the rest of one set can only be skipped entirely or returned as is
+                (Some(_), None) if !only_a => return None,
+                (None, Some(_)) if !only_b => return None,
+                (Some(_), None) => return self.a.next(),
+                (None, Some(_)) => return self.b.next(),
+                (None, None) => return None,
+            };
+
+            match ordering {
+                Ordering::Less => {
+                    let value = self.a.next();
+                    if only_a {
+                        return value;
+                    }
+                }
+                Ordering::Greater => {
+                    let value = self.b.next();
+                    if only_b {
+                        return value;
+                    }
+                }
+                Ordering::Equal => {
+                    let value = self.a.next();
+                    self.b.next();
+
+                    if both {
+                        return value;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::btree_set::SBTreeSet;
+    use crate::utils::key_order::{ByComparator, Comparator, MemcmpOrder};
+    use crate::{get_allocated_size, init_allocator, stable};
+    use std::cmp::Ordering;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn set_algebra_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut a = SBTreeSet::<u64>::new_with_page_size(256);
+        let mut b = SBTreeSet::<u64>::new_with_page_size(256);
+        let mut std_a = BTreeSet::new();
+        let mut std_b = BTreeSet::new();
+
+        let mut seed = 3u64;
+        for _ in 0..1000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let value = (seed >> 33) % 1500;
+
+            if seed % 4 < 2 {
+                a.insert(value);
+                std_a.insert(value);
+            } else {
+                b.insert(value);
+                std_b.insert(value);
+            }
+        }
+
+        assert!(a.iter().eq(std_a.iter().cloned()));
+        assert!(a.iter().rev().eq(std_a.iter().rev().cloned()));
+        assert!(a.range(100..200).eq(std_a.range(100..200).cloned()));
+
+        assert!(a.union(&b).eq(std_a.union(&std_b).cloned()));
+        assert!(a.intersection(&b).eq(std_a.intersection(&std_b).cloned()));
+        assert!(a.difference(&b).eq(std_a.difference(&std_b).cloned()));
+        assert!(a
+            .symmetric_difference(&b)
+            .eq(std_a.symmetric_difference(&std_b).cloned()));
+
+        // against a std set
+        assert!(a.union(&std_b).eq(std_a.union(&std_b).cloned()));
+        assert!(b.difference(&std_a).eq(std_b.difference(&std_a).cloned()));
+        assert!(a.union(&BTreeSet::new()).eq(std_a.iter().cloned()));
+        assert!(a.intersection(&BTreeSet::new()).next().is_none());
+
+        assert_eq!(a.is_subset(&b), std_a.is_subset(&std_b));
+        assert_eq!(a.is_disjoint(&b), std_a.is_disjoint(&std_b));
+        assert!(a.is_subset(&std_a.union(&std_b).cloned().collect::<BTreeSet<_>>()));
+        assert!(a.is_disjoint(&std_b.difference(&std_a).cloned().collect::<BTreeSet<_>>()));
+        assert!(!a.is_subset(&std_a.range(..1000).cloned().collect::<BTreeSet<_>>()));
+
+        a.retain(|it| it % 3 == 0);
+        std_a.retain(|it| it % 3 == 0);
+        assert!(a.iter().eq(std_a.iter().cloned()));
+        assert_eq!(a.len(), std_a.len() as u64);
+
+        a.drop();
+        b.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn set_algebra_works_fine_with_custom_orders() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        struct Desc;
+
+        impl Comparator<u64> for Desc {
+            fn cmp(a: &u64, b: &u64) -> Ordering {
+                b.cmp(a)
+            }
+        }
+
+        let mut a = SBTreeSet::<u64, ByComparator<Desc>>::new_with_order();
+        let mut b = SBTreeSet::<u64, ByComparator<Desc>>::new_with_order();
+        for i in 0..100 {
+            a.insert(i * 2);
+            b.insert(i * 3);
+        }
+
+        assert!(a
+            .intersection(&b)
+            .eq((0..100).rev().map(|i| i * 6).filter(|it| *it < 200)));
+        assert_eq!(a.union(&b).count(), 100 + 100 - 34);
+        assert!(a
+            .union(&b)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .all(|it| it[0] > it[1]));
+
+        let mut c = SBTreeSet::<u64, MemcmpOrder>::new_with_order();
+        for i in 0..100 {
+            c.insert(i * 2);
+        }
+
+        let std_set = (50..150).collect::<BTreeSet<u64>>();
+        assert!(c
+            .difference(&std_set)
+            .eq((0..25).chain(75..100).map(|i| i * 2)));
+
+        a.drop();
+        b.drop();
+        c.drop();
+        assert_eq!(get_allocated_size(), 0);
     }
 }
@@ -1,268 +1,985 @@
 use crate::collections::vec::SVec;
 use crate::mem::allocator::EMPTY_PTR;
-use crate::primitive::s_slice::{CELL_META_SIZE, PTR_SIZE};
+use crate::primitive::s_slice::PTR_SIZE;
 use crate::primitive::s_unsafe_cell::SUnsafeCell;
+use crate::utils::hasher::{SipHasher13, StableHasher};
 use crate::utils::phantom_data::SPhantomData;
 use crate::{allocate, deallocate, SSlice};
-use speedy::{LittleEndian, Readable, Writable};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use candid::{CandidType, Deserialize};
+use speedy::{Context, LittleEndian, Readable, Reader, Writable, Writer};
+use std::cmp::min;
+use std::collections::VecDeque;
 
-// TODO: make entry store value more efficiently
+const STABLE_HASH_MAP_DEFAULT_CAPACITY: u32 = 256;
 
-const STABLE_HASH_MAP_DEFAULT_CAPACITY: u32 = 8192 - CELL_META_SIZE as u32 * 2;
-type HashMapBucket<K, V> = SUnsafeCell<SVec<HashMapEntry<K, V>>>;
+/// The table grows twice, once the map holds more than `LOAD_FACTOR_PERCENT`% of its capacity
+const LOAD_FACTOR_PERCENT: u64 = 75;
 
-#[derive(Readable, Writable)]
-struct HashMapEntry<K, V> {
-    key: K,
-    val: SUnsafeCell<V>,
-}
+/// How many slots of the old table are moved to the new one by each insert or remove
+const REHASH_SLOTS_PER_OP: u32 = 16;
 
-impl<
-        'a,
-        K: Readable<'a, LittleEndian> + Writable<LittleEndian>,
-        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
-    > HashMapEntry<K, V>
-{
-    pub fn new(k: K, v: &V) -> Self {
-        let val = SUnsafeCell::new(v);
-        Self { key: k, val }
-    }
-}
+/// Each slot of the table is a hash of the key (`0` = empty slot) followed by a pointer to the
+/// entry cell
+const SLOT_SIZE: usize = 16;
+
+/// Marks slots of the old table, which entries are already moved or removed. Such slots keep
+/// their hashes, so the probe sequences going through them stay valid.
+const TOMBSTONE_PTR: u64 = u64::MAX;
+
+const SHASH_MAP_ITER_CHUNK_SIZE: u32 = 64;
+
+/// Both the key and the value are stored in a single cell
+type HashMapEntry<K, V> = SUnsafeCell<(K, V)>;
 
 #[derive(Copy, Clone)]
 struct SMapTable;
 
+/// Each map header starts with these bytes, followed by the version of the layout. Headers of
+/// maps created by versions 0.2.x start with the number of entries instead (see [LegacySHashMap]).
+const SHASH_MAP_MAGIC: [u8; 4] = [b'S', b'H', b'M', b'P'];
+const SHASH_MAP_LAYOUT_VERSION: u8 = 1;
+
+/// Makes a map stored with another layout fail to decode, instead of being misread
+struct SHashMapLayout;
+
+impl<'a, C: Context> Readable<'a, C> for SHashMapLayout {
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, <C as Context>::Error> {
+        let mut magic = [0u8; SHASH_MAP_MAGIC.len()];
+        reader.read_bytes(&mut magic)?;
+
+        if magic != SHASH_MAP_MAGIC {
+            return Err(speedy::Error::custom(
+                "SHashMap is stored with the layout of version 0.2.x - read it as LegacySHashMap and migrate it",
+            )
+            .into());
+        }
+
+        let version = reader.read_u8()?;
+        if version != SHASH_MAP_LAYOUT_VERSION {
+            return Err(speedy::Error::custom(format!(
+                "Unknown SHashMap layout version {} (the latest known is {})",
+                version, SHASH_MAP_LAYOUT_VERSION
+            ))
+            .into());
+        }
+
+        Ok(SHashMapLayout)
+    }
+}
+
+impl<C: Context> Writable<C> for SHashMapLayout {
+    fn write_to<W: ?Sized + Writer<C>>(&self, writer: &mut W) -> Result<(), <C as Context>::Error> {
+        writer.write_bytes(&SHASH_MAP_MAGIC)?;
+        writer.write_u8(SHASH_MAP_LAYOUT_VERSION)
+    }
+}
+
 #[derive(Readable, Writable)]
 struct SHashMapInfo {
+    _layout: SHashMapLayout,
     _len: u64,
+    _hasher_id: u8,
     _table_capacity: u32,
     _table: Option<SSlice<SMapTable>>,
+    // while the map is growing, entries are gradually moved from the old table to the new one
+    _old_table_capacity: u32,
+    _old_table: Option<SSlice<SMapTable>>,
+    _rehash_idx: u32,
+    // is incremented each time slots are moved, so a cursor of an iteration, which was started
+    // before that, can't be used anymore
+    _generation: u64,
 }
 
+/// A hash map with open addressing (Robin Hood hashing with backward shift deletion).
+///
+/// The table stores hashes of keys next to the pointers to their entries, so a lookup only reads
+/// a few adjacent slots and decodes a single entry - the one with the matching hash.
+///
+/// The map grows automatically. Once the load factor is exceeded, a twice bigger table is
+/// allocated and slots are moved there incrementally - a few per inserted or removed key - so no
+/// single operation has to rehash the whole map. Entries are never re-encoded during this process.
+///
+/// Encoded keys are hashed with `H` ([SipHasher13] by default), which output never changes, so the
+/// map stays valid after upgrades to newer compiler versions.
 #[derive(Readable, Writable)]
-pub struct SHashMap<K, V> {
+pub struct SHashMap<K, V, H = SipHasher13> {
     _info: SHashMapInfo,
     _k: SPhantomData<K>,
     _v: SPhantomData<V>,
+    _h: SPhantomData<H>,
 }
 
 impl<
         'a,
-        K: Hash + Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
         V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
-    > Default for SHashMap<K, V>
+        H: StableHasher,
+    > Default for SHashMap<K, V, H>
 {
     fn default() -> Self {
-        Self::new()
+        Self::new_with_hasher()
     }
 }
 
 impl<
         'a,
-        K: Hash + Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
         V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
     > SHashMap<K, V>
 {
     pub fn new() -> Self {
-        Self::new_with_capacity(STABLE_HASH_MAP_DEFAULT_CAPACITY)
+        Self::new_with_hasher()
     }
 
     pub fn new_with_capacity(capacity: u32) -> Self {
+        Self::new_with_capacity_and_hasher(capacity)
+    }
+}
+
+impl<
+        'a,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > SHashMap<K, V, H>
+{
+    /// Creates a map, which uses `H` to hash its keys:
+    /// ```ignore
+    /// let map = SHashMap::<u64, u64, FxHasher>::new_with_hasher();
+    /// ```
+    pub fn new_with_hasher() -> Self {
+        Self::new_with_capacity_and_hasher(STABLE_HASH_MAP_DEFAULT_CAPACITY)
+    }
+
+    pub fn new_with_capacity_and_hasher(capacity: u32) -> Self {
+        assert!(capacity > 0, "Capacity should be greater than zero");
+
         let _info = SHashMapInfo {
+            _layout: SHashMapLayout,
             _len: 0,
+            _hasher_id: H::ALGORITHM_ID,
             _table_capacity: capacity,
             _table: None,
+            _old_table_capacity: 0,
+            _old_table: None,
+            _rehash_idx: 0,
+            _generation: 0,
         };
 
         Self {
             _info,
             _k: SPhantomData::default(),
             _v: SPhantomData::default(),
+            _h: SPhantomData::default(),
         }
     }
 
     pub fn insert(&mut self, key: K, value: &V) -> Option<V> {
-        self.init_table();
+        match self.entry(key) {
+            Entry::Occupied(mut it) => Some(it.insert(value)),
+            Entry::Vacant(it) => {
+                it.insert(value);
+                None
+            }
+        }
+    }
 
-        let idx = self.find_bucket_idx(&key);
-        let bucket_box_opt = self.read_bucket(idx);
+    /// Locates the slot of the key, so it can be read and updated without looking it up again:
+    /// ```ignore
+    /// balances.entry(to).and_modify(|it| *it += qty).or_insert(qty);
+    /// ```
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, H> {
+        self.init_table();
 
-        let (mut bucket_box, mut bucket) = if let Some(bb) = bucket_box_opt {
-            let bucket = bb.get_cloned();
+        let hash = Self::hash(&key);
+        self.rehash_key(hash, &key);
+
+        let table = self.table();
+
+        match Self::find_slot(&table, self._info._table_capacity, 0, hash, &key) {
+            Some((idx, cell)) => Entry::Occupied(OccupiedEntry {
+                map: self,
+                key,
+                hash,
+                idx,
+                cell,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                map: self,
+                key,
+                hash,
+            }),
+        }
+    }
 
-            (bb, bucket)
-        } else {
-            let bucket = SVec::<HashMapEntry<K, V>>::new();
-            let bb = HashMapBucket::<K, V>::new(&bucket);
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if self.is_empty() {
+            return None;
+        }
 
-            self.set_bucket(idx, &bb);
+        let hash = Self::hash(key);
+        self.rehash_key(hash, key);
 
-            (bb, bucket)
-        };
+        let table = self.table();
+        let capacity = self._info._table_capacity;
 
-        let mut found = false;
-        let mut prev = None;
+        let prev = Self::find_slot(&table, capacity, 0, hash, key).map(|(idx, entry)| {
+            Self::remove_slot(&table, capacity, idx);
+            self._info._len -= 1;
+            self._info._generation += 1;
 
-        let new_entry = HashMapEntry::new(key, value);
+            let (_, prev_value) = entry.get_cloned();
+            entry.drop();
 
-        for i in 0..bucket.len() {
-            let prev_entry = bucket.get_cloned(i).unwrap();
+            prev_value
+        });
 
-            if prev_entry.key.eq(&new_entry.key) {
-                let prev_value = prev_entry.val;
-                prev = Some(prev_value.get_cloned());
-                prev_value.drop();
+        self.rehash_step();
 
-                bucket.replace(i, &new_entry);
+        prev
+    }
 
-                found = true;
-                break;
-            }
+    /// Removes all entries, for which the predicate doesn't hold. The predicate is called once
+    /// for each entry.
+    ///
+    /// Entries are removed right as the table is walked, so no heap memory is needed. Growth in
+    /// progress is finished first, so no entry can be moved past the walk.
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut f: F) {
+        while self.is_rehashing() {
+            self.rehash_step();
         }
 
-        if !found {
-            bucket.push(&new_entry);
-            self._info._len += 1;
+        if self.is_empty() {
+            return;
         }
 
-        unsafe {
-            let should_update = bucket_box.set(&bucket);
-
-            if should_update {
-                self.set_bucket(idx, &bucket_box);
+        let table = self.table();
+        let capacity = self._info._table_capacity;
+
+        // removed slots are filled by the following slots of their cluster, so the walk starts
+        // right after an empty slot - then no cluster wraps around the end of the walk, and only
+        // the slots, which are not visited yet, are moved
+        let start = (0..capacity)
+            .find(|idx| Self::read_slot(&table, *idx).0 == 0)
+            .unwrap_or(0);
+
+        let mut visited = 0;
+        while visited < capacity {
+            let idx = (start + 1 + visited) % capacity;
+            let (hash, ptr) = Self::read_slot(&table, idx);
+
+            if hash != 0 {
+                let entry = unsafe { HashMapEntry::<K, V>::from_ptr(ptr) };
+                let (key, value) = entry.get_cloned();
+
+                if !f(&key, &value) {
+                    Self::remove_slot(&table, capacity, idx);
+                    self._info._len -= 1;
+                    self._info._generation += 1;
+                    entry.drop();
+
+                    // the slot is taken by the next one of the cluster now
+                    continue;
+                }
             }
+
+            visited += 1;
         }
+    }
 
-        prev
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get_cloned(key).is_some()
     }
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
+    pub fn get_cloned(&self, key: &K) -> Option<V> {
         if self.is_empty() {
             return None;
         }
 
-        let idx = self.find_bucket_idx(key);
-        let bucket_box_opt = self.read_bucket(idx);
-        let mut bucket_box = bucket_box_opt?;
-        let mut bucket = bucket_box.get_cloned();
+        let hash = Self::hash(key);
 
-        let mut prev = None;
+        let entry = Self::find_slot(&self.table(), self._info._table_capacity, 0, hash, key)
+            .or_else(|| self.find_old_slot(hash, key))
+            .map(|(_, entry)| entry)?;
 
-        for i in 0..bucket.len() {
-            let elem = bucket.get_cloned(i).unwrap();
+        Some(entry.get_cloned().1)
+    }
 
-            if elem.key.eq(key) {
-                bucket.swap(i, bucket.len() - 1);
-                let elem = bucket.pop().unwrap();
+    /// Returns an iterator over all entries of this map in an unspecified order. Each entry is
+    /// decoded as the iterator reaches it.
+    pub fn iter(&self) -> SHashMapIter<'_, K, V, H> {
+        SHashMapIter {
+            map: self,
+            position: 0,
+            slots: VecDeque::new(),
+        }
+    }
 
-                prev = Some(elem.val.get_cloned());
-                elem.val.drop();
+    /// Continues the iteration from a cursor, returned by `SHashMapIter::cursor()` - possibly in
+    /// another message:
+    /// ```ignore
+    /// let mut iter = map.iter_from(cursor)?;
+    /// let page: Vec<_> = iter.by_ref().take(100).collect();
+    /// let next_cursor = iter.cursor(); // None, once the whole map is visited
+    /// ```
+    ///
+    /// The cursor is a position in the table, so each entry is visited exactly once. Inserts and
+    /// removes move slots (as well as the growth of the map), so a cursor returned before any of
+    /// them is rejected with [InvalidCursor] - the iteration has to be started over then.
+    pub fn iter_from(
+        &self,
+        cursor: SHashMapCursor,
+    ) -> Result<SHashMapIter<'_, K, V, H>, InvalidCursor> {
+        // nothing is visited yet, so there is nothing to skip or to visit twice
+        if cursor.position != 0 && cursor.generation != self._info._generation {
+            return Err(InvalidCursor);
+        }
 
-                self._info._len -= 1;
-                break;
-            }
+        Ok(SHashMapIter {
+            map: self,
+            position: cursor.position,
+            slots: VecDeque::new(),
+        })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn len(&self) -> u64 {
+        self._info._len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if entries are being moved to a bigger table at the moment
+    pub fn is_rehashing(&self) -> bool {
+        self._info._old_table.is_some()
+    }
+
+    pub fn drop(self) {
+        if let Some(table) = self._info._table {
+            Self::drop_table(table, self._info._table_capacity, 0);
         }
 
-        unsafe {
-            let should_update = bucket_box.set(&bucket);
+        if let Some(old_table) = self._info._old_table {
+            Self::drop_table(
+                old_table,
+                self._info._old_table_capacity,
+                self._info._rehash_idx,
+            );
+        }
+    }
+
+    fn drop_table(table: SSlice<SMapTable>, capacity: u32, from: u32) {
+        for idx in from..capacity {
+            let (hash, ptr) = Self::read_slot(&table, idx);
 
-            if should_update {
-                self.set_bucket(idx, &bucket_box);
+            if hash != 0 && ptr != TOMBSTONE_PTR {
+                unsafe { HashMapEntry::<K, V>::from_ptr(ptr) }.drop();
             }
         }
 
-        prev
+        deallocate(table);
     }
 
-    pub fn contains_key(&self, key: &K) -> bool {
-        self.get_cloned(key).is_some()
+    fn init_table(&mut self) {
+        if self._info._table.is_none() {
+            self._info._table = Some(Self::allocate_table(self._info._table_capacity));
+        }
     }
 
-    pub fn get_cloned(&self, key: &K) -> Option<V> {
-        if self.is_empty() {
-            return None;
+    fn allocate_table(capacity: u32) -> SSlice<SMapTable> {
+        let capacity_bytes = capacity as usize * SLOT_SIZE;
+        let table = allocate(capacity_bytes);
+
+        // the allocator can return a previously used memory, which is not zeroed
+        table._write_bytes(0, &vec![0u8; capacity_bytes]);
+
+        table
+    }
+
+    /// Starts moving entries to a twice bigger table, once the load factor is exceeded. The map
+    /// never starts a new growth until the previous one is finished.
+    fn grow_if_needed(&mut self) {
+        if self.is_rehashing() {
+            return;
+        }
+
+        let capacity = self._info._table_capacity as u64;
+        if self.len() * 100 <= capacity * LOAD_FACTOR_PERCENT || capacity * 2 > u32::MAX as u64 {
+            return;
         }
 
-        let idx = self.find_bucket_idx(key);
-        let bucket_box = self.read_bucket(idx);
-        let bucket = bucket_box?.get_cloned();
+        self._info._old_table = self._info._table.take();
+        self._info._old_table_capacity = self._info._table_capacity;
+        self._info._rehash_idx = 0;
+        self._info._generation += 1;
 
-        for i in 0..bucket.len() {
-            let elem = bucket.get_cloned(i).unwrap();
+        self._info._table_capacity = (capacity * 2) as u32;
+        self.init_table();
+    }
+
+    /// Moves up to `REHASH_SLOTS_PER_OP` slots of the old table to the new one. Slots before
+    /// `_rehash_idx` are considered moved - the old table is only read past this index.
+    fn rehash_step(&mut self) {
+        let old_table = match &self._info._old_table {
+            Some(it) => unsafe { it.clone() },
+            None => return,
+        };
 
-            if elem.key.eq(key) {
-                return Some(elem.val.get_cloned());
+        let table = self.table();
+        let old_capacity = self._info._old_table_capacity;
+        let to = min(
+            self._info._rehash_idx.saturating_add(REHASH_SLOTS_PER_OP),
+            old_capacity,
+        );
+
+        for idx in self._info._rehash_idx..to {
+            let (hash, ptr) = Self::read_slot(&old_table, idx);
+
+            if hash != 0 && ptr != TOMBSTONE_PTR {
+                Self::insert_slot(&table, self._info._table_capacity, hash, ptr);
             }
         }
 
-        None
+        self._info._rehash_idx = to;
+        self._info._generation += 1;
+
+        if to == old_capacity {
+            deallocate(self._info._old_table.take().unwrap());
+            self._info._old_table_capacity = 0;
+            self._info._rehash_idx = 0;
+        }
     }
 
-    pub fn len(&self) -> u64 {
-        self._info._len
+    /// Moves the key to the new table right away (if it's still in the old one), so the key can
+    /// only be found in the new table
+    fn rehash_key(&mut self, hash: u64, key: &K) {
+        if let Some((old_idx, entry)) = self.find_old_slot(hash, key) {
+            let old_table = unsafe { self._info._old_table.as_ref().unwrap().clone() };
+            Self::write_slot(&old_table, old_idx, hash, TOMBSTONE_PTR);
+
+            let table = self.table();
+            Self::insert_slot(&table, self._info._table_capacity, hash, unsafe {
+                entry.as_ptr()
+            });
+            self._info._generation += 1;
+        }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    fn find_old_slot(&self, hash: u64, key: &K) -> Option<(u32, HashMapEntry<K, V>)> {
+        let old_table = self._info._old_table.as_ref()?;
+
+        Self::find_slot(
+            old_table,
+            self._info._old_table_capacity,
+            self._info._rehash_idx,
+            hash,
+            key,
+        )
     }
 
-    pub fn drop(self) {
-        for i in 0..self._info._table_capacity {
-            let bucket_box_opt = self.read_bucket(i as usize);
-            if let Some(bb) = bucket_box_opt {
-                bb.drop();
+    /// Looks for the key, following its probe sequence. Slots before `moved_before` are skipped,
+    /// as well as tombstones. Only entries with the matching hash are decoded.
+    fn find_slot(
+        table: &SSlice<SMapTable>,
+        capacity: u32,
+        moved_before: u32,
+        hash: u64,
+        key: &K,
+    ) -> Option<(u32, HashMapEntry<K, V>)> {
+        let mut idx = Self::home_idx(hash, capacity);
+
+        for dist in 0..capacity {
+            let (slot_hash, ptr) = Self::read_slot(table, idx);
+
+            // robin hood invariant - the key would be placed here, if it was in the table
+            if slot_hash == 0 || Self::probe_distance(slot_hash, idx, capacity) < dist {
+                return None;
+            }
+
+            if slot_hash == hash && ptr != TOMBSTONE_PTR && idx >= moved_before {
+                let entry = unsafe { HashMapEntry::<K, V>::from_ptr(ptr) };
+
+                if entry.get_cloned().0.eq(key) {
+                    return Some((idx, entry));
+                }
             }
+
+            idx = (idx + 1) % capacity;
         }
 
-        deallocate(self.table());
+        None
     }
 
-    fn init_table(&mut self) {
-        if self._info._table.is_none() {
-            let capacity_bytes = self._info._table_capacity as usize * PTR_SIZE;
-            let table = allocate(capacity_bytes);
+    /// Inserts a slot, which is not in the table yet. Slots with shorter probe distances are
+    /// shifted further.
+    fn insert_slot(table: &SSlice<SMapTable>, capacity: u32, mut hash: u64, mut ptr: u64) {
+        let mut idx = Self::home_idx(hash, capacity);
+        let mut dist = 0;
+
+        loop {
+            let (slot_hash, slot_ptr) = Self::read_slot(table, idx);
+
+            if slot_hash == 0 {
+                Self::write_slot(table, idx, hash, ptr);
+                return;
+            }
+
+            let slot_dist = Self::probe_distance(slot_hash, idx, capacity);
+            if slot_dist < dist {
+                Self::write_slot(table, idx, hash, ptr);
+
+                hash = slot_hash;
+                ptr = slot_ptr;
+                dist = slot_dist;
+            }
 
-            self._info._table = Some(table);
+            idx = (idx + 1) % capacity;
+            dist += 1;
         }
     }
 
-    fn find_bucket_idx(&self, key: &K) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
+    /// Removes a slot, shifting the following slots of the cluster back
+    fn remove_slot(table: &SSlice<SMapTable>, capacity: u32, mut idx: u32) {
+        loop {
+            let next_idx = (idx + 1) % capacity;
+            let (next_hash, next_ptr) = Self::read_slot(table, next_idx);
 
-        (hash % self._info._table_capacity as u64) as usize
+            if next_hash == 0 || Self::probe_distance(next_hash, next_idx, capacity) == 0 {
+                Self::write_slot(table, idx, 0, 0);
+                return;
+            }
+
+            Self::write_slot(table, idx, next_hash, next_ptr);
+            idx = next_idx;
+        }
     }
 
-    fn set_bucket(&mut self, idx: usize, bucket_value: &HashMapBucket<K, V>) {
-        let offset = idx * PTR_SIZE;
-        self.table()
-            ._write_word(offset, unsafe { bucket_value.as_ptr() });
+    /// Hashes the encoded key, since neither `Hash` implementations nor the way they feed the
+    /// hasher are guaranteed to stay the same across compiler releases
+    fn hash(key: &K) -> u64 {
+        let buf = key.write_to_vec().expect("Unable to encode");
+
+        let mut hasher = H::default();
+        hasher.write(&buf);
+
+        // zero marks empty slots
+        hasher.finish().max(1)
     }
 
-    fn read_bucket(&self, idx: usize) -> Option<HashMapBucket<K, V>> {
-        let offset = idx * PTR_SIZE;
-        let ptr = self.table()._read_word(offset);
+    fn home_idx(hash: u64, capacity: u32) -> u32 {
+        (hash % capacity as u64) as u32
+    }
 
-        if ptr == 0 || ptr == EMPTY_PTR {
-            None
+    fn probe_distance(hash: u64, idx: u32, capacity: u32) -> u32 {
+        let capacity = capacity as u64;
+
+        ((idx as u64 + capacity - hash % capacity) % capacity) as u32
+    }
+
+    /// Maps a position of the iterator to a table and a slot index in it. Positions go through
+    /// the current table first and then through the part of the old table which is not moved yet.
+    fn slot_at(&self, position: u64) -> Option<(SSlice<SMapTable>, u32, u32)> {
+        let table = self._info._table.as_ref()?;
+        let capacity = self._info._table_capacity as u64;
+
+        if position < capacity {
+            return Some((unsafe { table.clone() }, position as u32, capacity as u32));
+        }
+
+        let old_table = self._info._old_table.as_ref()?;
+        let old_idx = position - capacity + self._info._rehash_idx as u64;
+
+        if old_idx < self._info._old_table_capacity as u64 {
+            Some((
+                unsafe { old_table.clone() },
+                old_idx as u32,
+                self._info._old_table_capacity,
+            ))
         } else {
-            Some(unsafe { HashMapBucket::<K, V>::from_ptr(ptr) })
+            None
         }
     }
 
+    fn read_slots(table: &SSlice<SMapTable>, idx: u32, count: u32) -> Vec<(u64, u64)> {
+        let mut buf = vec![0u8; count as usize * SLOT_SIZE];
+        table._read_bytes(idx as usize * SLOT_SIZE, &mut buf);
+
+        buf.chunks_exact(SLOT_SIZE)
+            .map(|it| {
+                (
+                    u64::from_le_bytes(it[..8].try_into().unwrap()),
+                    u64::from_le_bytes(it[8..].try_into().unwrap()),
+                )
+            })
+            .collect()
+    }
+
+    fn read_slot(table: &SSlice<SMapTable>, idx: u32) -> (u64, u64) {
+        let mut buf = [0u8; SLOT_SIZE];
+        table._read_bytes(idx as usize * SLOT_SIZE, &mut buf);
+
+        (
+            u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            u64::from_le_bytes(buf[8..].try_into().unwrap()),
+        )
+    }
+
+    fn write_slot(table: &SSlice<SMapTable>, idx: u32, hash: u64, ptr: u64) {
+        let mut buf = [0u8; SLOT_SIZE];
+        buf[..8].copy_from_slice(&hash.to_le_bytes());
+        buf[8..].copy_from_slice(&ptr.to_le_bytes());
+
+        table._write_bytes(idx as usize * SLOT_SIZE, &buf);
+    }
+
     fn table(&self) -> SSlice<SMapTable> {
+        // every operation goes through here, so it's the cheapest place to check the hasher
+        assert_eq!(
+            self._info._hasher_id,
+            H::ALGORITHM_ID,
+            "SHashMap is created with another hasher"
+        );
+
         unsafe { self._info._table.as_ref().unwrap().clone() }
     }
 }
 
+#[derive(Readable, Writable)]
+struct LegacyHashMapEntry<K, V> {
+    key: K,
+    val: SUnsafeCell<V>,
+}
+
+struct LegacyMapTable;
+
+/// [SHashMap] as it was stored by versions 0.2.x - a fixed table of buckets, where each bucket is
+/// a boxed `SVec` of boxed entries, placed by `std`'s `DefaultHasher`. Such a map can't be read as
+/// [SHashMap] anymore. Read it as `LegacySHashMap` instead and migrate it:
+/// ```ignore
+/// let legacy = get_var::<LegacySHashMap<SPrincipal, u64>>("balances");
+/// set_var("balances", &legacy.migrate::<SipHasher13>());
+/// ```
+/// Legacy sets (`SHashSet<T>`) are read as `LegacySHashMap<T, ()>` and migrated with `migrate_set()`.
+#[derive(Readable, Writable)]
+pub struct LegacySHashMap<K, V> {
+    _len: u64,
+    _table_capacity: u32,
+    _table: Option<SSlice<LegacyMapTable>>,
+    _k: SPhantomData<K>,
+    _v: SPhantomData<V>,
+}
+
+impl<
+        'a,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+    > LegacySHashMap<K, V>
+{
+    pub fn len(&self) -> u64 {
+        self._len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self._len == 0
+    }
+
+    /// Moves all the entries into a new map and frees this one. Takes time proportional to the
+    /// number of entries, all within a single call.
+    pub fn migrate<H: StableHasher>(self) -> SHashMap<K, V, H> {
+        let mut map = SHashMap::new_with_hasher();
+
+        if let Some(table) = self._table {
+            for i in 0..self._table_capacity as usize {
+                let bucket_ptr = table._read_word(i * PTR_SIZE);
+                if bucket_ptr == 0 || bucket_ptr == EMPTY_PTR {
+                    continue;
+                }
+
+                let bucket_box =
+                    unsafe { SUnsafeCell::<SVec<LegacyHashMapEntry<K, V>>>::from_ptr(bucket_ptr) };
+                let bucket = bucket_box.get_cloned();
+
+                for entry in bucket.iter() {
+                    map.insert(entry.key, &entry.val.get_cloned());
+                    entry.val.drop();
+                }
+
+                bucket.drop();
+                bucket_box.drop();
+            }
+
+            deallocate(table);
+        }
+
+        map
+    }
+}
+
+/// A view into a single entry of [SHashMap], created by [SHashMap::entry]
+pub enum Entry<'b, K, V, H> {
+    Occupied(OccupiedEntry<'b, K, V, H>),
+    Vacant(VacantEntry<'b, K, V, H>),
+}
+
+pub struct OccupiedEntry<'b, K, V, H> {
+    map: &'b mut SHashMap<K, V, H>,
+    key: K,
+    hash: u64,
+    idx: u32,
+    cell: HashMapEntry<K, V>,
+}
+
+pub struct VacantEntry<'b, K, V, H> {
+    map: &'b mut SHashMap<K, V, H>,
+    key: K,
+    hash: u64,
+}
+
+impl<
+        'a,
+        'b,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > Entry<'b, K, V, H>
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(it) => it.key(),
+            Entry::Vacant(it) => it.key(),
+        }
+    }
+
+    /// Inserts the default value, if the entry is vacant. Returns the value of the entry.
+    pub fn or_insert(self, default: V) -> V {
+        match self {
+            Entry::Occupied(it) => it.get(),
+            Entry::Vacant(it) => {
+                it.insert(&default);
+                default
+            }
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> V {
+        match self {
+            Entry::Occupied(it) => it.get(),
+            Entry::Vacant(it) => {
+                let value = f();
+                it.insert(&value);
+
+                value
+            }
+        }
+    }
+
+    /// Modifies the value, if the entry is occupied. The modified value is written back into the
+    /// same cell, unless it doesn't fit there anymore.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut it) => {
+                let mut value = it.get();
+                f(&mut value);
+                it.write(&value);
+
+                Entry::Occupied(it)
+            }
+            Entry::Vacant(it) => Entry::Vacant(it),
+        }
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Default + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > Entry<'b, K, V, H>
+{
+    pub fn or_default(self) -> V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > OccupiedEntry<'b, K, V, H>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the value of the entry. It's already decoded during the lookup, so this doesn't
+    /// read stable memory.
+    pub fn get(&self) -> V {
+        self.cell.get_cloned().1
+    }
+
+    /// Replaces the value of the entry, returning the previous one
+    pub fn insert(&mut self, value: &V) -> V {
+        let prev = self.get();
+        self.write(value);
+
+        prev
+    }
+
+    pub fn remove(self) -> V {
+        let table = self.map.table();
+        SHashMap::<K, V, H>::remove_slot(&table, self.map._info._table_capacity, self.idx);
+        self.map._info._len -= 1;
+        self.map._info._generation += 1;
+
+        let value = self.get();
+        self.cell.drop();
+
+        self.map.rehash_step();
+
+        value
+    }
+
+    fn write(&mut self, value: &V) {
+        let buf = (&self.key, value).write_to_vec().expect("Unable to encode");
+
+        if unsafe { self.cell.set_encoded(buf) } {
+            let table = self.map.table();
+            let ptr = unsafe { self.cell.as_ptr() };
+
+            SHashMap::<K, V, H>::write_slot(&table, self.idx, self.hash, ptr);
+        }
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > VacantEntry<'b, K, V, H>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(self, value: &V) {
+        let map = self.map;
+        let capacity = map._info._table_capacity;
+        assert!(map.len() < capacity as u64, "SHashMap is full");
+
+        let buf = (&self.key, value).write_to_vec().expect("Unable to encode");
+        let cell = HashMapEntry::<K, V>::new_encoded(buf);
+
+        SHashMap::<K, V, H>::insert_slot(&map.table(), capacity, self.hash, unsafe {
+            cell.as_ptr()
+        });
+        map._info._len += 1;
+        map._info._generation += 1;
+
+        map.rehash_step();
+        map.grow_if_needed();
+    }
+}
+
+/// A serializable position of [SHashMapIter], which can be stored somewhere (e.g. in a stable
+/// variable or on the client) to continue the iteration in another message
+#[derive(
+    CandidType, Deserialize, Readable, Writable, Clone, Copy, Debug, Default, PartialEq, Eq,
+)]
+pub struct SHashMapCursor {
+    position: u64,
+    generation: u64,
+}
+
+/// Is returned by [SHashMap::iter_from], when the map was modified after the cursor was returned
+#[derive(Debug, Copy, Clone)]
+pub struct InvalidCursor;
+
+/// An iterator over the entries of [SHashMap], created by [SHashMap::iter] or
+/// [SHashMap::iter_from]. Slots are read from stable memory in runs of up to
+/// `SHASH_MAP_ITER_CHUNK_SIZE`.
+pub struct SHashMapIter<'b, K, V, H> {
+    map: &'b SHashMap<K, V, H>,
+    position: u64,
+    slots: VecDeque<(u64, u64)>,
+}
+
+impl<
+        'a,
+        'b,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > SHashMapIter<'b, K, V, H>
+{
+    /// Returns the cursor to continue the iteration from, or `None` if there is nothing left
+    pub fn cursor(&self) -> Option<SHashMapCursor> {
+        let position = self.position - self.slots.len() as u64;
+        self.map.slot_at(position)?;
+
+        Some(SHashMapCursor {
+            position,
+            generation: self.map._info._generation,
+        })
+    }
+}
+
+impl<
+        'a,
+        'b,
+        K: Eq + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        V: Readable<'a, LittleEndian> + Writable<LittleEndian>,
+        H: StableHasher,
+    > Iterator for SHashMapIter<'b, K, V, H>
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.slots.is_empty() {
+                let (table, idx, capacity) = self.map.slot_at(self.position)?;
+                let count = min(SHASH_MAP_ITER_CHUNK_SIZE, capacity - idx);
+
+                self.slots = SHashMap::<K, V, H>::read_slots(&table, idx, count).into();
+                self.position += count as u64;
+            }
+
+            let (hash, ptr) = self.slots.pop_front().unwrap();
+
+            if hash != 0 && ptr != TOMBSTONE_PTR {
+                return Some(unsafe { HashMapEntry::<K, V>::from_ptr(ptr) }.get_cloned());
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::collections::hash_map::SHashMap;
-    use crate::init_allocator;
+    use crate::collections::hash_map::{
+        Entry, LegacyHashMapEntry, LegacyMapTable, LegacySHashMap, SHashMap, SHashMapCursor,
+    };
+    use crate::collections::hash_set::SHashSet;
+    use crate::collections::vec::SVec;
+    use crate::primitive::s_slice::PTR_SIZE;
+    use crate::primitive::s_unsafe_cell::SUnsafeCell;
+    use crate::utils::hasher::{FxHasher, SipHasher13};
     use crate::utils::mem_context::stable;
+    use crate::utils::phantom_data::SPhantomData;
+    use crate::{allocate, get_allocated_size, init_allocator};
+    use speedy::{Readable, Writable};
 
     fn test_body(mut map: SHashMap<String, i32>) {
         let k1 = "key1".to_string();
@@ -334,4 +1051,424 @@ mod tests {
         let map = SHashMap::new_with_capacity(3);
         test_body(map);
     }
+
+    #[test]
+    fn insert_replaces_and_frees_previous_value() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SHashMap::new();
+        let key = String::from("key");
+
+        assert!(map.insert(key.clone(), &1).is_none());
+        let allocated = get_allocated_size();
+
+        for i in 1..100 {
+            assert_eq!(map.insert(key.clone(), &(i + 1)), Some(i));
+        }
+
+        assert_eq!(map.get_cloned(&key), Some(100));
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            get_allocated_size(),
+            allocated,
+            "Replaced values leak memory"
+        );
+
+        map.drop();
+    }
+
+    #[test]
+    fn legacy_maps_are_rejected_and_migrated() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        // a map, as it was stored by 0.2.x
+        let table = allocate::<LegacyMapTable>(8 * PTR_SIZE);
+        for bucket_idx in 0..4usize {
+            let mut bucket = SVec::new();
+
+            for i in 0..10u64 {
+                let key = bucket_idx as u64 * 10 + i;
+                bucket.push(&LegacyHashMapEntry {
+                    key,
+                    val: SUnsafeCell::new(&(key * 2)),
+                });
+            }
+
+            let bucket_box = SUnsafeCell::new(&bucket);
+            table._write_word(bucket_idx * 2 * PTR_SIZE, unsafe { bucket_box.as_ptr() });
+        }
+
+        let legacy = LegacySHashMap::<u64, u64> {
+            _len: 40,
+            _table_capacity: 8,
+            _table: Some(table),
+            _k: SPhantomData::default(),
+            _v: SPhantomData::default(),
+        };
+        let buf = legacy.write_to_vec().unwrap();
+
+        assert!(SHashMap::<u64, u64>::read_from_buffer_copying_data(&buf).is_err());
+
+        let legacy = LegacySHashMap::<u64, u64>::read_from_buffer_copying_data(&buf).unwrap();
+        assert_eq!(legacy.len(), 40);
+
+        let map = legacy.migrate::<SipHasher13>();
+        assert_eq!(map.len(), 40);
+        for key in 0..40u64 {
+            assert_eq!(map.get_cloned(&key), Some(key * 2));
+        }
+
+        let buf = map.write_to_vec().unwrap();
+        let map = SHashMap::<u64, u64>::read_from_buffer_copying_data(&buf).unwrap();
+        assert_eq!(map.len(), 40);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    fn growth_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        let mut map = SHashMap::new_with_capacity(4);
+        let mut was_rehashing = false;
+
+        for i in 0..1000u64 {
+            assert!(map.insert(i, &(i * 2)).is_none());
+            was_rehashing |= map.is_rehashing();
+
+            // every key stays reachable, even if its bucket is not moved yet
+            assert_eq!(map.get_cloned(&(i / 2)), Some(i / 2 * 2));
+        }
+
+        assert!(was_rehashing);
+        assert_eq!(map.len(), 1000);
+
+        for i in (0..1000u64).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i * 2));
+        }
+
+        for i in 1000..1500u64 {
+            map.insert(i, &(i * 2));
+        }
+
+        assert_eq!(map.len(), 1000);
+        for i in 0..1500u64 {
+            let expected = if i < 1000 && i % 2 == 0 {
+                None
+            } else {
+                Some(i * 2)
+            };
+
+            assert_eq!(map.get_cloned(&i), expected);
+        }
+
+        let mut keys: Vec<_> = map.keys().collect();
+        keys.sort();
+        assert_eq!(keys.len(), 1000);
+        keys.dedup();
+        assert_eq!(keys.len(), 1000);
+
+        map.drop();
+
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    fn custom_hasher_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SHashMap::<u64, u64, FxHasher>::new_with_capacity_and_hasher(4);
+        for i in 0..100 {
+            map.insert(i, &i);
+        }
+
+        let buf = map.write_to_vec().unwrap();
+        let map = SHashMap::<u64, u64, FxHasher>::read_from_buffer(&buf).unwrap();
+
+        for i in 0..100 {
+            assert_eq!(map.get_cloned(&i), Some(i));
+        }
+
+        map.drop();
+    }
+
+    #[test]
+    fn key_hashes_never_change() {
+        // the reference values - keys are placed by them, so they should never change
+        let hash = |it: &str| SHashMap::<String, u64>::hash(&String::from(it));
+        assert_eq!(hash("a"), 0x196d_3a21_fb28_a625);
+        assert_eq!(hash("The string"), 0xd395_ad4c_ec74_7fb1);
+
+        let hash = |it: &str| SHashMap::<String, u64, FxHasher>::hash(&String::from(it));
+        assert_eq!(hash("a"), 0x8e5a_628a_5337_1e27);
+        assert_eq!(hash("The string"), 0x545c_e921_a638_739d);
+
+        assert_eq!(SHashMap::<u64, u64>::hash(&1), 0x1e9f_7341_61d6_2dd9);
+    }
+
+    #[test]
+    fn entry_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SHashMap::<u64, Vec<u64>>::new_with_capacity(4);
+
+        for i in 0..100u64 {
+            map.entry(i % 10).or_default();
+            // the value outgrows its cell every few updates
+            map.entry(i % 10)
+                .and_modify(|it| it.push(i))
+                .or_insert(vec![]);
+        }
+
+        assert_eq!(map.len(), 10);
+        for i in 0..10u64 {
+            let expected = (0..10).map(|j| i + j * 10).collect::<Vec<_>>();
+            assert_eq!(map.get_cloned(&i), Some(expected));
+        }
+
+        assert_eq!(map.entry(10).or_insert_with(|| vec![10]), vec![10]);
+        assert_eq!(map.entry(10).or_insert(vec![11]), vec![10]);
+
+        match map.entry(10) {
+            Entry::Occupied(mut it) => {
+                assert_eq!(*it.key(), 10);
+                assert_eq!(it.insert(&vec![12]), vec![10]);
+                assert_eq!(it.get(), vec![12]);
+                assert_eq!(it.remove(), vec![12]);
+            }
+            Entry::Vacant(_) => unreachable!(),
+        }
+
+        assert!(matches!(map.entry(10), Entry::Vacant(_)));
+        assert_eq!(map.len(), 10);
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "SHashMap is created with another hasher")]
+    fn hasher_mismatch_panics() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SHashMap::<u64, u64>::new();
+        map.insert(1, &1);
+
+        let buf = map.write_to_vec().unwrap();
+        let map = SHashMap::<u64, u64, FxHasher>::read_from_buffer(&buf).unwrap();
+
+        map.get_cloned(&1);
+    }
+
+    #[test]
+    fn randomized_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        let mut map = SHashMap::<u64, u64, FxHasher>::new_with_capacity_and_hasher(1);
+        let mut expected = std::collections::HashMap::new();
+
+        // lcg - keys are from a small range, so there are a lot of overwrites and removals
+        let mut seed = 42u64;
+        for i in 0..5000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let key = (seed >> 33) % 700;
+
+            if seed % 3 == 1 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, &i), expected.insert(key, i));
+            }
+
+            assert_eq!(map.len(), expected.len() as u64);
+        }
+
+        for key in 0..700 {
+            assert_eq!(map.get_cloned(&key), expected.get(&key).copied());
+        }
+
+        map.drop();
+
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    fn iteration_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SHashMap::new_with_capacity(4);
+        assert_eq!(map.iter().next(), None);
+
+        for i in 0..400u64 {
+            map.insert(i, &(i * 10));
+        }
+
+        // the map is in the middle of a growth - both tables are visited
+        assert!(map.is_rehashing());
+
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort();
+        assert_eq!(entries, (0..400).map(|i| (i, i * 10)).collect::<Vec<_>>());
+
+        assert_eq!(map.keys().sum::<u64>(), (0..400).sum::<u64>());
+        assert_eq!(
+            map.values().sum::<u64>(),
+            (0..400).map(|i| i * 10).sum::<u64>()
+        );
+
+        // page by page, passing the serialized cursor between "messages"
+        let mut cursor_buf = SHashMapCursor::default().write_to_vec().unwrap();
+        let mut keys = Vec::new();
+
+        loop {
+            let cursor = SHashMapCursor::read_from_buffer(&cursor_buf).unwrap();
+            let mut iter = map.iter_from(cursor).unwrap();
+            keys.extend(iter.by_ref().take(30).map(|(k, _)| k));
+
+            match iter.cursor() {
+                Some(it) => cursor_buf = it.write_to_vec().unwrap(),
+                None => break,
+            }
+        }
+
+        keys.sort();
+        assert_eq!(keys, (0..400).collect::<Vec<_>>());
+
+        map.drop();
+
+        let mut set = SHashSet::new_with_capacity(2);
+        for i in 0..100u64 {
+            set.insert(i);
+        }
+
+        let mut iter = set.iter();
+        let mut elems: Vec<_> = iter.by_ref().take(50).collect();
+        elems.extend(set.iter_from(iter.cursor().unwrap()).unwrap());
+        elems.sort();
+        assert_eq!(elems, (0..100).collect::<Vec<_>>());
+
+        set.drop();
+    }
+
+    #[test]
+    fn stale_cursors_are_rejected() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut map = SHashMap::new_with_capacity(16);
+        for i in 0..10u64 {
+            map.insert(i, &i);
+        }
+
+        let mut iter = map.iter();
+        iter.by_ref().take(5).count();
+        let cursor = iter.cursor().unwrap();
+
+        // updating values and lookups don't move slots
+        map.insert(1, &100);
+        map.entry(2).and_modify(|it| *it = 200);
+        assert_eq!(map.get_cloned(&3), Some(3));
+        assert!(map.iter_from(cursor).is_ok());
+
+        map.insert(10, &10);
+        assert!(map.iter_from(cursor).is_err());
+        assert_eq!(
+            map.iter_from(SHashMapCursor::default()).unwrap().count(),
+            11
+        );
+
+        let mut iter = map.iter();
+        iter.next();
+        let cursor = iter.cursor().unwrap();
+
+        map.remove(&10);
+        assert!(map.iter_from(cursor).is_err());
+
+        let mut iter = map.iter();
+        iter.next();
+        let cursor = iter.cursor().unwrap();
+
+        map.retain(|k, _| *k != 0);
+        assert!(map.iter_from(cursor).is_err());
+
+        // a removal of a missing key moves nothing, unless the map is growing
+        let mut iter = map.iter();
+        iter.next();
+        let cursor = iter.cursor().unwrap();
+
+        map.remove(&100);
+        assert!(map.iter_from(cursor).is_ok());
+
+        map.drop();
+        assert_eq!(get_allocated_size(), 0);
+    }
+
+    #[test]
+    fn retain_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        for modulo in [2u64, 3, 7] {
+            let mut map = SHashMap::<u64, u64, FxHasher>::new_with_capacity_and_hasher(4);
+            let mut expected = std::collections::HashMap::new();
+
+            for i in 0..700u64 {
+                let key = i.wrapping_mul(0x9E3779B97F4A7C15);
+                map.insert(key, &i);
+                expected.insert(key, i);
+            }
+
+            let mut calls = std::collections::HashMap::new();
+            map.retain(|k, v| {
+                *calls.entry(*k).or_insert(0) += 1;
+                v % modulo == 0
+            });
+            expected.retain(|_, v| *v % modulo == 0);
+
+            // each entry is seen exactly once
+            assert_eq!(calls.len(), 700);
+            assert!(calls.values().all(|it| *it == 1));
+
+            assert!(!map.is_rehashing());
+            assert_eq!(map.len(), expected.len() as u64);
+            for (k, v) in &expected {
+                assert_eq!(map.get_cloned(k), Some(*v));
+            }
+
+            map.retain(|_, _| false);
+            assert!(map.is_empty());
+            assert_eq!(map.iter().next(), None);
+
+            map.drop();
+            assert_eq!(get_allocated_size(), initial_allocated);
+        }
+    }
 }
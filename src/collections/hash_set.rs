@@ -1,22 +1,47 @@
-use crate::collections::hash_map::SHashMap;
+use crate::collections::hash_map::{
+    InvalidCursor, LegacySHashMap, SHashMap, SHashMapCursor, SHashMapIter,
+};
+use crate::utils::hasher::{SipHasher13, StableHasher};
 use speedy::{LittleEndian, Readable, Writable};
-use std::hash::Hash;
+use std::collections::{BTreeSet, HashSet};
+use std::hash::{BuildHasher, Hash};
 
 #[derive(Readable, Writable)]
-pub struct SHashSet<T> {
-    map: SHashMap<T, ()>,
+pub struct SHashSet<T, H = SipHasher13> {
+    map: SHashMap<T, (), H>,
 }
 
-impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Hash + Eq> SHashSet<T> {
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq> SHashSet<T> {
     pub fn new() -> Self {
+        Self::new_with_hasher()
+    }
+
+    pub fn new_with_capacity(capacity: u32) -> Self {
+        Self::new_with_capacity_and_hasher(capacity)
+    }
+}
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq> LegacySHashMap<T, ()> {
+    /// Same as [LegacySHashMap::migrate], but for a set stored by versions 0.2.x
+    pub fn migrate_set<H: StableHasher>(self) -> SHashSet<T, H> {
+        SHashSet {
+            map: self.migrate(),
+        }
+    }
+}
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq, H: StableHasher>
+    SHashSet<T, H>
+{
+    pub fn new_with_hasher() -> Self {
         Self {
-            map: SHashMap::new(),
+            map: SHashMap::new_with_hasher(),
         }
     }
 
-    pub fn new_with_capacity(capacity: u32) -> Self {
+    pub fn new_with_capacity_and_hasher(capacity: u32) -> Self {
         Self {
-            map: SHashMap::new_with_capacity(capacity),
+            map: SHashMap::new_with_capacity_and_hasher(capacity),
         }
     }
 
@@ -40,15 +65,221 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Hash + Eq> SHa
         self.map.is_empty()
     }
 
+    /// Returns an iterator over all elements of this set in an unspecified order
+    pub fn iter(&self) -> SHashSetIter<'_, T, H> {
+        SHashSetIter {
+            iter: self.map.iter(),
+        }
+    }
+
+    /// Continues the iteration from a cursor - see `SHashMap::iter_from()`
+    pub fn iter_from(
+        &self,
+        cursor: SHashMapCursor,
+    ) -> Result<SHashSetIter<'_, T, H>, InvalidCursor> {
+        Ok(SHashSetIter {
+            iter: self.map.iter_from(cursor)?,
+        })
+    }
+
+    /// Removes all elements, for which the predicate doesn't hold (see [SHashMap::retain])
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        self.map.retain(|it, _| f(it));
+    }
+
+    /// Elements of both sets. Elements of this set go first, then the rest of the other set:
+    /// ```ignore
+    /// let all = stable_set.union(&other_stable_set);
+    /// let common = stable_set.intersection(&std_hash_set);
+    /// ```
+    pub fn union<'b, S: LookupSet<T>>(&'b self, other: &'b S) -> impl Iterator<Item = T> + 'b {
+        self.iter()
+            .chain(other.values().filter(move |it| !self.contains(it)))
+    }
+
+    /// Elements of this set, which are in the other one too
+    pub fn intersection<'b, S: LookupSet<T>>(
+        &'b self,
+        other: &'b S,
+    ) -> impl Iterator<Item = T> + 'b {
+        self.iter().filter(move |it| other.contains_value(it))
+    }
+
+    /// Elements of this set, which are not in the other one
+    pub fn difference<'b, S: LookupSet<T>>(&'b self, other: &'b S) -> impl Iterator<Item = T> + 'b {
+        self.iter().filter(move |it| !other.contains_value(it))
+    }
+
+    /// Elements, which are in exactly one of the sets
+    pub fn symmetric_difference<'b, S: LookupSet<T>>(
+        &'b self,
+        other: &'b S,
+    ) -> impl Iterator<Item = T> + 'b {
+        self.difference(other)
+            .chain(other.values().filter(move |it| !self.contains(it)))
+    }
+
+    /// Whether all elements of this set are in the other one
+    pub fn is_subset<S: LookupSet<T>>(&self, other: &S) -> bool {
+        self.iter().all(|it| other.contains_value(&it))
+    }
+
+    /// Whether the sets have no elements in common
+    pub fn is_disjoint<S: LookupSet<T>>(&self, other: &S) -> bool {
+        self.intersection(other).next().is_none()
+    }
+
     pub fn drop(self) {
         self.map.drop()
     }
 }
 
-impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Hash + Eq> Default
-    for SHashSet<T>
+/// A set, which [SHashSet] can be combined with - another [SHashSet] or a set from
+/// `std::collections`
+pub trait LookupSet<T> {
+    fn contains_value(&self, value: &T) -> bool;
+
+    fn values(&self) -> Box<dyn Iterator<Item = T> + '_>;
+}
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq, H: StableHasher> LookupSet<T>
+    for SHashSet<T, H>
+{
+    fn contains_value(&self, value: &T) -> bool {
+        self.contains(value)
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+impl<T: Hash + Eq + Clone, S: BuildHasher> LookupSet<T> for HashSet<T, S> {
+    fn contains_value(&self, value: &T) -> bool {
+        self.contains(value)
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        Box::new(self.iter().cloned())
+    }
+}
+
+impl<T: Ord + Clone> LookupSet<T> for BTreeSet<T> {
+    fn contains_value(&self, value: &T) -> bool {
+        self.contains(value)
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = T> + '_> {
+        Box::new(self.iter().cloned())
+    }
+}
+
+/// An iterator over the elements of [SHashSet], created by [SHashSet::iter] or
+/// [SHashSet::iter_from]
+pub struct SHashSetIter<'b, T, H> {
+    iter: SHashMapIter<'b, T, (), H>,
+}
+
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq, H: StableHasher>
+    SHashSetIter<'b, T, H>
+{
+    /// Returns the cursor to continue the iteration from, or `None` if there is nothing left
+    pub fn cursor(&self) -> Option<SHashMapCursor> {
+        self.iter.cursor()
+    }
+}
+
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq, H: StableHasher> Iterator
+    for SHashSetIter<'b, T, H>
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(it, _)| it)
+    }
+}
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian> + Eq, H: StableHasher> Default
+    for SHashSet<T, H>
 {
     fn default() -> Self {
-        SHashSet::new()
+        SHashSet::new_with_hasher()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::hash_set::SHashSet;
+    use crate::{get_allocated_size, init_allocator, stable};
+    use std::collections::HashSet;
+
+    #[test]
+    fn set_algebra_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut a = SHashSet::<u64>::new();
+        let mut b = SHashSet::<u64>::new();
+        let mut std_a = HashSet::new();
+        let mut std_b = HashSet::new();
+
+        for i in 0..500 {
+            a.insert(i * 3 % 700);
+            std_a.insert(i * 3 % 700);
+            b.insert(i * 5 % 900);
+            std_b.insert(i * 5 % 900);
+        }
+
+        let collect = |it: &mut dyn Iterator<Item = u64>| {
+            let mut res = Vec::new();
+            res.extend(it);
+            res.sort();
+
+            res
+        };
+        let sorted = |it: &mut dyn Iterator<Item = &u64>| collect(&mut it.cloned());
+
+        assert_eq!(collect(&mut a.union(&b)), sorted(&mut std_a.union(&std_b)));
+        assert_eq!(
+            collect(&mut a.intersection(&b)),
+            sorted(&mut std_a.intersection(&std_b))
+        );
+        assert_eq!(
+            collect(&mut a.difference(&b)),
+            sorted(&mut std_a.difference(&std_b))
+        );
+        assert_eq!(
+            collect(&mut a.symmetric_difference(&b)),
+            sorted(&mut std_a.symmetric_difference(&std_b))
+        );
+
+        // against std sets
+        assert_eq!(
+            collect(&mut a.symmetric_difference(&std_b)),
+            sorted(&mut std_a.symmetric_difference(&std_b))
+        );
+        let std_btree_b = std_b
+            .iter()
+            .cloned()
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(
+            collect(&mut a.union(&std_btree_b)),
+            sorted(&mut std_a.union(&std_b))
+        );
+
+        assert_eq!(a.is_subset(&b), std_a.is_subset(&std_b));
+        assert_eq!(a.is_disjoint(&b), std_a.is_disjoint(&std_b));
+        assert!(a.is_subset(&std_a));
+        assert!(!a.is_disjoint(&std_a));
+
+        a.retain(|it| it % 2 == 0);
+        std_a.retain(|it| it % 2 == 0);
+        assert_eq!(collect(&mut a.iter()), sorted(&mut std_a.iter()));
+        assert_eq!(a.len(), std_a.len() as u64);
+
+        a.drop();
+        b.drop();
+        assert_eq!(get_allocated_size(), 0);
     }
 }
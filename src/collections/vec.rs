@@ -1,28 +1,132 @@
-use crate::primitive::s_slice::PTR_SIZE;
+use crate::primitive::fixed_size::FixedSize;
 use crate::utils::math::fast_log2_64;
 use crate::utils::phantom_data::SPhantomData;
 use crate::{allocate, deallocate, SSlice, SUnsafeCell};
 use speedy::{LittleEndian, Readable, Writable};
-use std::cmp::min;
-
-const TWO_IN_29: u64 = 2u64.pow(29);
+use std::cmp::{min, Ordering};
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+use std::{iter, mem};
+
+/// Sectors grow twice each time, up to 2^29 slots, but never take more than
+/// `MAX_SECTOR_SIZE_BYTES`, so the size of a sector always fits into `usize` on wasm32.
+/// Sectors of boxed elements (8-byte slots) therefore stop growing at 2^28 slots.
+const MAX_SECTOR_CAPACITY_LOG2: u32 = 29;
+const MAX_SECTOR_SIZE_BYTES: u64 = 2u64.pow(31);
+const SVEC_ITER_CHUNK_SIZE: u64 = 256;
 
 struct SVecSector;
 
+/// Defines how elements of [SVec] are stored in the slots of its sectors.
+pub trait SVecStorage<T> {
+    /// The size of a single slot in bytes
+    const SLOT_SIZE: usize;
+
+    /// Encodes the element into the contents of a new slot
+    fn store(it: &T) -> Vec<u8>;
+
+    /// Decodes the element from the contents of a slot
+    fn load(slot: &[u8]) -> T;
+
+    /// Frees everything the slot owns. Called once the element is removed from the vector.
+    fn free(slot: &[u8]);
+}
+
+/// The default storage - each element is stored in its own [SUnsafeCell], and slots only hold
+/// pointers to these cells. Works for elements of any size.
+pub struct SBoxed;
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVecStorage<T> for SBoxed {
+    const SLOT_SIZE: usize = 8;
+
+    fn store(it: &T) -> Vec<u8> {
+        let elem_cell = SUnsafeCell::new(it);
+
+        unsafe { elem_cell.as_ptr() }.to_le_bytes().to_vec()
+    }
+
+    fn load(slot: &[u8]) -> T {
+        unsafe { SUnsafeCell::<T>::from_ptr(Self::ptr(slot)) }.get_cloned()
+    }
+
+    fn free(slot: &[u8]) {
+        unsafe { SUnsafeCell::<T>::from_ptr(Self::ptr(slot)) }.drop();
+    }
+}
+
+impl SBoxed {
+    fn ptr(slot: &[u8]) -> u64 {
+        u64::from_le_bytes(slot.try_into().unwrap())
+    }
+}
+
+/// Stores encoded elements right inside the slots - no per-element allocations, and a single
+/// stable memory read per access. Only works for [FixedSize] elements.
+pub struct SInline;
+
+impl<'a, T: FixedSize + Readable<'a, LittleEndian> + Writable<LittleEndian>> SVecStorage<T>
+    for SInline
+{
+    const SLOT_SIZE: usize = T::SIZE;
+
+    fn store(it: &T) -> Vec<u8> {
+        let buf = it.write_to_vec().expect("Unable to encode");
+        assert_eq!(
+            buf.len(),
+            T::SIZE,
+            "Encoded size doesn't match FixedSize::SIZE"
+        );
+
+        buf
+    }
+
+    fn load(slot: &[u8]) -> T {
+        T::read_from_buffer_copying_data(slot).expect("Unable to decode")
+    }
+
+    fn free(_slot: &[u8]) {}
+}
+
 #[derive(Readable, Writable)]
 struct SVecInfo {
     _len: u64,
     _sectors: Vec<SSlice<SVecSector>>,
 }
 
+/// A vector, which elements are stored in stable memory sectors.
+///
+/// By default each element is boxed into its own allocation ([SBoxed]). Vectors of [FixedSize]
+/// elements can store them inline instead - create them with `SVec::new_inline()`.
+/// The storage is a part of the type and can't be changed for an existing vector.
 #[derive(Readable, Writable)]
-pub struct SVec<T> {
+pub struct SVec<T, S = SBoxed> {
     _info: SVecInfo,
-    _data: SPhantomData<T>,
+    _data: SPhantomData<(T, S)>,
 }
 
 impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T> {
     pub fn new() -> Self {
+        Self::empty()
+    }
+
+    /// Creates a vector with all the sectors needed to hold `capacity` elements allocated
+    /// upfront. For inline vectors use `SVec::new_inline()` followed by `reserve()`.
+    pub fn with_capacity(capacity: u64) -> Self {
+        let mut it = Self::empty();
+        it.reserve(capacity);
+
+        it
+    }
+}
+
+impl<'a, T: FixedSize + Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T, SInline> {
+    pub fn new_inline() -> Self {
+        Self::empty()
+    }
+}
+
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>> SVec<T, S> {
+    fn empty() -> Self {
         let _info = SVecInfo {
             _len: 0,
             _sectors: Vec::new(),
@@ -35,15 +139,7 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T> {
     }
 
     pub fn push(&mut self, element: &T) {
-        let elem_cell = SUnsafeCell::new(element);
-        let elem_ptr = unsafe { elem_cell.as_ptr() };
-
-        self.grow_if_needed();
-        self.set_len(self.len() + 1);
-
-        let (sector, offset) = self.calculate_inner_index(self.len() - 1);
-
-        sector._write_word(offset, elem_ptr);
+        self.push_slot(&S::store(element));
     }
 
     pub fn pop(&mut self) -> Option<T> {
@@ -53,15 +149,10 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T> {
         }
 
         let idx = len - 1;
-        let (sector, offset) = self.calculate_inner_index(idx);
-        let elem_ptr = sector._read_word(offset);
+        let slot = self.read_slot(idx);
         self.set_len(idx);
 
-        let elem_cell = unsafe { SUnsafeCell::<T>::from_ptr(elem_ptr) };
-        let elem = elem_cell.get_cloned();
-        elem_cell.drop();
-
-        Some(elem)
+        Some(Self::take_elem(&slot))
     }
 
     pub fn get_cloned(&self, idx: u64) -> Option<T> {
@@ -69,29 +160,19 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T> {
             return None;
         }
 
-        let (sector, offset) = self.calculate_inner_index(idx);
-
-        let elem_ptr = sector._read_word(offset);
-        let elem_cell = unsafe { SUnsafeCell::<T>::from_ptr(elem_ptr) };
-        let elem = elem_cell.get_cloned();
+        let slot = self.read_slot(idx);
 
-        Some(elem)
+        Some(S::load(&slot))
     }
 
     pub fn replace(&mut self, idx: u64, element: &T) -> T {
         assert!(idx < self.len(), "Out of bounds");
-        let new_elem_cell = SUnsafeCell::new(element);
-        let new_elem_ptr = unsafe { new_elem_cell.as_ptr() };
+        let new_slot = S::store(element);
 
-        let (sector, offset) = self.calculate_inner_index(idx);
-
-        let prev_elem_ptr = sector._read_word(offset);
-        let prev_elem_cell = unsafe { SUnsafeCell::<T>::from_ptr(prev_elem_ptr) };
-        let prev_elem = prev_elem_cell.get_cloned();
-
-        sector._write_word(offset, new_elem_ptr);
+        let prev_slot = self.read_slot(idx);
+        self.write_slots(idx, &[new_slot]);
 
-        prev_elem
+        Self::take_elem(&prev_slot)
     }
 
     pub fn swap(&mut self, idx1: u64, idx2: u64) {
@@ -102,33 +183,388 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T> {
             return;
         }
 
-        let (sector1, offset1) = self.calculate_inner_index(idx1);
-        let (sector2, offset2) = self.calculate_inner_index(idx2);
+        let slot1 = self.read_slot(idx1);
+        let slot2 = self.read_slot(idx2);
+
+        self.write_slots(idx1, &[slot2]);
+        self.write_slots(idx2, &[slot1]);
+    }
+
+    /// Inserts an element at position `idx`, shifting all elements after it to the right.
+    /// Only the slots are moved - elements are not re-encoded.
+    pub fn insert(&mut self, idx: u64, element: &T) {
+        let len = self.len();
+        assert!(idx <= len, "Out of bounds");
+
+        let slot = S::store(element);
+
+        if idx == len {
+            self.push_slot(&slot);
+        } else {
+            let last_slot = self.read_slot(len - 1);
+            self.push_slot(&last_slot);
+
+            self.move_slots(idx, idx + 1, len - 1 - idx);
+            self.write_slots(idx, &[slot]);
+        }
+    }
+
+    /// Removes and returns the element at position `idx`, shifting all elements after it to the
+    /// left.
+    pub fn remove(&mut self, idx: u64) -> T {
+        let len = self.len();
+        assert!(idx < len, "Out of bounds");
 
-        let elem_ptr_1 = sector1._read_word(offset1);
-        let elem_ptr_2 = sector2._read_word(offset2);
+        let slot = self.read_slot(idx);
+        self.move_slots(idx + 1, idx, len - idx - 1);
+        self.set_len(len - 1);
 
-        sector1._write_word(offset1, elem_ptr_2);
-        sector2._write_word(offset2, elem_ptr_1);
+        Self::take_elem(&slot)
     }
 
-    pub fn drop(mut self) {
+    /// Removes and returns the element at position `idx`, replacing it with the last element.
+    /// Doesn't preserve ordering, but is O(1).
+    pub fn swap_remove(&mut self, idx: u64) -> T {
+        let len = self.len();
+        assert!(idx < len, "Out of bounds");
+
+        let slot = self.read_slot(idx);
+        if idx < len - 1 {
+            let last_slot = self.read_slot(len - 1);
+            self.write_slots(idx, &[last_slot]);
+        }
+        self.set_len(len - 1);
+
+        Self::take_elem(&slot)
+    }
+
+    /// Shortens the vector to `len` elements, freeing the rest. Does nothing if the vector is
+    /// already shorter. Freed elements are not decoded.
+    pub fn truncate(&mut self, len: u64) {
+        while self.len() > len {
+            let count = min(SVEC_ITER_CHUNK_SIZE, self.len() - len);
+
+            let slots = self.read_slots_backward(self.len(), count);
+            self.set_len(self.len() - slots.len() as u64);
+
+            for slot in slots {
+                S::free(&slot);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Keeps only the elements for which the predicate returns `true`, preserving their order.
+    /// Each element is decoded once, removed elements are freed.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        self.retain_decoded(|it| f(&it));
+    }
+
+    fn retain_decoded<F: FnMut(T) -> bool>(&mut self, mut f: F) {
+        let len = self.len();
+        let mut kept = 0u64;
+        let mut idx = 0u64;
+
+        while idx < len {
+            // kept <= idx, so writes never touch slots which are not read yet
+            let slots = self.read_slots_forward(idx, min(SVEC_ITER_CHUNK_SIZE, len - idx));
+            idx += slots.len() as u64;
+
+            for slot in slots {
+                if f(S::load(&slot)) {
+                    self.write_slots(kept, &[slot]);
+                    kept += 1;
+                } else {
+                    S::free(&slot);
+                }
+            }
+        }
+
+        self.set_len(kept);
+    }
+
+    /// Appends all elements of the iterator to the end of this vector.
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for it in iter {
+            self.push(&it);
+        }
+    }
+
+    /// Splits the vector into two at the given index. Returns a new vector containing the
+    /// elements `[at, len)`, leaving `[0, at)` in this one. Elements are moved without
+    /// re-encoding.
+    pub fn split_off(&mut self, at: u64) -> Self {
+        let len = self.len();
+        assert!(at <= len, "Out of bounds");
+
+        let mut other = Self::empty();
+        let mut idx = at;
+
+        while idx < len {
+            let slots = self.read_slots_forward(idx, min(SVEC_ITER_CHUNK_SIZE, len - idx));
+            idx += slots.len() as u64;
+
+            for slot in slots {
+                other.push_slot(&slot);
+            }
+        }
+
+        self.set_len(at);
+
+        other
+    }
+
+    /// Sorts the vector with a stable merge sort, moving slots instead of re-encoding elements.
+    ///
+    /// Runs of `SVEC_ITER_CHUNK_SIZE` elements are sorted on the heap, then merged pass by pass
+    /// through a temporary buffer in stable memory, so heap usage doesn't depend on the length of
+    /// the vector. Each element is decoded once per pass.
+    pub fn sort_by<F: FnMut(&T, &T) -> Ordering>(&mut self, mut compare: F) {
+        let len = self.len();
+        let mut from = 0;
+
+        while from < len {
+            let to = min(from + SVEC_ITER_CHUNK_SIZE, len);
+
+            let mut iter = SVecIter::new(self, from, to);
+            let mut run: Vec<_> = iter::from_fn(|| iter.next_slot())
+                .map(|it| (S::load(&it), it))
+                .collect();
+            run.sort_by(|a, b| compare(&a.0, &b.0));
+
+            let slots: Vec<_> = run.into_iter().map(|(_, slot)| slot).collect();
+            self.write_slots(from, &slots);
+
+            from = to;
+        }
+
+        if len <= SVEC_ITER_CHUNK_SIZE {
+            return;
+        }
+
+        let mut buf = Self::empty();
+        buf.allocate_sectors(len);
+        buf.set_len(len);
+
+        let mut width = SVEC_ITER_CHUNK_SIZE;
+        let mut sorted_in_buf = false;
+
+        while width < len {
+            let (src, dst) = if sorted_in_buf {
+                (&buf, &*self)
+            } else {
+                (&*self, &buf)
+            };
+
+            let mut lo = 0;
+            while lo < len {
+                let mid = min(lo + width, len);
+                let hi = min(mid + width, len);
+
+                Self::merge_runs(src, dst, lo, mid, hi, &mut compare);
+                lo = hi;
+            }
+
+            sorted_in_buf = !sorted_in_buf;
+            width *= 2;
+        }
+
+        if sorted_in_buf {
+            mem::swap(&mut self._info._sectors, &mut buf._info._sectors);
+        }
+
+        // the buffer holds no elements of its own - only release its sectors
+        for sector in buf._info._sectors {
+            deallocate(sector);
+        }
+    }
+
+    pub fn sort_by_key<K: Ord, F: FnMut(&T) -> K>(&mut self, mut f: F) {
+        self.sort_by(|a, b| f(a).cmp(&f(b)));
+    }
+
+    /// Sorts the vector with an in-place heap sort. Unlike `sort_by()`, doesn't use any
+    /// additional memory, but decodes about `2 * log2(len)` elements per element.
+    pub fn sort_unstable_by<F: FnMut(&T, &T) -> Ordering>(&mut self, mut compare: F) {
+        let len = self.len();
+
+        for idx in (0..len / 2).rev() {
+            self.sift_down(idx, len, &mut compare);
+        }
+
+        for end in (1..len).rev() {
+            self.swap(0, end);
+            self.sift_down(0, end, &mut compare);
+        }
+    }
+
+    /// Binary searches this sorted vector with a comparator function. Only the probed elements
+    /// are decoded. Returns the same results as `slice::binary_search_by()`.
+    pub fn binary_search_by<F: FnMut(&T) -> Ordering>(&self, mut f: F) -> Result<u64, u64> {
+        let mut left = 0;
+        let mut right = self.len();
+
+        while left < right {
+            let mid = left + (right - left) / 2;
+
+            match f(&S::load(&self.read_slot(mid))) {
+                Ordering::Less => left = mid + 1,
+                Ordering::Greater => right = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+
+        Err(left)
+    }
+
+    /// Returns the index of the first element for which the predicate returns `false`, assuming
+    /// the vector is partitioned by it. Only the probed elements are decoded.
+    pub fn partition_point<P: FnMut(&T) -> bool>(&self, mut pred: P) -> u64 {
+        let mut left = 0;
+        let mut right = self.len();
+
+        while left < right {
+            let mid = left + (right - left) / 2;
+
+            if pred(&S::load(&self.read_slot(mid))) {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+
+        left
+    }
+
+    fn merge_runs<F: FnMut(&T, &T) -> Ordering>(
+        src: &Self,
+        dst: &Self,
+        lo: u64,
+        mid: u64,
+        hi: u64,
+        compare: &mut F,
+    ) {
+        let mut left = SVecIter::new(src, lo, mid);
+        let mut right = SVecIter::new(src, mid, hi);
+
+        let mut left_head = left.next_slot().map(|it| (S::load(&it), it));
+        let mut right_head = right.next_slot().map(|it| (S::load(&it), it));
+
+        let mut out = Vec::new();
+        let mut out_idx = lo;
+
+        loop {
+            let take_left = match (&left_head, &right_head) {
+                (Some(l), Some(r)) => compare(&l.0, &r.0) != Ordering::Greater,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+
+            let slot = if take_left {
+                let (_, slot) = left_head.take().unwrap();
+                left_head = left.next_slot().map(|it| (S::load(&it), it));
+
+                slot
+            } else {
+                let (_, slot) = right_head.take().unwrap();
+                right_head = right.next_slot().map(|it| (S::load(&it), it));
+
+                slot
+            };
+
+            out.push(slot);
+
+            if out.len() as u64 == SVEC_ITER_CHUNK_SIZE {
+                dst.write_slots(out_idx, &out);
+                out_idx += out.len() as u64;
+                out.clear();
+            }
+        }
+
+        dst.write_slots(out_idx, &out);
+    }
+
+    fn sift_down<F: FnMut(&T, &T) -> Ordering>(&self, mut idx: u64, end: u64, compare: &mut F) {
+        let slot = self.read_slot(idx);
+        let elem = S::load(&slot);
+
         loop {
-            if self.pop().is_none() {
+            let mut child = 2 * idx + 1;
+            if child >= end {
+                break;
+            }
+
+            let mut child_slot = self.read_slot(child);
+            let mut child_elem = S::load(&child_slot);
+
+            if child + 1 < end {
+                let right_slot = self.read_slot(child + 1);
+                let right_elem = S::load(&right_slot);
+
+                if compare(&child_elem, &right_elem) == Ordering::Less {
+                    child += 1;
+                    child_slot = right_slot;
+                    child_elem = right_elem;
+                }
+            }
+
+            if compare(&elem, &child_elem) != Ordering::Less {
                 break;
             }
+
+            self.write_slots(idx, &[child_slot]);
+            idx = child;
         }
 
+        self.write_slots(idx, &[slot]);
+    }
+
+    pub fn drop(mut self) {
+        self.clear();
+
         for sector in self._info._sectors {
             deallocate(sector);
         }
     }
 
     pub fn capacity(&self) -> u64 {
-        if self._info._sectors.len() < 28 {
-            2u64.pow(self._info._sectors.len() as u32 + 2) - 4
+        Self::capacity_of(self._info._sectors.len())
+    }
+
+    /// Allocates all the sectors needed to hold at least `additional` more elements at once, so
+    /// the following pushes don't touch the allocator.
+    pub fn reserve(&mut self, additional: u64) {
+        let capacity = self
+            .len()
+            .checked_add(additional)
+            .expect("capacity overflow");
+
+        self.allocate_sectors(capacity);
+    }
+
+    /// Releases trailing sectors which hold no elements. Sectors only come in fixed sizes, so the
+    /// capacity can stay bigger than the length.
+    pub fn shrink_to_fit(&mut self) {
+        let sectors = &mut self._info._sectors;
+
+        while !sectors.is_empty() && Self::capacity_of(sectors.len() - 1) >= self._info._len {
+            deallocate(sectors.pop().unwrap());
+        }
+    }
+
+    fn capacity_of(sectors_count: usize) -> u64 {
+        let max_log2 = Self::max_sector_capacity_log2();
+
+        if sectors_count < max_log2 as usize - 1 {
+            2u64.pow(sectors_count as u32 + 2) - 4
         } else {
-            TWO_IN_29 - 4 + TWO_IN_29 * (self._info._sectors.len() as u64 - 27)
+            let max_capacity = 2u64.pow(max_log2);
+
+            max_capacity - 4 + max_capacity * (sectors_count as u64 - (max_log2 as u64 - 2))
         }
     }
 
@@ -154,47 +590,330 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SVec<T> {
 
     fn grow_if_needed(&mut self) {
         if self.is_about_to_grow() {
-            let new_sector_size =
-                2u64.pow(min(self._info._sectors.len() as u32 + 2, 29)) as usize * PTR_SIZE;
+            self.allocate_sectors(self.len() + 1);
+        }
+    }
+
+    /// Allocates new sectors until the vector can hold `capacity` elements
+    fn allocate_sectors(&mut self, capacity: u64) {
+        while self.capacity() < capacity {
+            let new_sector_size = Self::sector_capacity(self._info._sectors.len())
+                .checked_mul(S::SLOT_SIZE as u64)
+                .and_then(|it| usize::try_from(it).ok())
+                .expect("capacity overflow");
 
             let sector = allocate(new_sector_size);
             self._info._sectors.push(sector);
         }
     }
 
-    fn calculate_inner_index(&self, mut idx: u64) -> (&SSlice<SVecSector>, usize) {
+    /// Returns an iterator over all elements of this vector. Elements are decoded one by one, as
+    /// the iterator advances.
+    pub fn iter(&self) -> SVecIter<'_, T, S> {
+        SVecIter::new(self, 0, self.len())
+    }
+
+    /// Returns an iterator over the elements of this vector within the range of indices.
+    /// Panics if the range is out of bounds.
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> SVecIter<'_, T, S> {
+        let from = match range.start_bound() {
+            Bound::Included(it) => *it,
+            Bound::Excluded(it) => *it + 1,
+            Bound::Unbounded => 0,
+        };
+
+        let to = match range.end_bound() {
+            Bound::Included(it) => *it + 1,
+            Bound::Excluded(it) => *it,
+            Bound::Unbounded => self.len(),
+        };
+
+        assert!(from <= to, "Invalid range");
+        assert!(to <= self.len(), "Out of bounds");
+
+        SVecIter::new(self, from, to)
+    }
+
+    fn read_slot(&self, idx: u64) -> Vec<u8> {
+        let (sector, offset) = self.calculate_inner_index(idx);
+
+        let mut slot = vec![0u8; S::SLOT_SIZE];
+        sector._read_bytes(offset, &mut slot);
+
+        slot
+    }
+
+    /// Reads a run of consecutive slots, starting from `idx`. The run never crosses a sector
+    /// boundary, so it can be shorter than `max_count`.
+    fn read_slots_forward(&self, idx: u64, max_count: u64) -> Vec<Vec<u8>> {
+        let (sector_idx, offset_slot) = Self::calculate_sector_and_offset(idx);
+        let count = min(
+            max_count,
+            Self::sector_capacity(sector_idx) - offset_slot as u64,
+        ) as usize;
+
+        self.read_slots(sector_idx, offset_slot, count)
+    }
+
+    /// Reads a run of consecutive slots, ending right before `idx`. The run never crosses a
+    /// sector boundary, so it can be shorter than `max_count`.
+    fn read_slots_backward(&self, idx: u64, max_count: u64) -> Vec<Vec<u8>> {
+        let (sector_idx, offset_slot) = Self::calculate_sector_and_offset(idx - 1);
+        let count = min(max_count, offset_slot as u64 + 1) as usize;
+
+        self.read_slots(sector_idx, offset_slot + 1 - count, count)
+    }
+
+    fn read_slots(&self, sector_idx: usize, offset_slot: usize, count: usize) -> Vec<Vec<u8>> {
+        let mut buf = vec![0u8; count * S::SLOT_SIZE];
+        self.get_sector(sector_idx)
+            ._read_bytes(offset_slot * S::SLOT_SIZE, &mut buf);
+
+        buf.chunks_exact(S::SLOT_SIZE)
+            .map(|it| it.to_vec())
+            .collect()
+    }
+
+    /// Writes consecutive slots, starting from `idx`, possibly across several sectors.
+    fn write_slots(&self, mut idx: u64, mut slots: &[Vec<u8>]) {
+        while !slots.is_empty() {
+            let (sector_idx, offset_slot) = Self::calculate_sector_and_offset(idx);
+            let count = min(
+                slots.len() as u64,
+                Self::sector_capacity(sector_idx) - offset_slot as u64,
+            ) as usize;
+
+            self.get_sector(sector_idx)
+                ._write_bytes(offset_slot * S::SLOT_SIZE, &slots[..count].concat());
+
+            idx += count as u64;
+            slots = &slots[count..];
+        }
+    }
+
+    /// Moves `count` slots from `src` to `dst`. The ranges can overlap - slots are copied in
+    /// runs, in the direction which never overwrites an unread slot.
+    fn move_slots(&self, src: u64, dst: u64, count: u64) {
+        let mut moved = 0;
+
+        if dst < src {
+            while moved < count {
+                let slots =
+                    self.read_slots_forward(src + moved, min(SVEC_ITER_CHUNK_SIZE, count - moved));
+                self.write_slots(dst + moved, &slots);
+
+                moved += slots.len() as u64;
+            }
+        } else if dst > src {
+            while moved < count {
+                let slots = self.read_slots_backward(
+                    src + count - moved,
+                    min(SVEC_ITER_CHUNK_SIZE, count - moved),
+                );
+                moved += slots.len() as u64;
+
+                self.write_slots(dst + count - moved, &slots);
+            }
+        }
+    }
+
+    fn push_slot(&mut self, slot: &[u8]) {
+        self.grow_if_needed();
+        self.set_len(self.len() + 1);
+
+        let (sector, offset) = self.calculate_inner_index(self.len() - 1);
+        sector._write_bytes(offset, slot);
+    }
+
+    fn take_elem(slot: &[u8]) -> T {
+        let elem = S::load(slot);
+        S::free(slot);
+
+        elem
+    }
+
+    fn sector_capacity(sector_idx: usize) -> u64 {
+        2u64.pow(min(sector_idx as u32 + 2, Self::max_sector_capacity_log2()))
+    }
+
+    /// Sectors grow twice each time, until they reach `2^max_sector_capacity_log2()` slots.
+    /// The limit only depends on the slot size, so it never changes for an existing vector.
+    fn max_sector_capacity_log2() -> u32 {
+        let max_capacity = MAX_SECTOR_SIZE_BYTES / S::SLOT_SIZE.max(1) as u64;
+
+        (fast_log2_64(max_capacity.max(1)) as u32).clamp(2, MAX_SECTOR_CAPACITY_LOG2)
+    }
+
+    fn calculate_inner_index(&self, idx: u64) -> (&SSlice<SVecSector>, usize) {
         assert!(idx < self.len());
 
-        let (sector_idx, offset_ptr) = if idx > TWO_IN_29 - 4 {
-            idx -= TWO_IN_29 - 4;
-            let sector_idx = 27 + (idx / TWO_IN_29) as usize;
-            let offset_ptr = (idx % TWO_IN_29) as usize;
+        let (sector_idx, offset_slot) = Self::calculate_sector_and_offset(idx);
 
-            (sector_idx, offset_ptr)
+        (self.get_sector(sector_idx), offset_slot * S::SLOT_SIZE)
+    }
+
+    fn calculate_sector_and_offset(mut idx: u64) -> (usize, usize) {
+        let max_log2 = Self::max_sector_capacity_log2();
+        let max_capacity = 2u64.pow(max_log2);
+
+        if idx > max_capacity - 4 {
+            idx -= max_capacity - 4;
+            let sector_idx = max_log2 as usize - 2 + (idx / max_capacity) as usize;
+            let offset_slot = (idx % max_capacity) as usize;
+
+            (sector_idx, offset_slot)
         } else {
             let sector_idx = fast_log2_64(idx + 4) as usize - 2;
-            let ptrs_in_prev_sectors = 2u64.pow(sector_idx as u32 + 2) - 4;
+            let slots_in_prev_sectors = 2u64.pow(sector_idx as u32 + 2) - 4;
 
-            let offset_ptr = (idx - ptrs_in_prev_sectors) as usize;
+            let offset_slot = (idx - slots_in_prev_sectors) as usize;
 
-            (sector_idx, offset_ptr)
-        };
+            (sector_idx, offset_slot)
+        }
+    }
+}
+
+impl<'a, T: PartialEq + Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>>
+    SVec<T, S>
+{
+    /// Removes consecutive repeated elements, keeping the first one of each run.
+    pub fn dedup(&mut self) {
+        let mut prev = None;
+
+        self.retain_decoded(|it| {
+            if prev.as_ref() == Some(&it) {
+                false
+            } else {
+                prev = Some(it);
+                true
+            }
+        });
+    }
+}
+
+impl<'a, T: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>>
+    SVec<T, S>
+{
+    /// See `sort_by()`
+    pub fn sort(&mut self) {
+        self.sort_by(T::cmp);
+    }
 
-        (self.get_sector(sector_idx), offset_ptr * PTR_SIZE)
+    pub fn binary_search(&self, it: &T) -> Result<u64, u64> {
+        self.binary_search_by(|probe| probe.cmp(it))
     }
 }
 
-impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> Default for SVec<T> {
+impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>> Default
+    for SVec<T, S>
+{
     fn default() -> Self {
-        Self::new()
+        Self::empty()
+    }
+}
+
+/// An iterator over the elements of [SVec], created by [SVec::iter] or [SVec::range].
+///
+/// Slots are read from stable memory in runs of up to `SVEC_ITER_CHUNK_SIZE`, so iterating
+/// costs about one stable memory read per boxed element (the element itself) plus a small
+/// overhead for each run.
+pub struct SVecIter<'b, T, S = SBoxed> {
+    vec: &'b SVec<T, S>,
+    front: u64,
+    back: u64,
+    front_slots: VecDeque<Vec<u8>>,
+    back_slots: VecDeque<Vec<u8>>,
+}
+
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>>
+    SVecIter<'b, T, S>
+{
+    fn new(vec: &'b SVec<T, S>, front: u64, back: u64) -> Self {
+        Self {
+            vec,
+            front,
+            back,
+            front_slots: VecDeque::new(),
+            back_slots: VecDeque::new(),
+        }
+    }
+
+    fn next_slot(&mut self) -> Option<Vec<u8>> {
+        if self.front == self.back {
+            return None;
+        }
+
+        if self.front_slots.is_empty() {
+            // front and back runs never overlap - the front run can only grow up to the back one
+            let unread_back = self.back - self.back_slots.len() as u64;
+
+            if self.front < unread_back {
+                let count = min(SVEC_ITER_CHUNK_SIZE, unread_back - self.front);
+                self.front_slots = self.vec.read_slots_forward(self.front, count).into();
+            } else {
+                self.front_slots
+                    .push_back(self.back_slots.pop_front().unwrap());
+            }
+        }
+
+        self.front += 1;
+
+        self.front_slots.pop_front()
+    }
+}
+
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>> Iterator
+    for SVecIter<'b, T, S>
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_slot().map(|it| S::load(&it))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.back - self.front) as usize;
+
+        (len, Some(len))
     }
 }
 
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>>
+    DoubleEndedIterator for SVecIter<'b, T, S>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+
+        if self.back_slots.is_empty() {
+            let unread_front = self.front + self.front_slots.len() as u64;
+
+            if unread_front < self.back {
+                let count = min(SVEC_ITER_CHUNK_SIZE, self.back - unread_front);
+                self.back_slots = self.vec.read_slots_backward(self.back, count).into();
+            } else {
+                self.back_slots
+                    .push_front(self.front_slots.pop_back().unwrap());
+            }
+        }
+
+        self.back -= 1;
+
+        self.back_slots.pop_back().map(|it| S::load(&it))
+    }
+}
+
+impl<'a, 'b, T: Readable<'a, LittleEndian> + Writable<LittleEndian>, S: SVecStorage<T>>
+    ExactSizeIterator for SVecIter<'b, T, S>
+{
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::collections::vec::SVec;
-    use crate::init_allocator;
+    use crate::collections::vec::{SBoxed, SInline, SVec, SVecStorage, MAX_SECTOR_SIZE_BYTES};
     use crate::utils::mem_context::stable;
+    use crate::{get_allocated_size, init_allocator};
     use speedy::{Readable, Writable};
 
     #[derive(Readable, Writable, Debug)]
@@ -220,6 +939,93 @@ mod tests {
         stable_vec.drop();
     }
 
+    struct Wide;
+
+    impl SVecStorage<u64> for Wide {
+        const SLOT_SIZE: usize = 1024;
+
+        fn store(it: &u64) -> Vec<u8> {
+            let mut slot = it.to_le_bytes().to_vec();
+            slot.resize(Self::SLOT_SIZE, 0);
+
+            slot
+        }
+
+        fn load(slot: &[u8]) -> u64 {
+            u64::from_le_bytes(slot[..8].try_into().unwrap())
+        }
+
+        fn free(_slot: &[u8]) {}
+    }
+
+    fn check_sector_layout<S: SVecStorage<u64>>(max_log2: u32) {
+        assert_eq!(SVec::<u64, S>::max_sector_capacity_log2(), max_log2);
+
+        let mut capacity = 0;
+        for sector_idx in 0..64 {
+            let sector_capacity = SVec::<u64, S>::sector_capacity(sector_idx);
+            assert!(sector_capacity * S::SLOT_SIZE as u64 <= MAX_SECTOR_SIZE_BYTES);
+
+            assert_eq!(
+                SVec::<u64, S>::calculate_sector_and_offset(capacity),
+                (sector_idx, 0)
+            );
+
+            capacity += sector_capacity;
+            assert_eq!(SVec::<u64, S>::capacity_of(sector_idx + 1), capacity);
+            assert_eq!(
+                SVec::<u64, S>::calculate_sector_and_offset(capacity - 1),
+                (sector_idx, sector_capacity as usize - 1)
+            );
+        }
+    }
+
+    #[test]
+    fn sectors_are_capped_by_size() {
+        check_sector_layout::<SBoxed>(28);
+        check_sector_layout::<SInline>(28);
+        check_sector_layout::<Wide>(21);
+
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut stable_vec = SVec::<u64, Wide>::empty();
+        for i in 0..100 {
+            stable_vec.push(&i);
+        }
+
+        assert_eq!(
+            stable_vec.iter().collect::<Vec<_>>(),
+            (0..100).collect::<Vec<_>>()
+        );
+        stable_vec.drop();
+    }
+
+    #[test]
+    fn replace_frees_previous_element() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut stable_vec = SVec::<String>::new();
+        stable_vec.push(&String::from("first"));
+        let allocated = get_allocated_size();
+
+        for i in 0..100 {
+            stable_vec.replace(0, &format!("element {}", i));
+        }
+
+        assert_eq!(stable_vec.get_cloned(0).unwrap(), "element 99");
+        assert_eq!(
+            get_allocated_size(),
+            allocated,
+            "Replaced elements leak memory"
+        );
+
+        stable_vec.drop();
+    }
+
     #[test]
     fn push_pop_work_fine() {
         stable::clear();
@@ -271,4 +1077,291 @@ mod tests {
 
         stable_vec.drop();
     }
+
+    #[test]
+    fn iterators_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut stable_vec = SVec::new();
+        let count = 1000u64;
+
+        for i in 0..count {
+            stable_vec.push(&i);
+        }
+
+        let forward: Vec<_> = stable_vec.iter().collect();
+        assert_eq!(forward, (0..count).collect::<Vec<_>>());
+
+        let backward: Vec<_> = stable_vec.iter().rev().collect();
+        assert_eq!(backward, (0..count).rev().collect::<Vec<_>>());
+
+        let range: Vec<_> = stable_vec.range(10..300).collect();
+        assert_eq!(range, (10..300).collect::<Vec<_>>());
+
+        let range: Vec<_> = stable_vec.range(..=3).rev().collect();
+        assert_eq!(range, vec![3, 2, 1, 0]);
+
+        assert_eq!(stable_vec.range(500..).len(), 500);
+        assert_eq!(stable_vec.range(5..5).next(), None);
+
+        // both ends meet in the middle
+        let mut iter = stable_vec.range(100..700);
+        let mut front = vec![];
+        let mut back = vec![];
+
+        while let Some(it) = iter.next() {
+            front.push(it);
+
+            match iter.next_back() {
+                Some(it) => back.push(it),
+                None => break,
+            }
+        }
+
+        back.reverse();
+        front.extend(back);
+
+        assert_eq!(front, (100..700).collect::<Vec<_>>());
+
+        let page: Vec<_> = stable_vec.iter().skip(20).step_by(10).take(3).collect();
+        assert_eq!(page, vec![20, 30, 40]);
+
+        stable_vec.drop();
+    }
+
+    #[test]
+    fn editing_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        let mut stable_vec = SVec::new();
+        let mut vec = Vec::new();
+
+        stable_vec.extend(0..500u64);
+        vec.extend(0..500u64);
+
+        // shifts across several sectors
+        stable_vec.insert(0, &1000);
+        vec.insert(0, 1000);
+        stable_vec.insert(250, &1001);
+        vec.insert(250, 1001);
+        stable_vec.insert(stable_vec.len(), &1002);
+        vec.push(1002);
+
+        assert_eq!(stable_vec.remove(3), vec.remove(3));
+        assert_eq!(stable_vec.remove(0), vec.remove(0));
+        assert_eq!(stable_vec.swap_remove(10), vec.swap_remove(10));
+        assert_eq!(
+            stable_vec.swap_remove(stable_vec.len() - 1),
+            vec.swap_remove(vec.len() - 1)
+        );
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), vec);
+
+        stable_vec.retain(|it| it % 3 != 0);
+        vec.retain(|it| it % 3 != 0);
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), vec);
+
+        let mut stable_tail = stable_vec.split_off(100);
+        let tail = vec.split_off(100);
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), vec);
+        assert_eq!(stable_tail.iter().collect::<Vec<_>>(), tail);
+
+        stable_tail.truncate(10);
+        assert_eq!(stable_tail.iter().collect::<Vec<_>>(), tail[..10]);
+        stable_tail.truncate(20);
+        assert_eq!(stable_tail.len(), 10);
+
+        stable_tail.clear();
+        assert!(stable_tail.is_empty());
+        stable_tail.drop();
+
+        stable_vec.clear();
+        for it in [1u64, 1, 2, 3, 3, 3, 1, 4, 4] {
+            stable_vec.push(&it);
+        }
+        stable_vec.dedup();
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), vec![1, 2, 3, 1, 4]);
+
+        stable_vec.drop();
+
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    fn inline_storage_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        let mut stable_vec = SVec::<(u64, i32), SInline>::new_inline();
+        let count = 1000u64;
+
+        for i in 0..count {
+            stable_vec.push(&(i, -(i as i32)));
+        }
+
+        assert_eq!(stable_vec.len(), count);
+        assert_eq!(stable_vec.get_cloned(500), Some((500, -500)));
+
+        // only sectors (12 bytes per slot plus meta) are allocated, no cells
+        let sectors_size: u64 = (0..8).map(|i| 2u64.pow(i + 2) * 12 + 16).sum();
+        assert_eq!(get_allocated_size() - initial_allocated, sectors_size);
+
+        assert_eq!(stable_vec.replace(10, &(1, 1)), (10, -10));
+        assert_eq!(stable_vec.remove(10), (1, 1));
+        stable_vec.insert(10, &(10, -10));
+        stable_vec.swap(0, 1);
+        stable_vec.swap(0, 1);
+
+        let all: Vec<_> = stable_vec.iter().collect();
+        assert_eq!(
+            all,
+            (0..count).map(|i| (i, -(i as i32))).collect::<Vec<_>>()
+        );
+
+        stable_vec.retain(|(i, _)| i % 2 == 0);
+        assert_eq!(
+            stable_vec.range(..3).rev().collect::<Vec<_>>(),
+            vec![(4, -4), (2, -2), (0, 0)]
+        );
+        assert_eq!(stable_vec.pop(), Some((998, -998)));
+
+        let buf = stable_vec.write_to_vec().unwrap();
+        let stable_vec = SVec::<(u64, i32), SInline>::read_from_buffer(&buf).unwrap();
+        assert_eq!(stable_vec.get_cloned(3), Some((6, -6)));
+
+        stable_vec.drop();
+
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    fn sort_and_search_work_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        // pseudo-random pairs with a lot of equal keys
+        let vec: Vec<(u64, u64)> = (0..2000u64).map(|i| ((i * 7919) % 101, i)).collect();
+
+        let mut stable_vec = SVec::new();
+        stable_vec.extend(vec.iter().copied());
+
+        // stable, so equal keys keep their original order
+        let mut expected = vec.clone();
+        expected.sort_by_key(|it| it.0);
+        stable_vec.sort_by_key(|it| it.0);
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), expected);
+
+        let mut expected = vec.clone();
+        expected.sort_by(|a, b| b.cmp(a));
+        stable_vec.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), expected);
+
+        stable_vec.sort();
+        expected.sort();
+        assert_eq!(stable_vec.iter().collect::<Vec<_>>(), expected);
+
+        assert_eq!(stable_vec.binary_search(&expected[1234]), Ok(1234));
+        assert_eq!(
+            stable_vec.binary_search(&(50, 5000)),
+            expected
+                .binary_search(&(50, 5000))
+                .map(|it| it as u64)
+                .map_err(|it| it as u64)
+        );
+        assert_eq!(
+            stable_vec.partition_point(|it| it.0 < 30),
+            expected.partition_point(|it| it.0 < 30) as u64
+        );
+        assert_eq!(
+            stable_vec.binary_search_by(|it| it.0.cmp(&1000)),
+            Err(stable_vec.len())
+        );
+
+        stable_vec.drop();
+
+        let mut inline_vec = SVec::<u32, SInline>::new_inline();
+        inline_vec.extend((0..300u32).rev());
+        inline_vec.sort();
+        assert_eq!(
+            inline_vec.iter().collect::<Vec<_>>(),
+            (0..300).collect::<Vec<_>>()
+        );
+        inline_vec.drop();
+
+        let mut empty = SVec::<u64>::new();
+        empty.sort();
+        empty.sort_unstable_by(|a, b| a.cmp(b));
+        assert_eq!(empty.binary_search(&10), Err(0));
+        empty.drop();
+
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    fn capacity_management_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let initial_allocated = get_allocated_size();
+
+        let mut stable_vec = SVec::<u64>::with_capacity(1000);
+        assert!(stable_vec.capacity() >= 1000);
+        assert_eq!(stable_vec.len(), 0);
+
+        let sectors_allocated = get_allocated_size();
+        stable_vec.extend(0..1000);
+        // only element cells (16 bytes each, plus meta) are allocated
+        assert_eq!(get_allocated_size() - sectors_allocated, 1000 * 32);
+
+        stable_vec.reserve(100);
+        assert!(stable_vec.capacity() >= 1100);
+
+        stable_vec.truncate(10);
+        stable_vec.shrink_to_fit();
+        assert_eq!(stable_vec.capacity(), 12);
+        assert_eq!(
+            stable_vec.iter().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+
+        stable_vec.clear();
+        stable_vec.shrink_to_fit();
+        assert_eq!(stable_vec.capacity(), 0);
+        assert_eq!(get_allocated_size(), initial_allocated);
+
+        stable_vec.push(&1);
+        assert_eq!(stable_vec.pop(), Some(1));
+        stable_vec.drop();
+
+        let mut inline_vec = SVec::<u64, SInline>::new_inline();
+        inline_vec.reserve(10);
+        assert_eq!(inline_vec.capacity(), 12);
+        inline_vec.drop();
+
+        assert_eq!(get_allocated_size(), initial_allocated);
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity overflow")]
+    fn reserve_overflow_panics() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut stable_vec = SVec::<u64>::new();
+        stable_vec.push(&1);
+        stable_vec.reserve(u64::MAX);
+    }
 }
@@ -2,6 +2,10 @@ use crate::mem::allocator::StableMemoryAllocator;
 use crate::primitive::s_unsafe_cell::SUnsafeCell;
 use ic_cdk::print;
 use primitive::s_slice::SSlice;
+#[cfg(not(target_family = "wasm"))]
+use std::cell::RefCell;
+#[cfg(target_family = "wasm")]
+use std::ptr::addr_of_mut;
 
 mod benchmarks;
 pub mod collections;
@@ -11,82 +15,114 @@ pub mod primitive;
 pub mod utils;
 
 pub use crate::utils::mem_context::{stable, OutOfMemory, PAGE_SIZE_BYTES};
-pub use crate::utils::vars::{init_vars, reinit_vars, store_vars};
+pub use crate::utils::vars::{init_vars, reinit_vars, store_vars, SVar};
 use crate::utils::MemMetrics;
 
+#[cfg(target_family = "wasm")]
 static mut STABLE_MEMORY_ALLOCATOR: Option<SSlice<StableMemoryAllocator>> = None;
 
+#[cfg(target_family = "wasm")]
+fn with_allocator_slot<R, F: FnOnce(&mut Option<SSlice<StableMemoryAllocator>>) -> R>(f: F) -> R {
+    unsafe { f(&mut *addr_of_mut!(STABLE_MEMORY_ALLOCATOR)) }
+}
+
+// Outside of canisters stable memory is emulated per thread (see `stable`), so each test, running
+// in its own thread, gets its own allocator as well.
+#[cfg(not(target_family = "wasm"))]
+thread_local! {
+    static STABLE_MEMORY_ALLOCATOR: RefCell<Option<SSlice<StableMemoryAllocator>>> = const { RefCell::new(None) };
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn with_allocator_slot<R, F: FnOnce(&mut Option<SSlice<StableMemoryAllocator>>) -> R>(f: F) -> R {
+    STABLE_MEMORY_ALLOCATOR.with(|it| f(&mut it.borrow_mut()))
+}
+
 pub fn init_allocator(offset: u64) {
-    unsafe {
-        if STABLE_MEMORY_ALLOCATOR.is_none() {
-            let allocator = SSlice::<StableMemoryAllocator>::init(offset);
+    with_allocator_slot(|it| {
+        if it.is_none() {
+            let allocator = unsafe { SSlice::<StableMemoryAllocator>::init(offset) };
 
-            STABLE_MEMORY_ALLOCATOR = Some(allocator)
+            *it = Some(allocator)
         } else {
             unreachable!("StableMemoryAllocator can only be initialized once");
         }
-    }
+    })
 }
 
 pub fn reinit_allocator(offset: u64) {
-    unsafe {
-        if STABLE_MEMORY_ALLOCATOR.is_none() {
-            let allocator = SSlice::<StableMemoryAllocator>::reinit(offset)
+    with_allocator_slot(|it| {
+        if it.is_none() {
+            let allocator = unsafe { SSlice::<StableMemoryAllocator>::reinit(offset) }
                 .expect("Unable to reinit StableMemoryAllocator");
 
-            STABLE_MEMORY_ALLOCATOR = Some(allocator)
+            *it = Some(allocator)
         } else {
             unreachable!("StableMemoryAllocator can only be initialized once")
         }
-    }
+    })
+}
+
+fn with_allocator<R, F: FnOnce(&mut SSlice<StableMemoryAllocator>) -> R>(f: F) -> R {
+    with_allocator_slot(|it| {
+        f(it.as_mut()
+            .expect("StableMemoryAllocator is not initialized yet"))
+    })
 }
 
-fn get_allocator() -> &'static mut SSlice<StableMemoryAllocator> {
-    unsafe { STABLE_MEMORY_ALLOCATOR.as_mut().unwrap() }
+/// Forgets the allocator, so it can be reinitialized, like after an upgrade
+#[cfg(test)]
+pub(crate) fn deinit_allocator() {
+    with_allocator_slot(|it| *it = None)
 }
 
 pub fn allocate<T>(size: usize) -> SSlice<T> {
-    get_allocator().allocate(size)
+    with_allocator(|it| it.allocate(size))
+}
+
+/// Same as [allocate], but returns [OutOfMemory] instead of trapping
+pub fn try_allocate<T>(size: usize) -> Result<SSlice<T>, OutOfMemory> {
+    with_allocator(|it| it.try_allocate(size))
 }
 
 pub fn deallocate<T>(membox: SSlice<T>) {
-    get_allocator().deallocate(membox)
+    with_allocator(|it| it.deallocate(membox))
 }
 
 pub fn reallocate<T>(membox: SSlice<T>, new_size: usize) -> SSlice<T> {
-    get_allocator().reallocate(membox, new_size)
+    with_allocator(|it| it.reallocate(membox, new_size))
 }
 
 pub fn set_max_allocation_pages(pages: u32) {
-    get_allocator().set_max_allocation_pages(pages)
+    with_allocator(|it| it.set_max_allocation_pages(pages))
 }
 
 pub fn get_max_allocation_pages() -> u32 {
-    get_allocator().get_max_allocation_pages()
+    with_allocator(|it| it.get_max_allocation_pages())
 }
 
 pub fn set_max_grow_pages(pages: u64) {
-    get_allocator().set_max_grow_pages(pages)
+    with_allocator(|it| it.set_max_grow_pages(pages))
 }
 
 pub fn get_max_grow_pages() -> u64 {
-    get_allocator().get_max_grow_pages()
+    with_allocator(|it| it.get_max_grow_pages())
 }
 
 pub fn reset() {
-    get_allocator().reset()
+    with_allocator(|it| it.reset())
 }
 
 pub fn get_allocated_size() -> u64 {
-    get_allocator().get_allocated_size()
+    with_allocator(|it| it.get_allocated_size())
 }
 
 pub fn get_free_size() -> u64 {
-    get_allocator().get_free_size()
+    with_allocator(|it| it.get_free_size())
 }
 
 pub fn _set_custom_data_ptr(idx: usize, data_ptr: u64) {
-    get_allocator().set_custom_data_ptr(idx, data_ptr)
+    with_allocator(|it| it.set_custom_data_ptr(idx, data_ptr))
 }
 
 pub fn get_mem_metrics() -> MemMetrics {
@@ -98,11 +134,11 @@ pub fn get_mem_metrics() -> MemMetrics {
 }
 
 pub fn _get_custom_data_ptr(idx: usize) -> u64 {
-    get_allocator().get_custom_data_ptr(idx)
+    with_allocator(|it| it.get_custom_data_ptr(idx))
 }
 
 pub fn _debug_print_allocator() {
-    print(format!("{:?}", get_allocator()))
+    print(with_allocator(|it| format!("{:?}", it)))
 }
 
 pub fn stable_memory_init(should_grow: bool, allocator_pointer: u64) {
@@ -114,9 +150,9 @@ pub fn stable_memory_init(should_grow: bool, allocator_pointer: u64) {
     init_vars();
 }
 
-pub fn stable_memory_pre_upgrade() {
-    store_vars();
-}
+/// Does nothing - all the data, including stable vars, is already in stable memory.
+/// Kept for backward compatibility, so it is fine to still call it in `pre_upgrade`.
+pub fn stable_memory_pre_upgrade() {}
 
 pub fn stable_memory_post_upgrade(allocator_pointer: u64) {
     reinit_allocator(allocator_pointer);
@@ -1,5 +1,23 @@
 #[macro_export]
 macro_rules! s {
+    ( mut $key:literal : $ty:ty => $f:expr ) => {
+        ic_stable_memory::utils::vars::with_var_mut::<$ty, _, _>($key, $f)
+    };
+    ( $key:literal : $ty:ty => $f:expr ) => {
+        ic_stable_memory::utils::vars::with_var::<$ty, _, _>($key, $f)
+    };
+    ( mut $name:ident => $f:expr ) => {
+        ic_stable_memory::utils::vars::with_var_mut::<$name, _, _>(stringify!($name), $f)
+    };
+    ( $name:ident => $f:expr ) => {
+        ic_stable_memory::utils::vars::with_var::<$name, _, _>(stringify!($name), $f)
+    };
+    ( $key:literal : $ty:ty ) => {
+        ic_stable_memory::utils::vars::get_var::<$ty>($key)
+    };
+    ( $key:literal : $ty:ty = $val:expr ) => {
+        ic_stable_memory::utils::vars::set_var::<$ty>($key, &$val)
+    };
     ( $name:ident ) => {
         ic_stable_memory::utils::vars::get_var::<$name>(stringify!($name))
     };
@@ -2,8 +2,11 @@ use crate::primitive::s_slice::{Side, CELL_MIN_SIZE, PTR_SIZE};
 use crate::utils::math::fast_log2;
 use crate::utils::mem_context::{stable, OutOfMemory, PAGE_SIZE_BYTES};
 use crate::SSlice;
+#[cfg(target_family = "wasm")]
 use ic_cdk::api::call::call_raw;
-use ic_cdk::{id, print, spawn, trap};
+use ic_cdk::trap;
+#[cfg(target_family = "wasm")]
+use ic_cdk::{id, print, spawn};
 use std::fmt::{Debug, Formatter};
 use std::usize;
 
@@ -13,6 +16,7 @@ pub(crate) const SEG_CLASS_PTRS_COUNT: u32 = usize::BITS - 4;
 pub(crate) const CUSTOM_DATA_PTRS_COUNT: usize = 4;
 pub(crate) const DEFAULT_MAX_ALLOCATION_PAGES: u32 = 180; // 180 * 64k = ~10MB
 pub(crate) const DEFAULT_MAX_GROW_PAGES: u64 = 0;
+#[cfg(target_family = "wasm")]
 pub(crate) const LOW_ON_MEMORY_HOOK_NAME: &str = "on_low_stable_memory";
 
 pub(crate) type SegClassId = u32;
@@ -61,7 +65,16 @@ impl SSlice<StableMemoryAllocator> {
         Some(membox)
     }
 
-    pub(crate) fn allocate<T>(&mut self, mut size: usize) -> SSlice<T> {
+    pub(crate) fn allocate<T>(&mut self, size: usize) -> SSlice<T> {
+        match self.try_allocate(size) {
+            Ok(it) => it,
+            Err(_) => trap(format!("Not enough stable memory to allocate {} more bytes. Grown: {} bytes; Allocated: {} bytes; Free: {} bytes", size, stable::size_pages() * PAGE_SIZE_BYTES as u64, self.get_allocated_size(), self.get_free_size()).as_str())
+        }
+    }
+
+    /// Same as `allocate()`, but returns an error instead of trapping, when there is not enough
+    /// free stable memory. The allocator stays usable after an error.
+    pub(crate) fn try_allocate<T>(&mut self, mut size: usize) -> Result<SSlice<T>, OutOfMemory> {
         if size < CELL_MIN_SIZE {
             size = CELL_MIN_SIZE
         }
@@ -69,10 +82,7 @@ impl SSlice<StableMemoryAllocator> {
         // will be called only once during first ever allocate()
         self.handle_free_buffer();
 
-        let free_membox = match self.pop_allocated_membox(size) {
-            Ok(m) => m,
-            Err(_) => trap(format!("Not enough stable memory to allocate {} more bytes. Grown: {} bytes; Allocated: {} bytes; Free: {} bytes", size, stable::size_pages() * PAGE_SIZE_BYTES as u64, self.get_allocated_size(), self.get_free_size()).as_str())
-        };
+        let free_membox = self.pop_allocated_membox(size)?;
 
         self.handle_free_buffer();
 
@@ -84,7 +94,7 @@ impl SSlice<StableMemoryAllocator> {
         let buf = vec![0u8; it.get_size_bytes()];
         it._write_bytes(0, &buf);
 
-        it
+        Ok(it)
     }
 
     pub(crate) fn deallocate<T>(&mut self, mut membox: SSlice<T>) {
@@ -460,24 +470,7 @@ impl SSlice<StableMemoryAllocator> {
             return;
         }
 
-        print(
-            format!(
-                "Low on stable memory, triggering {}()...",
-                LOW_ON_MEMORY_HOOK_NAME
-            )
-            .as_str(),
-        );
-
-        spawn(async {
-            call_raw(id(), LOW_ON_MEMORY_HOOK_NAME, &EMPTY_ARGS, 0)
-                .await
-                .unwrap_or_else(|_| {
-                    panic!(
-                        "Unable to trigger {}(), failing silently...",
-                        LOW_ON_MEMORY_HOOK_NAME
-                    )
-                });
-        });
+        trigger_low_memory_hook();
 
         self.set_on_low_executed_flag(true);
     }
@@ -493,8 +486,35 @@ impl SSlice<StableMemoryAllocator> {
     }
 }
 
+#[cfg(target_family = "wasm")]
 const EMPTY_ARGS: [u8; 6] = [b'D', b'I', b'D', b'L', 0, 0];
 
+#[cfg(target_family = "wasm")]
+fn trigger_low_memory_hook() {
+    print(
+        format!(
+            "Low on stable memory, triggering {}()...",
+            LOW_ON_MEMORY_HOOK_NAME
+        )
+        .as_str(),
+    );
+
+    spawn(async {
+        call_raw(id(), LOW_ON_MEMORY_HOOK_NAME, &EMPTY_ARGS, 0)
+            .await
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Unable to trigger {}(), failing silently...",
+                    LOW_ON_MEMORY_HOOK_NAME
+                )
+            });
+    });
+}
+
+// there is no canister to call outside of the IC, so running out of memory in tests is quiet
+#[cfg(not(target_family = "wasm"))]
+fn trigger_low_memory_hook() {}
+
 fn get_seg_class_id(size: usize) -> SegClassId {
     let mut log = fast_log2(size);
 
@@ -0,0 +1,63 @@
+/// A type, which speedy encoding always takes exactly `SIZE` bytes.
+///
+/// Such types can be stored inline, without boxing each value into its own allocation
+/// (see [SInline](crate::collections::vec::SInline)). To implement it for your own type, make sure
+/// every field of it is `FixedSize` too (no strings, vectors, options or enums with data):
+/// ```ignore
+/// #[derive(Readable, Writable)]
+/// struct Balance {
+///     qty: u64,
+///     timestamp: u64,
+/// }
+///
+/// impl FixedSize for Balance {
+///     const SIZE: usize = u64::SIZE + u64::SIZE;
+/// }
+/// ```
+pub trait FixedSize {
+    const SIZE: usize;
+}
+
+macro_rules! impl_fixed_size {
+    ($($ty:ty = $size:expr),*) => {
+        $(
+            impl FixedSize for $ty {
+                const SIZE: usize = $size;
+            }
+        )*
+    };
+}
+
+impl_fixed_size!(
+    u8 = 1,
+    u16 = 2,
+    u32 = 4,
+    u64 = 8,
+    u128 = 16,
+    i8 = 1,
+    i16 = 2,
+    i32 = 4,
+    i64 = 8,
+    i128 = 16,
+    f32 = 4,
+    f64 = 8,
+    bool = 1,
+    char = 4
+);
+
+impl<T: FixedSize, const N: usize> FixedSize for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+}
+
+macro_rules! impl_fixed_size_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: FixedSize),+> FixedSize for ($($name,)+) {
+            const SIZE: usize = 0 $(+ $name::SIZE)+;
+        }
+    };
+}
+
+impl_fixed_size_for_tuple!(A);
+impl_fixed_size_for_tuple!(A, B);
+impl_fixed_size_for_tuple!(A, B, C);
+impl_fixed_size_for_tuple!(A, B, C, D);
@@ -1,2 +1,4 @@
+pub mod fixed_size;
+pub mod ord_key;
 pub mod s_slice;
 pub mod s_unsafe_cell;
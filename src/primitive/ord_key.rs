@@ -0,0 +1,378 @@
+use crate::utils::ic_types::SPrincipal;
+use candid::Principal;
+
+/// A binary encoding of keys, which preserves their order - encodings of two keys compare byte by
+/// byte (like `memcmp`) the same way the keys themselves do. Maps can then compare encoded keys
+/// right inside their pages, without decoding them
+/// (see [MemcmpOrder](crate::utils::key_order::MemcmpOrder)).
+///
+/// No encoding is a prefix of another one, so a composite key is encoded by simply concatenating
+/// encodings of its parts - such keys are sorted by the first part, then by the second one, etc:
+/// ```ignore
+/// struct OrderKey {
+///     user: SPrincipal,
+///     id: u64,
+/// }
+///
+/// impl OrdKey for OrderKey {
+///     fn write_ord(&self, buf: &mut Vec<u8>) {
+///         self.user.write_ord(buf);
+///         self.id.write_ord(buf);
+///     }
+///
+///     fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+///         let user = SPrincipal::read_ord(buf)?;
+///         let id = u64::read_ord(buf)?;
+///
+///         Some(Self { user, id })
+///     }
+/// }
+/// ```
+pub trait OrdKey: Sized {
+    fn write_ord(&self, buf: &mut Vec<u8>);
+
+    /// Reads the key from the beginning of the buffer, moving the buffer past it. Returns `None`,
+    /// if the buffer doesn't start with a valid encoding.
+    fn read_ord(buf: &mut &[u8]) -> Option<Self>;
+
+    fn to_ord_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_ord(&mut buf);
+
+        buf
+    }
+
+    /// Returns `None`, if the buffer is not an encoding of a single key
+    fn from_ord_bytes(mut buf: &[u8]) -> Option<Self> {
+        let key = Self::read_ord(&mut buf)?;
+
+        if buf.is_empty() {
+            Some(key)
+        } else {
+            None
+        }
+    }
+}
+
+fn take<'b>(buf: &mut &'b [u8], len: usize) -> Option<&'b [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+
+    Some(head)
+}
+
+// unsigned integers are big-endian, so more significant bytes are compared first
+macro_rules! impl_ord_key_for_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl OrdKey for $ty {
+                fn write_ord(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+                    let bytes = take(buf, std::mem::size_of::<Self>())?;
+
+                    Some(Self::from_be_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_ord_key_for_unsigned!(u8, u16, u32, u64, u128);
+
+// signed integers have their sign bit flipped, so negative numbers go before positive ones
+macro_rules! impl_ord_key_for_signed {
+    ($($ty:ty = $unsigned:ty),*) => {
+        $(
+            impl OrdKey for $ty {
+                fn write_ord(&self, buf: &mut Vec<u8>) {
+                    ((*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1))).write_ord(buf);
+                }
+
+                fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+                    Some((<$unsigned>::read_ord(buf)? ^ (1 << (<$unsigned>::BITS - 1))) as Self)
+                }
+            }
+        )*
+    };
+}
+
+impl_ord_key_for_signed!(i8 = u8, i16 = u16, i32 = u32, i64 = u64, i128 = u128);
+
+// always 64-bit, so keys encoded in tests match the ones encoded in canisters
+impl OrdKey for usize {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        (*self as u64).write_ord(buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        Some(u64::read_ord(buf)? as Self)
+    }
+}
+
+impl OrdKey for isize {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        (*self as i64).write_ord(buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        Some(i64::read_ord(buf)? as Self)
+    }
+}
+
+impl OrdKey for bool {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        (*self as u8).write_ord(buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        match u8::read_ord(buf)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl OrdKey for char {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        (*self as u32).write_ord(buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        char::from_u32(u32::read_ord(buf)?)
+    }
+}
+
+impl<const N: usize> OrdKey for [u8; N] {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        Some(take(buf, N)?.try_into().unwrap())
+    }
+}
+
+/// Byte strings of any length are terminated with `0x00 0x00`, and each zero byte inside of them
+/// is escaped as `0x00 0xFF`. This way a shorter string goes before a longer one, which starts
+/// with it, and the terminator can't be confused with the content.
+fn write_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for b in bytes {
+        buf.push(*b);
+
+        if *b == 0 {
+            buf.push(0xFF);
+        }
+    }
+
+    buf.extend_from_slice(&[0, 0]);
+}
+
+fn read_bytes(buf: &mut &[u8]) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+
+    loop {
+        match take(buf, 1)?[0] {
+            0 => match take(buf, 1)?[0] {
+                0 => return Some(res),
+                0xFF => res.push(0),
+                _ => return None,
+            },
+            b => res.push(b),
+        }
+    }
+}
+
+/// Encodes the beginning of a byte string or a `String` key (without the terminator), so it can be
+/// used as a prefix of encoded keys (see [SBTreeMap::prefix](crate::collections::btree_map::SBTreeMap::prefix))
+pub fn ord_prefix(prefix: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_bytes(prefix, &mut buf);
+    buf.truncate(buf.len() - 2);
+
+    buf
+}
+
+impl OrdKey for Vec<u8> {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        write_bytes(self, buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        read_bytes(buf)
+    }
+}
+
+impl OrdKey for String {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        write_bytes(self.as_bytes(), buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(read_bytes(buf)?).ok()
+    }
+}
+
+// principals are ordered by their length first, so the length goes first as well
+impl OrdKey for Principal {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        (self.as_slice().len() as u8).write_ord(buf);
+        buf.extend_from_slice(self.as_slice());
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        let len = u8::read_ord(buf)? as usize;
+
+        Principal::try_from(take(buf, len)?).ok()
+    }
+}
+
+impl OrdKey for SPrincipal {
+    fn write_ord(&self, buf: &mut Vec<u8>) {
+        self.0.write_ord(buf);
+    }
+
+    fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+        Some(SPrincipal(Principal::read_ord(buf)?))
+    }
+}
+
+macro_rules! impl_ord_key_for_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: OrdKey),+> OrdKey for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn write_ord(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.write_ord(buf);)+
+            }
+
+            fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+                Some(($($name::read_ord(buf)?,)+))
+            }
+        }
+    };
+}
+
+impl_ord_key_for_tuple!(A);
+impl_ord_key_for_tuple!(A, B);
+impl_ord_key_for_tuple!(A, B, C);
+impl_ord_key_for_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use crate::primitive::ord_key::OrdKey;
+    use crate::utils::ic_types::SPrincipal;
+    use crate::utils::key_order::{KeyOrder, MemcmpOrder};
+    use candid::Principal;
+    use std::fmt::Debug;
+
+    fn check_order<T: OrdKey + Ord + Debug>(mut keys: Vec<T>) {
+        keys.sort();
+
+        for pair in keys.windows(2) {
+            assert!(
+                pair[0].to_ord_bytes() < pair[1].to_ord_bytes(),
+                "{:?} should go before {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+
+        for key in keys {
+            assert_eq!(T::from_ord_bytes(&key.to_ord_bytes()), Some(key));
+        }
+    }
+
+    #[test]
+    fn ord_keys_work_fine() {
+        check_order(vec![0u64, 1, 255, 256, u64::MAX - 1, u64::MAX]);
+        check_order(vec![i32::MIN, -256, -1, 0, 1, 255, i32::MAX]);
+        check_order(vec![i128::MIN, -1, 0, i128::MAX]);
+        check_order(vec![-1isize, 0, 1]);
+        check_order(vec![false, true]);
+        check_order(vec!['a', 'b', 'я']);
+        check_order(vec![[0u8, 1], [1, 0], [1, 1]]);
+
+        check_order(
+            [
+                "", "a", "a\0", "a\0\0", "a\0b", "aa", "ab", "b", "user/1", "user/10", "user/2",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        );
+        check_order(vec![
+            vec![],
+            vec![0u8],
+            vec![0, 0],
+            vec![0, 255],
+            vec![1],
+            vec![255, 0],
+        ]);
+
+        check_order(vec![
+            SPrincipal(Principal::management_canister()),
+            SPrincipal(Principal::anonymous()),
+            SPrincipal(Principal::from_slice(&[0, 0, 1])),
+            SPrincipal(Principal::from_slice(&[0, 1])),
+        ]);
+
+        // composite keys are sorted part by part
+        check_order(vec![
+            (String::from("a"), 10u64),
+            (String::from("a"), 2),
+            (String::from("ab"), 1),
+            (String::from("b"), 0),
+        ]);
+        check_order(vec![
+            (1u8, -1i64, true),
+            (1, -1, false),
+            (0, 5, true),
+            (1, 0, false),
+        ]);
+    }
+
+    #[test]
+    fn malformed_ord_keys_are_rejected() {
+        assert_eq!(u64::from_ord_bytes(&[0, 1, 2]), None);
+        assert_eq!(u8::from_ord_bytes(&[0, 1]), None);
+        assert_eq!(bool::from_ord_bytes(&[2]), None);
+        assert_eq!(char::from_ord_bytes(&0xD800u32.to_ord_bytes()), None);
+
+        assert_eq!(String::from_ord_bytes(b"abc"), None);
+        assert_eq!(String::from_ord_bytes(b"a\0\x01\0\0"), None);
+        assert_eq!(String::from_ord_bytes(b"\xFF\0\0"), None);
+        assert_eq!(
+            Vec::<u8>::from_ord_bytes(b"a\0\xFF\0\0"),
+            Some(vec![b'a', 0])
+        );
+
+        assert_eq!(SPrincipal::from_ord_bytes(&[30]), None);
+        assert_eq!(<(String, u64)>::from_ord_bytes(b"a\0\0"), None);
+
+        // a key, which is decoded from more than one encoding
+        #[derive(Debug, PartialEq)]
+        struct Flag(bool);
+
+        impl OrdKey for Flag {
+            fn write_ord(&self, buf: &mut Vec<u8>) {
+                self.0.write_ord(buf);
+            }
+
+            fn read_ord(buf: &mut &[u8]) -> Option<Self> {
+                Some(Flag(u8::read_ord(buf)? != 0))
+            }
+        }
+
+        assert_eq!(Flag::from_ord_bytes(&[2]), Some(Flag(true)));
+        assert_eq!(MemcmpOrder::try_decode(&[2]), None::<Flag>);
+        assert_eq!(MemcmpOrder::try_decode(&[1]), Some(Flag(true)));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::primitive::s_slice::Side;
-use crate::{allocate, deallocate, reallocate, SSlice};
+use crate::{allocate, deallocate, reallocate, try_allocate, OutOfMemory, SSlice};
 use speedy::{LittleEndian, Readable, Writable};
 use std::cell::RefCell;
 use std::cmp::Ordering;
@@ -16,6 +16,13 @@ pub struct SUnsafeCell<T> {
 impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SUnsafeCell<T> {
     pub fn new(it: &T) -> Self {
         let buf = it.write_to_vec().expect("Unable to encode");
+
+        Self::new_encoded(buf)
+    }
+
+    /// Creates a cell from bytes, which decode into `T` - useful, when there is no owned `T` to
+    /// encode (e.g. when `T` is a tuple, but there are only references to its elements).
+    pub(crate) fn new_encoded(buf: Vec<u8>) -> Self {
         let slice = allocate(buf.len());
 
         slice._write_bytes(0, &buf);
@@ -26,6 +33,25 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SUnsafeCell<T>
         }
     }
 
+    /// Same as `new()`, but returns an error instead of trapping, when there is not enough stable
+    /// memory
+    pub fn try_new(it: &T) -> Result<Self, OutOfMemory> {
+        let buf = it.write_to_vec().expect("Unable to encode");
+
+        Self::try_new_encoded(buf)
+    }
+
+    pub(crate) fn try_new_encoded(buf: Vec<u8>) -> Result<Self, OutOfMemory> {
+        let slice = try_allocate(buf.len())?;
+
+        slice._write_bytes(0, &buf);
+
+        Ok(Self {
+            slice,
+            buf: RefCell::new(Some(buf)),
+        })
+    }
+
     pub fn get_cloned(&self) -> T {
         {
             if let Some(buf) = &*self.buf.borrow() {
@@ -36,10 +62,13 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SUnsafeCell<T>
         let mut buf = vec![0u8; self._allocated_size()];
         self.slice._read_bytes(0, &mut buf);
 
-        let res = T::read_from_buffer_copying_data(&buf).expect("Unable to decode");
+        // the cell can be bigger than the value, so only the bytes of the value are cached -
+        // otherwise set_if_changed() would never find them equal to a new encoding
+        let (res, len) = T::read_with_length_from_buffer_copying_data(&buf);
+        buf.truncate(len);
         *self.buf.borrow_mut() = Some(buf);
 
-        res
+        res.expect("Unable to decode")
     }
 
     /// # Safety
@@ -48,6 +77,38 @@ impl<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>> SUnsafeCell<T>
     /// Use the return bool value to determine if the location is changed (true = you need to update).
     pub unsafe fn set(&mut self, it: &T) -> bool {
         let buf = it.write_to_vec().expect("Unable to encode");
+
+        self.set_encoded(buf)
+    }
+
+    /// Same as `set()`, but doesn't touch stable memory at all, if the encoded value is the same
+    /// as the one that is already stored in this cell.
+    ///
+    /// # Safety
+    /// Same as for `set()`.
+    pub unsafe fn set_if_changed(&mut self, it: &T) -> bool {
+        let buf = it.write_to_vec().expect("Unable to encode");
+
+        {
+            if self.buf.borrow().is_none() {
+                self.get_cloned();
+            }
+
+            if let Some(prev_buf) = &*self.buf.borrow() {
+                if *prev_buf == buf {
+                    return false;
+                }
+            }
+        }
+
+        self.set_encoded(buf)
+    }
+
+    /// Same as `set()`, but takes bytes, which decode into `T`
+    ///
+    /// # Safety
+    /// Same as for `set()`.
+    pub(crate) unsafe fn set_encoded(&mut self, buf: Vec<u8>) -> bool {
         let mut res = false;
 
         if self._allocated_size() < buf.len() {
@@ -196,4 +257,39 @@ mod tests {
 
         assert_eq!(obj, obj1);
     }
+
+    #[test]
+    fn set_if_changed_works_fine() {
+        stable::clear();
+        stable::grow(1).unwrap();
+        init_allocator(0);
+
+        let mut membox = SUnsafeCell::new(&vec![1u8, 2, 3]);
+        let ptr = unsafe { membox.as_ptr() };
+
+        unsafe {
+            assert!(!membox.set_if_changed(&vec![1u8, 2, 3]));
+            assert!(!membox.set_if_changed(&vec![1u8, 2]));
+        }
+
+        let membox = unsafe { SUnsafeCell::<Vec<u8>>::from_ptr(ptr) };
+        assert_eq!(membox.get_cloned(), vec![1u8, 2]);
+
+        // the cell is bigger than the value now, but an unchanged value is still not written -
+        // a change made behind the loaded cell's back survives
+        let mut membox = unsafe { SUnsafeCell::<Vec<u8>>::from_ptr(ptr) };
+        assert_eq!(membox.get_cloned(), vec![1u8, 2]);
+
+        membox.slice._write_bytes(4, &[3]);
+        unsafe { assert!(!membox.set_if_changed(&vec![1u8, 2])) };
+
+        let membox1 = unsafe { SUnsafeCell::<Vec<u8>>::from_ptr(ptr) };
+        assert_eq!(membox1.get_cloned(), vec![3u8, 2]);
+
+        let mut membox = unsafe { SUnsafeCell::<Vec<u8>>::from_ptr(ptr) };
+        unsafe { assert!(!membox.set_if_changed(&vec![1u8])) };
+
+        let membox = unsafe { SUnsafeCell::<Vec<u8>>::from_ptr(ptr) };
+        assert_eq!(membox.get_cloned(), vec![1u8]);
+    }
 }
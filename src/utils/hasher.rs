@@ -0,0 +1,233 @@
+use std::hash::Hasher;
+
+/// A hash function, which output is guaranteed to never change - neither across compiler
+/// releases, nor across platforms. Stable hash maps store keys in buckets chosen by their hashes,
+/// so the hash of a key should be the same after any canister upgrade.
+///
+/// `usize` and `isize` are always hashed as 64-bit integers, so hashes computed in tests (64-bit)
+/// match the ones computed in canisters (32-bit). Stable hash maps don't rely on `Hash`
+/// implementations of their keys at all - they hash the encoded keys instead.
+pub trait StableHasher: Hasher + Default {
+    /// Is stored along with each map, so the map can't be accidentally opened with another hasher.
+    /// Never reuse ids of existing hashers.
+    const ALGORITHM_ID: u8;
+}
+
+/// SipHash-1-3 with both keys set to zero, implemented here, so its output can never change.
+/// The default hasher for stable hash maps.
+pub struct SipHasher13 {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+impl SipHasher13 {
+    pub fn new_with_keys(k0: u64, k1: u64) -> Self {
+        Self {
+            v0: k0 ^ 0x736f6d6570736575,
+            v1: k1 ^ 0x646f72616e646f6d,
+            v2: k0 ^ 0x6c7967656e657261,
+            v3: k1 ^ 0x7465646279746573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    fn sip_round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13);
+        v[1] ^= v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16);
+        v[3] ^= v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21);
+        v[3] ^= v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17);
+        v[1] ^= v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        let mut v = [self.v0, self.v1, self.v2, self.v3];
+
+        v[3] ^= m;
+        Self::sip_round(&mut v);
+        v[0] ^= m;
+
+        [self.v0, self.v1, self.v2, self.v3] = v;
+    }
+}
+
+impl Default for SipHasher13 {
+    fn default() -> Self {
+        Self::new_with_keys(0, 0)
+    }
+}
+
+impl Hasher for SipHasher13 {
+    fn finish(&self) -> u64 {
+        let mut v = [self.v0, self.v1, self.v2, self.v3];
+        let b = ((self.length as u64 & 0xff) << 56) | self.tail;
+
+        v[3] ^= b;
+        Self::sip_round(&mut v);
+        v[0] ^= b;
+
+        v[2] ^= 0xff;
+        Self::sip_round(&mut v);
+        Self::sip_round(&mut v);
+        Self::sip_round(&mut v);
+
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.length += bytes.len();
+
+        for b in bytes {
+            self.tail |= (*b as u64) << (8 * self.ntail);
+            self.ntail += 1;
+
+            if self.ntail == 8 {
+                self.compress(self.tail);
+                self.tail = 0;
+                self.ntail = 0;
+            }
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+}
+
+impl StableHasher for SipHasher13 {
+    const ALGORITHM_ID: u8 = 1;
+}
+
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+/// A fixed variant of FxHash (the one rustc uses) - much faster than SipHash, but is not resistant
+/// to hash flooding, so only use it for keys users can't choose freely.
+#[derive(Default)]
+pub struct FxHasher {
+    hash: u64,
+}
+
+impl FxHasher {
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        while bytes.len() >= 8 {
+            self.add_to_hash(u64::from_le_bytes(bytes[..8].try_into().unwrap()));
+            bytes = &bytes[8..];
+        }
+
+        if bytes.len() >= 4 {
+            self.add_to_hash(u32::from_le_bytes(bytes[..4].try_into().unwrap()) as u64);
+            bytes = &bytes[4..];
+        }
+
+        for b in bytes {
+            self.add_to_hash(*b as u64);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add_to_hash(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.add_to_hash(i as u64);
+    }
+}
+
+impl StableHasher for FxHasher {
+    const ALGORITHM_ID: u8 = 2;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::hasher::{FxHasher, SipHasher13};
+    use std::hash::{Hash, Hasher};
+
+    fn hash<T: Hash, H: Hasher + Default>(it: &T) -> u64 {
+        let mut hasher = H::default();
+        it.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    fn hash_bytes<H: Hasher + Default>(bytes: &[u8]) -> u64 {
+        let mut hasher = H::default();
+        hasher.write(bytes);
+
+        hasher.finish()
+    }
+
+    #[test]
+    fn hashers_work_fine() {
+        // the reference values, which should never change
+        let long = (0..15u8).collect::<Vec<_>>();
+
+        assert_eq!(hash_bytes::<SipHasher13>(b""), 0xd1fb_a762_150c_532c);
+        assert_eq!(hash_bytes::<SipHasher13>(b"a"), 0x4074_48d2_b89b_1813);
+        assert_eq!(
+            hash_bytes::<SipHasher13>(b"The string"),
+            0x2e74_0b96_3aae_f42d
+        );
+        assert_eq!(hash_bytes::<SipHasher13>(&long), 0xf30e_b725_bb91_c9ea);
+        assert_eq!(
+            hash::<_, SipHasher13>(&0xdeadbeefu32),
+            0x3533_5b91_84fb_8253
+        );
+
+        assert_eq!(hash_bytes::<FxHasher>(b"a"), 0xe045_6665_d3e6_0275);
+        assert_eq!(hash_bytes::<FxHasher>(b"The string"), 0xbb83_7aea_1f81_cf86);
+        assert_eq!(hash_bytes::<FxHasher>(&long), 0x5f39_4810_ae12_7020);
+        assert_eq!(hash::<_, FxHasher>(&1u64), 0x517c_c1b7_2722_0a95);
+
+        assert_eq!(
+            hash::<_, SipHasher13>(&1usize),
+            hash::<_, SipHasher13>(&1u64)
+        );
+        assert_eq!(hash::<_, FxHasher>(&1usize), hash::<_, FxHasher>(&1u64));
+        assert_ne!(hash::<_, FxHasher>(&"a"), hash::<_, FxHasher>(&"b"));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::primitive::ord_key::OrdKey;
+use speedy::{LittleEndian, Readable, Writable};
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+/// Defines, how a sorted collection encodes its keys and in which order it keeps them.
+///
+/// Keys are stored in their encoded form, so a lookup compares the encoded keys of the
+/// collection with the key being searched for. This key is passed in both forms - as is and
+/// encoded with the same order - so an implementation can use whichever is cheaper.
+pub trait KeyOrder<K> {
+    /// Is stored along with each collection, so the collection can't be accidentally opened with
+    /// another order. Never reuse ids of existing orders.
+    const ORDER_ID: u8;
+
+    fn encode(key: &K) -> Vec<u8>;
+
+    fn decode(buf: &[u8]) -> K;
+
+    /// Decodes a key, which doesn't come from the collection (e.g. a cursor token from a client).
+    /// Returns `None`, if the buffer is not an encoding of a key.
+    fn try_decode(buf: &[u8]) -> Option<K>;
+
+    /// Compares a stored key with the given one
+    fn cmp(stored: &[u8], key: &K, encoded_key: &[u8]) -> Ordering;
+
+    /// Compares two keys, which are not stored yet
+    fn cmp_keys(a: &K, b: &K) -> Ordering {
+        Self::cmp(&Self::encode(a), b, &Self::encode(b))
+    }
+}
+
+/// Keys are encoded with speedy and compared by their `Ord` implementation, which means, that
+/// each compared key is decoded first. The default order.
+pub struct OrdOrder;
+
+impl<'a, K: Ord + Readable<'a, LittleEndian> + Writable<LittleEndian>> KeyOrder<K> for OrdOrder {
+    const ORDER_ID: u8 = 1;
+
+    fn encode(key: &K) -> Vec<u8> {
+        key.write_to_vec().expect("Unable to encode")
+    }
+
+    fn decode(buf: &[u8]) -> K {
+        K::read_from_buffer_copying_data(buf).expect("Unable to decode")
+    }
+
+    fn try_decode(buf: &[u8]) -> Option<K> {
+        K::read_from_buffer_copying_data(buf)
+            .ok()
+            .filter(|it| <Self as KeyOrder<K>>::encode(it) == buf)
+    }
+
+    fn cmp(stored: &[u8], key: &K, _encoded_key: &[u8]) -> Ordering {
+        <Self as KeyOrder<K>>::decode(stored).cmp(key)
+    }
+
+    fn cmp_keys(a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Keys are encoded with [OrdKey] and compared as byte strings, without decoding them.
+/// The fastest order, when keys implement [OrdKey].
+pub struct MemcmpOrder;
+
+impl<K: OrdKey> KeyOrder<K> for MemcmpOrder {
+    const ORDER_ID: u8 = 2;
+
+    fn encode(key: &K) -> Vec<u8> {
+        key.to_ord_bytes()
+    }
+
+    fn decode(buf: &[u8]) -> K {
+        K::from_ord_bytes(buf).expect("Unable to decode")
+    }
+
+    fn try_decode(buf: &[u8]) -> Option<K> {
+        // keys are compared as bytes, so a key has to be decoded from its only encoding
+        K::from_ord_bytes(buf).filter(|it| it.to_ord_bytes() == buf)
+    }
+
+    fn cmp(stored: &[u8], _key: &K, encoded_key: &[u8]) -> Ordering {
+        stored.cmp(encoded_key)
+    }
+}
+
+/// An order, which can't be expressed with [OrdKey] or `Ord` of the key (e.g. by a projection of
+/// the key or in reverse). Use it with [ByComparator]:
+/// ```ignore
+/// struct ByScoreDesc;
+///
+/// impl Comparator<Player> for ByScoreDesc {
+///     fn cmp(a: &Player, b: &Player) -> Ordering {
+///         b.score.cmp(&a.score).then_with(|| a.id.cmp(&b.id))
+///     }
+/// }
+///
+/// let leaderboard = SBTreeSet::<Player, ByComparator<ByScoreDesc>>::new_with_order();
+/// ```
+pub trait Comparator<K> {
+    fn cmp(a: &K, b: &K) -> Ordering;
+}
+
+/// Keys are encoded with speedy and compared with `C`, after being decoded.
+///
+/// All comparators share the same [KeyOrder::ORDER_ID], so make sure a collection is always
+/// opened with the same comparator.
+pub struct ByComparator<C>(PhantomData<C>);
+
+impl<'a, K: Readable<'a, LittleEndian> + Writable<LittleEndian>, C: Comparator<K>> KeyOrder<K>
+    for ByComparator<C>
+{
+    const ORDER_ID: u8 = 3;
+
+    fn encode(key: &K) -> Vec<u8> {
+        key.write_to_vec().expect("Unable to encode")
+    }
+
+    fn decode(buf: &[u8]) -> K {
+        K::read_from_buffer_copying_data(buf).expect("Unable to decode")
+    }
+
+    fn try_decode(buf: &[u8]) -> Option<K> {
+        K::read_from_buffer_copying_data(buf)
+            .ok()
+            .filter(|it| <Self as KeyOrder<K>>::encode(it) == buf)
+    }
+
+    fn cmp(stored: &[u8], key: &K, _encoded_key: &[u8]) -> Ordering {
+        C::cmp(&<Self as KeyOrder<K>>::decode(stored), key)
+    }
+
+    fn cmp_keys(a: &K, b: &K) -> Ordering {
+        C::cmp(a, b)
+    }
+}
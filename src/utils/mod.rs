@@ -1,10 +1,14 @@
 use speedy::{Readable, Writable};
 
+pub mod hasher;
 pub mod ic_types;
+pub mod key_order;
 pub mod math;
 pub mod mem_context;
 pub mod phantom_data;
+pub mod type_tag;
 pub mod vars;
+pub mod versioned;
 
 pub struct MemMetrics {
     pub available: u64,
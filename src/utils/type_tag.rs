@@ -0,0 +1,211 @@
+use crate::collections::binary_heap::SBinaryHeap;
+use crate::collections::btree_map::{LegacySBTreeMap, SBTreeMap};
+use crate::collections::btree_set::SBTreeSet;
+use crate::collections::hash_map::{LegacySHashMap, SHashMap};
+use crate::collections::hash_set::SHashSet;
+use crate::collections::vec::{SBoxed, SInline, SVec};
+use crate::primitive::s_unsafe_cell::SUnsafeCell;
+use crate::utils::hasher::StableHasher;
+use crate::utils::ic_types::SPrincipal;
+use crate::utils::key_order::KeyOrder;
+use crate::utils::versioned::SVersioned;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// A stable identifier of a type. It is stored next to each stable variable and is checked on
+/// every read, so a variable can't be silently decoded as some other type after an upgrade.
+///
+/// Tags are explicit constants - unlike `std::any::type_name()`, they don't depend on the
+/// compiler and only change when you change them. Implement this trait for your own types with
+/// [type_tag]:
+/// ```ignore
+/// impl TypeTag for HistoryEntry {
+///     const TYPE_TAG: u64 = type_tag("HistoryEntry");
+/// }
+/// ```
+/// Generic types mix tags of their arguments into their own tag with [type_tag_with].
+pub trait TypeTag {
+    /// Never change the tag of a type, which values are already stored, unless the type itself
+    /// is changed in a way its old values can't be decoded anymore.
+    const TYPE_TAG: u64;
+}
+
+/// Returns the tag for the given name (its FNV-1a hash)
+pub const fn type_tag(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u64).wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+
+    hash
+}
+
+/// Mixes the tag of a type argument (or any other number, which is a part of the type, like an
+/// array length or a hasher id) into the tag of a generic type
+pub const fn type_tag_with(tag: u64, arg: u64) -> u64 {
+    let bytes = arg.to_le_bytes();
+    let mut hash = tag;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u64).wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+
+    hash
+}
+
+macro_rules! impl_type_tag {
+    ($($ty:ty),*) => {
+        $(
+            impl TypeTag for $ty {
+                const TYPE_TAG: u64 = type_tag(stringify!($ty));
+            }
+        )*
+    };
+}
+
+impl_type_tag!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    String,
+    SPrincipal
+);
+
+impl<T: TypeTag> TypeTag for Vec<T> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("Vec"), T::TYPE_TAG);
+}
+
+impl<T: TypeTag> TypeTag for Option<T> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("Option"), T::TYPE_TAG);
+}
+
+impl<T: TypeTag, const N: usize> TypeTag for [T; N] {
+    const TYPE_TAG: u64 = type_tag_with(type_tag_with(type_tag("Array"), T::TYPE_TAG), N as u64);
+}
+
+impl<A: TypeTag, B: TypeTag> TypeTag for (A, B) {
+    const TYPE_TAG: u64 =
+        type_tag_with(type_tag_with(type_tag("Tuple2"), A::TYPE_TAG), B::TYPE_TAG);
+}
+
+impl<A: TypeTag, B: TypeTag, C: TypeTag> TypeTag for (A, B, C) {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(type_tag_with(type_tag("Tuple3"), A::TYPE_TAG), B::TYPE_TAG),
+        C::TYPE_TAG,
+    );
+}
+
+impl<T: TypeTag> TypeTag for SUnsafeCell<T> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("SUnsafeCell"), T::TYPE_TAG);
+}
+
+impl<T: TypeTag> TypeTag for SVersioned<T> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("SVersioned"), T::TYPE_TAG);
+}
+
+impl<T: TypeTag> TypeTag for SVec<T, SBoxed> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("SVec"), T::TYPE_TAG);
+}
+
+impl<T: TypeTag> TypeTag for SVec<T, SInline> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("SVec<SInline>"), T::TYPE_TAG);
+}
+
+impl<T: TypeTag> TypeTag for SBinaryHeap<T> {
+    const TYPE_TAG: u64 = type_tag_with(type_tag("SBinaryHeap"), T::TYPE_TAG);
+}
+
+impl<K: TypeTag, V: TypeTag, H: StableHasher> TypeTag for SHashMap<K, V, H> {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(
+            type_tag_with(type_tag("SHashMap"), K::TYPE_TAG),
+            V::TYPE_TAG,
+        ),
+        H::ALGORITHM_ID as u64,
+    );
+}
+
+impl<K: TypeTag, V: TypeTag> TypeTag for LegacySHashMap<K, V> {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(type_tag("LegacySHashMap"), K::TYPE_TAG),
+        V::TYPE_TAG,
+    );
+}
+
+impl<T: TypeTag, H: StableHasher> TypeTag for SHashSet<T, H> {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(type_tag("SHashSet"), T::TYPE_TAG),
+        H::ALGORITHM_ID as u64,
+    );
+}
+
+impl<K: TypeTag, V: TypeTag, O: KeyOrder<K>> TypeTag for SBTreeMap<K, V, O> {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(
+            type_tag_with(type_tag("SBTreeMap"), K::TYPE_TAG),
+            V::TYPE_TAG,
+        ),
+        O::ORDER_ID as u64,
+    );
+}
+
+impl<K: TypeTag, V: TypeTag> TypeTag for LegacySBTreeMap<K, V> {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(type_tag("LegacySBTreeMap"), K::TYPE_TAG),
+        V::TYPE_TAG,
+    );
+}
+
+impl<T: TypeTag, O: KeyOrder<T>> TypeTag for SBTreeSet<T, O> {
+    const TYPE_TAG: u64 = type_tag_with(
+        type_tag_with(type_tag("SBTreeSet"), T::TYPE_TAG),
+        O::ORDER_ID as u64,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::hash_map::SHashMap;
+    use crate::collections::vec::{SInline, SVec};
+    use crate::utils::hasher::FxHasher;
+    use crate::utils::type_tag::{type_tag, TypeTag};
+
+    #[test]
+    fn tags_are_fixed() {
+        // these values are stored in stable memory - they should never change
+        assert_eq!(type_tag(""), 0xcbf29ce484222325);
+        assert_eq!(type_tag("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(u64::TYPE_TAG, type_tag("u64"));
+
+        assert_ne!(u64::TYPE_TAG, u32::TYPE_TAG);
+        assert_ne!(Vec::<u64>::TYPE_TAG, Vec::<u32>::TYPE_TAG);
+        assert_ne!(Vec::<u64>::TYPE_TAG, Option::<u64>::TYPE_TAG);
+        assert_ne!(<(u64, u32)>::TYPE_TAG, <(u32, u64)>::TYPE_TAG);
+        assert_ne!(SVec::<u64>::TYPE_TAG, SVec::<u64, SInline>::TYPE_TAG);
+        assert_ne!(
+            SHashMap::<u64, u64>::TYPE_TAG,
+            SHashMap::<u64, u64, FxHasher>::TYPE_TAG
+        );
+    }
+}
@@ -1,47 +1,582 @@
 use crate::collections::hash_map::SHashMap;
+use crate::collections::vec::SVec;
+use crate::mem::allocator::EMPTY_PTR;
+use crate::primitive::s_slice::PTR_SIZE;
 use crate::primitive::s_unsafe_cell::SUnsafeCell;
-use crate::{_get_custom_data_ptr, _set_custom_data_ptr};
+use crate::utils::type_tag::TypeTag;
+use crate::{_get_custom_data_ptr, _set_custom_data_ptr, deallocate, SSlice};
 use ic_cdk::trap;
 use speedy::{LittleEndian, Readable, Writable};
+use std::any::type_name;
+use std::cell::RefCell;
+use std::marker::PhantomData;
 
-static mut VARS: Option<SHashMap<String, u64>> = None;
+/// The registry cell starts with these bytes. Registries written before it got a header start
+/// with the number of vars instead (as `u64`), which never gets that big.
+const VARS_MAGIC: [u8; 4] = [b'S', b'V', b'A', b'R'];
+const VARS_LAYOUT_VERSION: u8 = 1;
 
-pub fn init_vars() {
-    unsafe { VARS = Some(SHashMap::new_with_capacity(101)) }
+/// Vars, which came from a legacy registry, have no type tag. Such a var can be read as any type
+/// and gets its tag on the first write.
+const LEGACY_TYPE_TAG: u64 = 0;
+
+type SVarRegistry = SHashMap<String, SVarEntry>;
+
+thread_local! {
+    static VARS: RefCell<Option<(SVarRegistryHeader, SUnsafeCell<SVarRegistryHeader>)>> = const { RefCell::new(None) };
 }
 
-pub fn store_vars() {
-    let vars = unsafe { VARS.take() };
-    let vars_box = SUnsafeCell::new(&vars.expect("Stable vars are not initialized yet"));
+#[derive(Readable, Writable)]
+struct SVarRegistryHeader {
+    magic: [u8; 4],
+    version: u8,
+    vars: SVarRegistry,
+}
 
-    _set_custom_data_ptr(0, unsafe { vars_box.as_ptr() });
+#[derive(Readable, Writable)]
+struct SVarEntry {
+    ptr: u64,
+    type_tag: u64,
 }
 
-pub fn reinit_vars() {
-    let vars_box_ptr = _get_custom_data_ptr(0);
-    let vars_box = unsafe { SUnsafeCell::from_ptr(vars_box_ptr) };
+struct LegacyVarTable;
 
-    unsafe { VARS = Some(vars_box.get_cloned()) }
+/// The registry, as it was stored in `store_vars()` by versions 0.2.x - an `SHashMap<String, u64>`
+/// with a fixed table of buckets, where each bucket is a boxed `SVec` of boxed entries
+#[derive(Readable, Writable)]
+struct LegacyVarRegistry {
+    len: u64,
+    table_capacity: u32,
+    table: Option<SSlice<LegacyVarTable>>,
 }
 
-pub fn set_var<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>>(name: &str, value: &T) {
-    let val_box = SUnsafeCell::new(value);
+#[derive(Readable, Writable)]
+struct LegacyVarEntry {
+    name: String,
+    ptr: SUnsafeCell<u64>,
+}
 
-    unsafe {
-        VARS.as_mut()
-            .expect("Stable vars are not initialized yet")
-            .insert(String::from(name), &val_box.as_ptr())
+/// A typed handle to a stable variable. Declare it once, as a constant, and use it everywhere:
+/// ```ignore
+/// const BALANCES: SVar<SHashMap<SPrincipal, u64>> = SVar::new("balances");
+///
+/// BALANCES.set(&SHashMap::new());
+/// let balances = BALANCES.get();
+/// ```
+/// Any number of variables can share the same type - they are only distinguished by their names.
+pub struct SVar<T> {
+    name: &'static str,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SVar<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<'a, T: TypeTag + Readable<'a, LittleEndian> + Writable<LittleEndian>> SVar<T> {
+    pub fn get(&self) -> T {
+        get_var::<T>(self.name)
+    }
+
+    pub fn set(&self, value: &T) {
+        set_var::<T>(self.name, value)
+    }
+
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        with_var::<T, R, F>(self.name, f)
+    }
+
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        with_var_mut::<T, R, F>(self.name, f)
+    }
+}
+
+/// Creates an empty var registry. The registry header is stored in stable memory right away and
+/// is rewritten on each change, so there is nothing to save in `pre_upgrade`.
+pub fn init_vars() {
+    let header = SVarRegistryHeader {
+        magic: VARS_MAGIC,
+        version: VARS_LAYOUT_VERSION,
+        vars: SVarRegistry::new_with_capacity(101),
     };
+    let header_box = SUnsafeCell::new(&header);
+
+    _set_custom_data_ptr(0, unsafe { header_box.as_ptr() });
+
+    VARS.with(|it| *it.borrow_mut() = Some((header, header_box)))
+}
+
+/// Does nothing - the var registry is always up to date in stable memory.
+/// Kept for backward compatibility.
+pub fn store_vars() {}
+
+/// Loads the var registry after an upgrade.
+///
+/// A registry saved by `store_vars()` of versions 0.2.x is converted into the current layout once,
+/// here. Its vars have no type tags yet, so they can be read as any type until they are written.
+pub fn reinit_vars() {
+    let header_box_ptr = _get_custom_data_ptr(0);
+    let header_box = unsafe { SUnsafeCell::<SVarRegistryHeader>::from_ptr(header_box_ptr) };
+
+    let mut prefix = [0u8; VARS_MAGIC.len() + 1];
+    header_box.slice._read_bytes(0, &mut prefix);
+
+    if prefix[..VARS_MAGIC.len()] != VARS_MAGIC {
+        init_vars();
+        migrate_legacy_vars(header_box_ptr);
+
+        return;
+    }
+
+    let version = prefix[VARS_MAGIC.len()];
+    if version > VARS_LAYOUT_VERSION {
+        trap(
+            format!(
+                "Unknown stable var registry version {} (the latest known is {})",
+                version, VARS_LAYOUT_VERSION
+            )
+            .as_str(),
+        );
+    }
+
+    let header = header_box.get_cloned();
+
+    VARS.with(|it| *it.borrow_mut() = Some((header, header_box)))
+}
+
+/// Moves all the vars from the legacy registry into the current one and frees the legacy registry
+fn migrate_legacy_vars(legacy_box_ptr: u64) {
+    let legacy_box = unsafe { SUnsafeCell::<LegacyVarRegistry>::from_ptr(legacy_box_ptr) };
+    let legacy = legacy_box.get_cloned();
+
+    if let Some(table) = legacy.table {
+        for i in 0..legacy.table_capacity as usize {
+            let bucket_ptr = table._read_word(i * PTR_SIZE);
+            if bucket_ptr == 0 || bucket_ptr == EMPTY_PTR {
+                continue;
+            }
+
+            let bucket_box = unsafe { SUnsafeCell::<SVec<LegacyVarEntry>>::from_ptr(bucket_ptr) };
+            let bucket = bucket_box.get_cloned();
+
+            for legacy_entry in bucket.iter() {
+                let entry = SVarEntry {
+                    ptr: legacy_entry.ptr.get_cloned(),
+                    type_tag: LEGACY_TYPE_TAG,
+                };
+                legacy_entry.ptr.drop();
+
+                with_vars_mut(|vars| vars.insert(legacy_entry.name, &entry));
+            }
+
+            bucket.drop();
+            bucket_box.drop();
+        }
+
+        deallocate(table);
+    }
+
+    legacy_box.drop();
+}
+
+fn with_vars<R, F: FnOnce(&SVarRegistry) -> R>(f: F) -> R {
+    VARS.with(|it| {
+        let header = it.borrow();
+        let (header, _) = header
+            .as_ref()
+            .expect("Stable vars are not initialized yet");
+
+        f(&header.vars)
+    })
+}
+
+fn with_vars_mut<R, F: FnOnce(&mut SVarRegistry) -> R>(f: F) -> R {
+    VARS.with(|it| {
+        let mut header = it.borrow_mut();
+        let (header, header_box) = header
+            .as_mut()
+            .expect("Stable vars are not initialized yet");
+
+        let res = f(&mut header.vars);
+
+        unsafe {
+            let should_update = header_box.set(header);
+
+            if should_update {
+                _set_custom_data_ptr(0, header_box.as_ptr());
+            }
+        }
+
+        res
+    })
+}
+
+/// Sets a new value to the stable variable, creating it if it doesn't exist yet.
+///
+/// If the variable already exists, its value is written back into the same stable memory cell,
+/// which only gets reallocated when the new value doesn't fit into it anymore.
+pub fn set_var<'a, T: TypeTag + Readable<'a, LittleEndian> + Writable<LittleEndian>>(
+    name: &str,
+    value: &T,
+) {
+    let name = String::from(name);
+    let type_tag = T::TYPE_TAG;
+
+    match with_vars(|vars| vars.get_cloned(&name)) {
+        Some(mut entry) => {
+            let mut val_box = unsafe { SUnsafeCell::<T>::from_ptr(entry.ptr) };
+            let should_update = unsafe { val_box.set(value) };
+
+            if should_update || entry.type_tag != type_tag {
+                entry.ptr = unsafe { val_box.as_ptr() };
+                entry.type_tag = type_tag;
+
+                with_vars_mut(|vars| vars.insert(name, &entry));
+            }
+        }
+        None => {
+            let val_box = SUnsafeCell::new(value);
+            let entry = SVarEntry {
+                ptr: unsafe { val_box.as_ptr() },
+                type_tag,
+            };
+
+            with_vars_mut(|vars| vars.insert(name, &entry));
+        }
+    }
+}
+
+pub fn has_var(name: &str) -> bool {
+    with_vars(|vars| vars.contains_key(&String::from(name)))
+}
+
+/// Removes the stable variable and frees the stable memory cell its value was stored in.
+/// Returns `false` if there was no such variable.
+///
+/// Only the value itself is freed - if it is a stable collection, call its `drop()` method first.
+pub fn remove_var(name: &str) -> bool {
+    match with_vars_mut(|vars| vars.remove(&String::from(name))) {
+        Some(entry) => {
+            let val_box = unsafe { SUnsafeCell::<()>::from_ptr(entry.ptr) };
+            val_box.drop();
+
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns names of all stable variables, in no particular order.
+pub fn list_vars() -> Vec<String> {
+    with_vars(|vars| vars.keys().collect())
+}
+
+pub fn get_var<'a, T: TypeTag + Readable<'a, LittleEndian> + Writable<LittleEndian>>(
+    name: &str,
+) -> T {
+    let entry = get_var_entry::<T>(name);
+
+    unsafe { SUnsafeCell::from_ptr(entry.ptr) }.get_cloned()
 }
 
-pub fn get_var<'a, T: Readable<'a, LittleEndian> + Writable<LittleEndian>>(name: &str) -> T {
+/// Decodes the stable variable and passes it to the closure.
+pub fn with_var<
+    'a,
+    T: TypeTag + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+    R,
+    F: FnOnce(&T) -> R,
+>(
+    name: &str,
+    f: F,
+) -> R {
+    f(&get_var::<T>(name))
+}
+
+/// Decodes the stable variable and passes it to the closure, mutably. Once the closure returns,
+/// the variable is written back to the same stable memory cell - but only if it has changed.
+///
+/// This is the way to work with stable collections stored in stable vars - their headers
+/// (length, pointers to the data) change on mutation and have to be written back:
+/// ```ignore
+/// with_var_mut("ledger", |ledger: &mut SVec<u64>| ledger.push(&10));
+/// ```
+pub fn with_var_mut<
+    'a,
+    T: TypeTag + Readable<'a, LittleEndian> + Writable<LittleEndian>,
+    R,
+    F: FnOnce(&mut T) -> R,
+>(
+    name: &str,
+    f: F,
+) -> R {
+    let mut entry = get_var_entry::<T>(name);
+    let mut val_box = unsafe { SUnsafeCell::<T>::from_ptr(entry.ptr) };
+    let mut it = val_box.get_cloned();
+
+    let res = f(&mut it);
+
     unsafe {
-        SUnsafeCell::from_ptr(
-            VARS.as_ref()
-                .expect("Stable vars are not initialized yet")
-                .get_cloned(&String::from(name))
-                .unwrap_or_else(|| trap(format!("Invalid stable var name {}", name).as_str())),
-        )
-        .get_cloned()
+        let should_update = val_box.set_if_changed(&it);
+
+        if should_update || entry.type_tag != T::TYPE_TAG {
+            entry.ptr = val_box.as_ptr();
+            entry.type_tag = T::TYPE_TAG;
+
+            let name = String::from(name);
+            with_vars_mut(|vars| vars.insert(name, &entry));
+        }
+    }
+
+    res
+}
+
+fn get_var_entry<'a, T: TypeTag + Readable<'a, LittleEndian> + Writable<LittleEndian>>(
+    name: &str,
+) -> SVarEntry {
+    let entry = with_vars(|vars| vars.get_cloned(&String::from(name)))
+        .unwrap_or_else(|| trap(format!("Invalid stable var name {}", name).as_str()));
+
+    if entry.type_tag != T::TYPE_TAG && entry.type_tag != LEGACY_TYPE_TAG {
+        trap(
+            format!(
+                "Stable var {} is stored with a different type (expected {})",
+                name,
+                type_name::<T>()
+            )
+            .as_str(),
+        );
+    }
+
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::vec::SVec;
+    use crate::primitive::s_slice::PTR_SIZE;
+    use crate::primitive::s_unsafe_cell::SUnsafeCell;
+    use crate::utils::type_tag::TypeTag;
+    use crate::utils::vars::{
+        get_var, has_var, list_vars, remove_var, set_var, with_var, with_var_mut, with_vars,
+        LegacyVarEntry, LegacyVarRegistry, LegacyVarTable, SVar,
+    };
+    use crate::{
+        _set_custom_data_ptr, allocate, deinit_allocator, get_allocated_size, stable,
+        stable_memory_init, stable_memory_post_upgrade, stable_memory_pre_upgrade,
+    };
+
+    const FIRST: SVar<u64> = SVar::new("first");
+    const SECOND: SVar<u64> = SVar::new("second");
+    const NAME: SVar<String> = SVar::new("name");
+
+    #[test]
+    fn typed_vars_work_fine() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        FIRST.set(&10);
+        SECOND.set(&20);
+        NAME.set(&String::from("test"));
+
+        assert_eq!(FIRST.get(), 10);
+        assert_eq!(SECOND.get(), 20);
+        assert_eq!(NAME.get(), "test");
+        assert_eq!(get_var::<u64>("first"), 10);
+
+        stable_memory_pre_upgrade();
+        deinit_allocator();
+        stable_memory_post_upgrade(0);
+
+        assert_eq!(FIRST.get(), 10);
+        assert_eq!(SECOND.get(), 20);
+        assert_eq!(NAME.get(), "test");
+    }
+
+    #[test]
+    fn vars_survive_upgrade_without_pre_upgrade() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        for i in 0..200u64 {
+            set_var(format!("var {}", i).as_str(), &i);
+        }
+        remove_var("var 0");
+
+        deinit_allocator();
+        stable_memory_post_upgrade(0);
+
+        assert!(!has_var("var 0"));
+        assert_eq!(list_vars().len(), 199);
+
+        for i in 1..200u64 {
+            assert_eq!(get_var::<u64>(format!("var {}", i).as_str()), i);
+        }
+    }
+
+    #[test]
+    fn closure_access_works_fine() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        const LEDGER: SVar<SVec<u64>> = SVar::new("ledger");
+        LEDGER.set(&SVec::new());
+
+        for i in 0..100u64 {
+            LEDGER.with_mut(|ledger| ledger.push(&i));
+        }
+
+        assert_eq!(LEDGER.with(|ledger| ledger.len()), 100);
+        assert_eq!(
+            with_var("ledger", |ledger: &SVec<u64>| ledger.get_cloned(99)),
+            Some(99)
+        );
+
+        set_var("counter", &0u64);
+        let allocated = get_allocated_size();
+
+        with_var_mut("counter", |counter: &mut u64| *counter += 1);
+        let counter = with_var_mut("counter", |counter: &mut u64| *counter);
+
+        assert_eq!(counter, 1);
+        assert_eq!(get_var::<u64>("counter"), 1);
+        assert_eq!(get_allocated_size(), allocated);
+
+        set_var("str", &String::from("a"));
+        with_var_mut("str", |it: &mut String| it.push_str(&"b".repeat(100)));
+
+        assert_eq!(get_var::<String>("str"), format!("a{}", "b".repeat(100)));
+    }
+
+    #[test]
+    fn with_var_mut_skips_unchanged_values() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        set_var("counter", &10u64);
+        stable_memory_pre_upgrade();
+        deinit_allocator();
+        stable_memory_post_upgrade(0);
+
+        // the cell is changed behind the back of with_var_mut() - the change only survives, if
+        // the unchanged value is not written back
+        let ptr = with_vars(|vars| vars.get_cloned(&String::from("counter")))
+            .unwrap()
+            .ptr;
+        let cell = unsafe { SUnsafeCell::<u64>::from_ptr(ptr) };
+
+        with_var_mut("counter", |_: &mut u64| {
+            cell.slice._write_bytes(0, &20u64.to_le_bytes())
+        });
+
+        assert_eq!(get_var::<u64>("counter"), 20);
+    }
+
+    #[test]
+    #[should_panic]
+    fn type_mismatch_traps() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        set_var("var", &10u64);
+        get_var::<u32>("var");
+    }
+
+    #[test]
+    fn var_lifecycle_works_fine() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        assert!(!has_var("first"));
+        assert!(list_vars().is_empty());
+
+        set_var("first", &vec![1u64, 2, 3]);
+        set_var("second", &10u64);
+        let allocated = get_allocated_size();
+
+        for i in 0..100u64 {
+            set_var("second", &i);
+        }
+
+        assert_eq!(get_allocated_size(), allocated, "Var update leaks memory");
+        assert_eq!(get_var::<u64>("second"), 99);
+
+        set_var("first", &(0..100u64).collect::<Vec<_>>());
+        assert_eq!(get_var::<Vec<u64>>("first").len(), 100);
+
+        let mut vars = list_vars();
+        vars.sort();
+        assert_eq!(vars, vec![String::from("first"), String::from("second")]);
+
+        assert!(has_var("first"));
+        assert!(remove_var("first"));
+        assert!(!has_var("first"));
+        assert!(!remove_var("first"));
+        assert_eq!(list_vars(), vec![String::from("second")]);
+
+        assert!(remove_var("second"));
+        assert!(list_vars().is_empty());
+    }
+
+    #[test]
+    fn legacy_registry_is_migrated() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        // the registry, as it was written by store_vars() of 0.2.x
+        let table = allocate::<LegacyVarTable>(4 * PTR_SIZE);
+        let mut bucket = SVec::<LegacyVarEntry>::new();
+
+        for i in 0..3u64 {
+            let val_box = SUnsafeCell::new(&(i * 10));
+
+            bucket.push(&LegacyVarEntry {
+                name: format!("var {}", i),
+                ptr: SUnsafeCell::new(&unsafe { val_box.as_ptr() }),
+            });
+        }
+
+        let bucket_box = SUnsafeCell::new(&bucket);
+        table._write_word(PTR_SIZE, unsafe { bucket_box.as_ptr() });
+
+        let legacy_box = SUnsafeCell::new(&LegacyVarRegistry {
+            len: 3,
+            table_capacity: 4,
+            table: Some(table),
+        });
+        _set_custom_data_ptr(0, unsafe { legacy_box.as_ptr() });
+
+        deinit_allocator();
+        stable_memory_post_upgrade(0);
+
+        let mut vars = list_vars();
+        vars.sort();
+        assert_eq!(vars, vec!["var 0", "var 1", "var 2"]);
+        assert_eq!(get_var::<u64>("var 1"), 10);
+
+        set_var("var 1", &11u64);
+        with_var_mut("var 2", |it: &mut u64| *it += 1);
+
+        let tags = with_vars(|vars| {
+            (0..3)
+                .map(|i| vars.get_cloned(&format!("var {}", i)).unwrap().type_tag)
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(tags, vec![0, u64::TYPE_TAG, u64::TYPE_TAG]);
+
+        deinit_allocator();
+        stable_memory_post_upgrade(0);
+
+        assert_eq!(get_var::<u64>("var 0"), 0);
+        assert_eq!(get_var::<u64>("var 1"), 11);
+        assert_eq!(get_var::<u64>("var 2"), 21);
     }
 }
@@ -0,0 +1,284 @@
+use crate::collections::vec::SVec;
+use speedy::{Context, LittleEndian, Readable, Reader, Writable, Writer};
+use std::cmp::min;
+use std::ops::{Deref, DerefMut};
+
+/// Turns an encoded value of some version into an encoded value of the next version.
+pub type Migration = fn(&[u8]) -> Vec<u8>;
+
+/// A type which layout can change over time.
+///
+/// Each time you change the layout of such a type, keep its previous definition under some
+/// other name and append a new migration to `MIGRATIONS`:
+/// ```ignore
+/// #[derive(Readable, Writable)]
+/// struct HistoryEntryV0 {
+///     qty: u64,
+/// }
+///
+/// #[derive(Readable, Writable)]
+/// struct HistoryEntry {
+///     qty: u64,
+///     memo: Option<String>,
+/// }
+///
+/// impl From<HistoryEntryV0> for HistoryEntry {
+///     fn from(it: HistoryEntryV0) -> Self {
+///         Self { qty: it.qty, memo: None }
+///     }
+/// }
+///
+/// impl Versioned for HistoryEntry {
+///     const MIGRATIONS: &'static [Migration] = &[migration::<HistoryEntryV0, HistoryEntry>];
+/// }
+/// ```
+pub trait Versioned {
+    /// `MIGRATIONS[i]` upgrades an encoded value of version `i` to version `i + 1`.
+    /// The current version is therefore equal to the number of migrations.
+    const MIGRATIONS: &'static [Migration];
+
+    fn version() -> u32 {
+        Self::MIGRATIONS.len() as u32
+    }
+}
+
+/// A migration, which decodes a value as `Old`, converts it into `New` and encodes it back.
+pub fn migration<'a, Old: Readable<'a, LittleEndian>, New: From<Old> + Writable<LittleEndian>>(
+    buf: &[u8],
+) -> Vec<u8> {
+    let old = Old::read_from_buffer_copying_data(buf).expect("Unable to decode");
+
+    New::from(old).write_to_vec().expect("Unable to encode")
+}
+
+/// A versioned envelope around a value - the value is stored together with the version of its
+/// layout. Values stored with older versions are migrated on read, and are stored with the
+/// current version, once written back.
+///
+/// Wrapping a value into an envelope changes its encoding - existing unversioned values can't be
+/// read as `SVersioned`.
+pub struct SVersioned<T> {
+    it: T,
+    stored_version: u32,
+}
+
+impl<T: Versioned> SVersioned<T> {
+    pub fn new(it: T) -> Self {
+        Self {
+            it,
+            stored_version: T::version(),
+        }
+    }
+
+    /// Returns the version this value was stored with. Less than `T::version()` means the value
+    /// was migrated during decoding, but the migrated value is not written back yet.
+    pub fn stored_version(&self) -> u32 {
+        self.stored_version
+    }
+
+    pub fn is_outdated(&self) -> bool {
+        self.stored_version < T::version()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.it
+    }
+}
+
+impl<T> Deref for SVersioned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.it
+    }
+}
+
+impl<T> DerefMut for SVersioned<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.it
+    }
+}
+
+impl<'a, T: Versioned + Readable<'a, LittleEndian>> Readable<'a, LittleEndian> for SVersioned<T> {
+    fn read_from<R: Reader<'a, LittleEndian>>(
+        reader: &mut R,
+    ) -> Result<Self, <LittleEndian as Context>::Error> {
+        let stored_version = reader.read_u32()?;
+        let mut buf: Vec<u8> = reader.read_value()?;
+
+        if stored_version > T::version() {
+            return Err(speedy::Error::custom(format!(
+                "Unknown version {} (the latest known is {})",
+                stored_version,
+                T::version()
+            )));
+        }
+
+        for migration in &T::MIGRATIONS[stored_version as usize..] {
+            buf = migration(&buf);
+        }
+
+        let it = T::read_from_buffer_copying_data(&buf)?;
+
+        Ok(Self { it, stored_version })
+    }
+}
+
+impl<T: Versioned + Writable<LittleEndian>> Writable<LittleEndian> for SVersioned<T> {
+    fn write_to<W: ?Sized + Writer<LittleEndian>>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), <LittleEndian as Context>::Error> {
+        let buf = self.it.write_to_vec()?;
+
+        writer.write_u32(T::version())?;
+        writer.write_value(&buf)
+    }
+}
+
+/// Eagerly migrates up to `count` elements of the vector, starting from index `from`. Only the
+/// outdated elements are written back.
+///
+/// Returns the index to continue from, or `None` if the end of the vector is reached. Store it
+/// somewhere (e.g. in a stable variable) to resume the migration in the next message:
+/// ```ignore
+/// #[heartbeat]
+/// fn tick() {
+///     if let Some(from) = s!("migration_cursor": Option<u64>) {
+///         let next = LEDGER.with_mut(|ledger| migrate_svec(ledger, from, 1000));
+///         s! { "migration_cursor": Option<u64> = next };
+///     }
+/// }
+/// ```
+pub fn migrate_svec<'a, T: Versioned + Readable<'a, LittleEndian> + Writable<LittleEndian>>(
+    vec: &mut SVec<SVersioned<T>>,
+    from: u64,
+    count: u64,
+) -> Option<u64> {
+    let to = min(from.saturating_add(count), vec.len());
+
+    for idx in from..to {
+        let it = vec.get_cloned(idx).unwrap();
+
+        if it.is_outdated() {
+            vec.replace(idx, &it);
+        }
+    }
+
+    if to < vec.len() {
+        Some(to)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collections::vec::SVec;
+    use crate::primitive::s_unsafe_cell::SUnsafeCell;
+    use crate::utils::versioned::{migrate_svec, migration, Migration, SVersioned, Versioned};
+    use crate::{stable, stable_memory_init};
+    use speedy::{Readable, Writable};
+
+    #[derive(Readable, Writable)]
+    struct EntryV0 {
+        qty: u64,
+    }
+
+    impl Versioned for EntryV0 {
+        const MIGRATIONS: &'static [Migration] = &[];
+    }
+
+    #[derive(Readable, Writable)]
+    struct EntryV1 {
+        qty: u64,
+        memo: String,
+    }
+
+    impl From<EntryV0> for EntryV1 {
+        fn from(it: EntryV0) -> Self {
+            Self {
+                qty: it.qty,
+                memo: String::from("none"),
+            }
+        }
+    }
+
+    #[derive(Readable, Writable, Debug, PartialEq, Eq)]
+    struct Entry {
+        qty: u128,
+        memo: Option<String>,
+    }
+
+    impl From<EntryV1> for Entry {
+        fn from(it: EntryV1) -> Self {
+            Self {
+                qty: it.qty as u128,
+                memo: Some(it.memo),
+            }
+        }
+    }
+
+    impl Versioned for Entry {
+        const MIGRATIONS: &'static [Migration] =
+            &[migration::<EntryV0, EntryV1>, migration::<EntryV1, Entry>];
+    }
+
+    #[test]
+    fn lazy_migration_works_fine() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        let cell = SUnsafeCell::new(&SVersioned::new(EntryV0 { qty: 10 }));
+        let ptr = unsafe { cell.as_ptr() };
+
+        let mut cell = unsafe { SUnsafeCell::<SVersioned<Entry>>::from_ptr(ptr) };
+        let it = cell.get_cloned();
+
+        assert_eq!(it.stored_version(), 0);
+        assert!(it.is_outdated());
+        assert_eq!(
+            *it,
+            Entry {
+                qty: 10,
+                memo: Some(String::from("none"))
+            }
+        );
+
+        unsafe { cell.set(&it) };
+        let cell = unsafe { SUnsafeCell::<SVersioned<Entry>>::from_ptr(cell.as_ptr()) };
+
+        assert_eq!(cell.get_cloned().stored_version(), 2);
+        assert_eq!(cell.get_cloned().into_inner().qty, 10);
+    }
+
+    #[test]
+    fn eager_migration_works_fine() {
+        stable::clear();
+        stable_memory_init(true, 0);
+
+        let mut old_vec = SVec::<SVersioned<EntryV0>>::new();
+        for i in 0..100 {
+            old_vec.push(&SVersioned::new(EntryV0 { qty: i }));
+        }
+
+        let mut vec =
+            SVec::<SVersioned<Entry>>::read_from_buffer(&old_vec.write_to_vec().unwrap()).unwrap();
+
+        let mut cursor = Some(0);
+        let mut iterations = 0;
+        while let Some(from) = cursor {
+            cursor = migrate_svec(&mut vec, from, 30);
+            iterations += 1;
+        }
+
+        assert_eq!(iterations, 4);
+
+        for i in 0..100 {
+            let it = vec.get_cloned(i).unwrap();
+
+            assert!(!it.is_outdated());
+            assert_eq!(it.qty, i as u128);
+        }
+    }
+}